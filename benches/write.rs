@@ -0,0 +1,84 @@
+#[macro_use]
+extern crate criterion;
+extern crate futures;
+extern crate rustls;
+extern crate tokio_io;
+extern crate tokio_rustls;
+extern crate webpki;
+
+use std::io::{ self, BufReader, Cursor, Read, Write };
+use std::sync::Arc;
+use criterion::{ Criterion, Throughput };
+use futures::{ Async, Future, Poll };
+use rustls::{ ClientConfig, NoClientAuth, ServerConfig, ServerSession, Session };
+use rustls::internal::pemfile::{ certs, rsa_private_keys };
+use tokio_io::{ AsyncRead, AsyncWrite };
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+/// Loops the client straight into a server session, discarding the
+/// plaintext it decrypts.
+struct Loopback(ServerSession);
+
+impl Read for Loopback {
+    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+        self.0.write_tls(&mut buf)
+    }
+}
+
+impl Write for Loopback {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = self.0.read_tls(&mut buf)?;
+        self.0.process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        io::copy(&mut self.0, &mut io::sink())?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Loopback {}
+impl AsyncWrite for Loopback {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    const CERT: &str = include_str!("../tests/end.cert");
+    const CHAIN: &str = include_str!("../tests/end.chain");
+    const RSA: &str = include_str!("../tests/end.rsa");
+
+    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    let mut sconfig = ServerConfig::new(NoClientAuth::new());
+    sconfig.set_single_cert(cert, keys.pop().unwrap()).unwrap();
+
+    let mut cconfig = ClientConfig::new();
+    cconfig.root_store.add_pem_file(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+
+    (Arc::new(sconfig), Arc::new(cconfig))
+}
+
+fn bench_write(c: &mut Criterion) {
+    let (sconfig, cconfig) = configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(cconfig)
+        .connect(domain, Loopback(ServerSession::new(&sconfig)))
+        .wait()
+        .unwrap();
+
+    let mut group = c.benchmark_group("write");
+    for &size in &[16, 64, 16 * 1024, 64 * 1024] {
+        let data = vec![0x42; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("{}B", size), |b| b.iter(|| stream.write_all(&data).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_write);
+criterion_main!(benches);
@@ -1,19 +1,129 @@
 use super::*;
-use rustls::Session;
-use std::io::Write;
+use bytes::Bytes;
+use duplex::Duplex;
+use framed::Framed;
+use futures::Sink;
+use inspect::{ Direction, Inspect };
+#[cfg(feature = "idle-timeout")]
+use idle::{ CloseTimeoutAction, CloseTimer, IdleAction, IdleTimer };
+#[cfg(feature = "coalesce")]
+use coalesce::Coalescer;
+#[cfg(any(feature = "idle-timeout", feature = "coalesce"))]
+use std::time::Duration;
+use rustls::{ ResolvesClientCert, Session, SignatureScheme };
+use rustls::sign::CertifiedKey;
+use std::any::Any;
+use std::io::{ Read, Write };
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Instant, SystemTime };
+#[cfg(feature = "early-data")]
+use common::WriteTls;
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
 #[derive(Debug)]
 pub struct TlsStream<IO> {
-    pub(crate) io: IO,
-    pub(crate) session: ClientSession,
+    pub(crate) io: common::Slot<IO>,
+    pub(crate) session: common::Slot<ClientSession>,
     pub(crate) state: TlsState,
+    pub(crate) ctx: common::Context,
+    pub(crate) read_buf: (usize, Vec<u8>),
+    pub(crate) context: Option<Box<dyn Any + Send>>,
+    #[cfg(feature = "idle-timeout")]
+    pub(crate) idle: Option<IdleTimer>,
+    #[cfg(feature = "idle-timeout")]
+    pub(crate) close_timer: Option<CloseTimer>,
+    #[cfg(feature = "coalesce")]
+    pub(crate) coalesce: Option<Coalescer>,
 
     #[cfg(feature = "early-data")]
     pub(crate) early_data: (usize, Vec<u8>),
+    #[cfg(feature = "early-data")]
+    pub(crate) sent_early_data: bool,
+    pub(crate) on_drop: Option<fn(&mut TlsStream<IO>)>,
+}
+
+/// Passes on what the config's client certificate resolver picks, noting
+/// whether it picked a certificate.
+pub(crate) struct RecordClientCert {
+    pub(crate) inner: Arc<dyn ResolvesClientCert>,
+    pub(crate) sent: Arc<AtomicBool>,
+}
+
+impl ResolvesClientCert for RecordClientCert {
+    fn resolve(&self, acceptable_issuers: &[&[u8]], sigschemes: &[SignatureScheme]) -> Option<CertifiedKey> {
+        let key = self.inner.resolve(acceptable_issuers, sigschemes);
+        if key.is_some() {
+            self.sent.store(true, Ordering::Relaxed);
+        }
+        key
+    }
+
+    fn has_certs(&self) -> bool {
+        self.inner.has_certs()
+    }
+}
+
+/// Verifies like the wrapped config does, noting the OCSP response the
+/// server stapled.
+#[cfg(feature = "dangerous_configuration")]
+pub(crate) struct RecordOcsp {
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) stapled: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for RecordOcsp {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, TLSError> {
+        *self.stapled.lock().unwrap_or_else(|err| err.into_inner()) = Some(ocsp_response.to_vec());
+        self.config.get_verifier().verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+    }
+}
+
+/// Keeps new sessions in the wrapped store, but resumes from none of them.
+pub(crate) struct NoResumption(pub(crate) Arc<dyn rustls::StoresClientSessions>);
+
+impl rustls::StoresClientSessions for NoResumption {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, _: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
 }
 
+/// Keeps sessions in the wrapped store, noting those put by one
+/// connection, for `export_resumption`.
+pub(crate) struct RecordSessions {
+    pub(crate) inner: Arc<dyn rustls::StoresClientSessions>,
+    pub(crate) puts: common::SessionPuts,
+}
+
+impl rustls::StoresClientSessions for RecordSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        {
+            let mut puts = self.puts.lock().unwrap_or_else(|err| err.into_inner());
+            puts.retain(|(k, _)| *k != key);
+            puts.push((key.clone(), value.clone()));
+        }
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.get(key)
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum MidHandshake<IO> {
     Handshaking(TlsStream<IO>),
     #[cfg(feature = "early-data")]
@@ -22,19 +132,813 @@ pub(crate) enum MidHandshake<IO> {
 }
 
 impl<IO> TlsStream<IO> {
+    pub(crate) fn new(io: IO, session: ClientSession) -> TlsStream<IO> {
+        TlsStream {
+            io: common::Slot::new(io),
+            session: common::Slot::new(session),
+            state: TlsState::Stream,
+            ctx: common::Context::default(),
+            read_buf: (0, Vec::new()),
+            context: None,
+            #[cfg(feature = "idle-timeout")]
+            idle: None,
+            #[cfg(feature = "idle-timeout")]
+            close_timer: None,
+            #[cfg(feature = "coalesce")]
+            coalesce: None,
+
+            #[cfg(feature = "early-data")]
+            early_data: (0, Vec::new()),
+            #[cfg(feature = "early-data")]
+            sent_early_data: false,
+            on_drop: None,
+        }
+    }
+
     #[inline]
     pub fn get_ref(&self) -> (&IO, &ClientSession) {
-        (&self.io, &self.session)
+        (&*self.io, &*self.session)
     }
 
     #[inline]
     pub fn get_mut(&mut self) -> (&mut IO, &mut ClientSession) {
-        (&mut self.io, &mut self.session)
+        (&mut *self.io, &mut *self.session)
+    }
+
+    /// Attach a value to the connection, replacing any previous one.
+    pub fn set_context<T: Any + Send>(&mut self, value: T) {
+        self.context = Some(Box::new(value));
+    }
+
+    /// The value attached with `set_context`, if it is a `T`.
+    pub fn get_context<T: Any>(&self) -> Option<&T> {
+        self.context.as_ref()?.downcast_ref()
+    }
+
+    /// TLS records seen on the wire so far, handshake included.
+    pub fn record_stats(&self) -> RecordStats {
+        RecordStats {
+            read: self.ctx.records_read.count,
+            written: self.ctx.records_written.count,
+        }
+    }
+
+    /// Plaintext bytes `(read, written)` so far, leaving out TLS overhead.
+    ///
+    /// Plaintext from `poll_fill_buf` counts as read once it is buffered.
+    pub fn byte_counts(&self) -> (u64, u64) {
+        (self.ctx.bytes_read, self.ctx.bytes_written)
+    }
+
+    /// An ID for the connection, unique within the process and increasing
+    /// in the order streams were created, to correlate log lines by.
+    pub fn connection_id(&self) -> u64 {
+        self.ctx.id
+    }
+
+    /// When the handshake finished, `None` while handshaking.
+    pub fn established_at(&self) -> Option<Instant> {
+        self.ctx.established_at
+    }
+
+    /// The `TLSError` behind the last error that came from rustls, to match
+    /// on instead of the `io::Error` wrapping it.
+    pub fn last_tls_error(&self) -> Option<&TLSError> {
+        self.ctx.last_tls_error.as_ref()
+    }
+
+    /// How many bytes of TLS the last `write` put on the IO, to see what
+    /// records add to the plaintext.
+    ///
+    /// These are the records that went out during that call: records held
+    /// back by an IO that blocked count towards the call that writes them
+    /// out, and so does what a coalesced write held back.
+    pub fn last_write_wire_bytes(&self) -> usize {
+        self.ctx.last_write_wire_bytes
+    }
+
+    /// Whether plaintext has been written that has not all reached the IO
+    /// as records yet, so that a flush is still needed.
+    ///
+    /// Plaintext written during the handshake counts until the handshake
+    /// is done.
+    pub fn has_unflushed_plaintext(&self) -> bool {
+        self.ctx.unflushed && (self.session.wants_write() || self.session.is_handshaking())
+    }
+
+    /// Make each read return the plaintext of at most one TLS record, for
+    /// consumers that want to follow the peer's record boundaries.
+    ///
+    /// A read into a buffer smaller than the record still returns less, and
+    /// `poll_fill_buf` fills its buffer one record at a time too.
+    pub fn set_record_reads(&mut self, flag: bool) {
+        self.ctx.record_reads = flag;
+    }
+
+    /// Make reads fail with `ErrorKind::UnexpectedEof` when the IO ends
+    /// without the peer's close_notify, rather than return `Ok(0)`, so that
+    /// a truncated stream is told from a complete one.
+    ///
+    /// Protocols framing their own messages can do without; off by default.
+    pub fn set_require_clean_close(&mut self, flag: bool) {
+        self.ctx.require_clean_close = flag;
+    }
+
+    /// Stop reading the IO, so that the OS buffers and then the peer are
+    /// held back until `resume_reads`.
+    ///
+    /// Reads still return plaintext that was already decrypted or read, and
+    /// fail with `WouldBlock` once that runs out. Nothing wakes the task for
+    /// the IO while paused, so poll again after resuming.
+    ///
+    /// The handshake reads too, so it stalls while reads are paused.
+    pub fn pause_reads(&mut self) {
+        self.ctx.reads_paused = true;
+    }
+
+    /// Read the IO again after `pause_reads`.
+    pub fn resume_reads(&mut self) {
+        self.ctx.reads_paused = false;
+    }
+
+    /// The cap on plaintext and TLS the session buffers ahead of the IO,
+    /// or `None` if it has none.
+    ///
+    /// Limits set on the session directly through `get_mut` are not seen.
+    pub fn buffer_limit(&self) -> Option<usize> {
+        Some(self.ctx.buffer_limit).filter(|&limit| limit != 0)
+    }
+
+    /// Change the session's buffer limit, e.g. to shrink it on a busy
+    /// connection. `None`, like `Some(0)`, removes it.
+    ///
+    /// What is already buffered stays; writes take nothing more until the
+    /// backlog drops below the new limit.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        self.ctx.buffer_limit = limit.unwrap_or(0);
+        self.session.set_buffer_limit(self.ctx.buffer_limit);
+    }
+
+    /// Close the connection once `bytes` of plaintext have been read and
+    /// written in all, counting from its start.
+    ///
+    /// Reads and writes are cut short at the limit. The next one sends
+    /// close_notify, shuts both directions and fails with `Other`, as does
+    /// every one after it.
+    pub fn set_transfer_limit(&mut self, bytes: u64) {
+        self.ctx.transfer_limit = Some(bytes);
+    }
+
+    /// Make reads, writes and flushes fail with `NotConnected` while the
+    /// handshake is unfinished, instead of driving it, so that it only
+    /// progresses through `poll_handshake`.
+    ///
+    /// Off by default. Streams from a `TlsConnector` or `TlsAcceptor` have
+    /// finished the handshake already, unless they started in 0-RTT.
+    pub fn set_require_explicit_handshake(&mut self, flag: bool) {
+        self.ctx.explicit_handshake = flag;
+    }
+
+    /// Take the encrypted bytes read from the IO but not yet handed to the
+    /// session: the start of a record still waiting for the rest of it, and
+    /// with `set_record_reads` whole records queued behind the current one.
+    ///
+    /// Once the handshake is done these are all that is buffered, so they
+    /// can be replayed ahead of the IO, together with the session from
+    /// `into_inner`, to carry the connection on elsewhere.
+    pub fn take_buffered_tls(&mut self) -> Vec<u8> {
+        mem::take(&mut self.ctx.partial_tls)
+    }
+
+    /// A snapshot of what the handshake negotiated, or `None` while it is
+    /// still in progress.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        // the server's chain is only kept from a full handshake
+        let resumed = self.session.get_peer_certificates().is_none();
+        ConnectionInfo::new(&*self.session, Some(resumed))
+    }
+
+    /// The grade of the negotiated cipher suite, see `CipherStrength::of`.
+    pub fn cipher_strength(&self) -> Option<CipherStrength> {
+        self.session.get_negotiated_ciphersuite()
+            .map(|suite| CipherStrength::of(suite.suite))
+    }
+
+    /// Whether the handshake was keyed with a pre-shared key.
+    ///
+    /// rustls 0.16 has no external PSK support and does not report whether
+    /// a session was resumed, so this is always `false` for now. It should
+    /// be wired to the session once rustls exposes that.
+    pub fn used_psk(&self) -> bool {
+        false
     }
 
+    /// Whether the handshake used Encrypted Client Hello.
+    ///
+    /// rustls 0.16 does not implement ECH, so this is always `false` for
+    /// now. It should be wired to the session once rustls exposes that.
+    pub fn used_ech(&self) -> bool {
+        // TODO: ask the session once rustls supports ECH.
+        false
+    }
+
+    /// Whether the records are compressed, the precondition for CRIME-style
+    /// attacks.
+    ///
+    /// rustls implements no TLS compression and only offers the null
+    /// method, so this is always `false`.
+    pub fn compression_in_use(&self) -> bool {
+        false
+    }
+
+    /// The state this connection's session can be resumed from, to resume
+    /// it through `TlsConnector::connect_resuming` elsewhere.
+    ///
+    /// This is what the session gave the config's `session_persistence`
+    /// during this connection. A TLS 1.3 server sends its tickets after the
+    /// handshake, so they are only here once a read has taken them in.
+    /// `None` without any, or unless the connector was set to
+    /// `exportable_resumption`.
+    pub fn export_resumption(&self) -> Option<ResumptionData> {
+        let record = self.ctx.session_record.as_ref()?;
+        let entries = record.puts.lock().unwrap_or_else(|err| err.into_inner()).clone();
+        if entries.is_empty() {
+            return None;
+        }
+
+        Some(ResumptionData {
+            server_name: <&str>::from(record.server_name.as_ref()).to_owned(),
+            entries,
+        })
+    }
+
+    /// Act on a connection that has neither read nor written anything for
+    /// `timeout`.
+    ///
+    /// The timer is only checked while a read or write is blocked, so it
+    /// needs the stream to be polled from a task on a runtime with a timer.
+    #[cfg(feature = "idle-timeout")]
+    pub fn set_idle_timeout(&mut self, timeout: Duration, action: IdleAction) {
+        self.idle = Some(IdleTimer::new(timeout, action));
+    }
+
+    /// Stop `shutdown` from waiting more than `timeout` for the close_notify
+    /// to be written out, e.g. to a peer that stopped reading.
+    ///
+    /// The clock starts with the first `shutdown` call. Once it runs out the
+    /// IO is shut down regardless, and `action` picks what is reported.
+    #[cfg(feature = "idle-timeout")]
+    pub fn set_close_timeout(&mut self, timeout: Duration, action: CloseTimeoutAction) {
+        self.close_timer = Some(CloseTimer::new(timeout, action));
+    }
+
+    /// Hold small writes back for up to `window`, so that a burst of them
+    /// goes out in fewer, larger records. `None` stops holding writes back.
+    ///
+    /// What is held goes out once it fills a record, with the first write,
+    /// read or flush after the window is up, and on `flush` and `shutdown`.
+    /// The task writing is woken when the window is up, which takes a
+    /// runtime with a timer; without one writes are not held back.
+    /// `into_inner` drops what is still held.
+    #[cfg(feature = "coalesce")]
+    pub fn set_write_coalesce(&mut self, window: Option<Duration>) {
+        let window = window.unwrap_or_default();
+        match &mut self.coalesce {
+            Some(coalesce) => coalesce.set_window(window),
+            None if window > Duration::from_secs(0) => self.coalesce = Some(Coalescer::new(window)),
+            None => (),
+        }
+    }
+
+    /// Check the server's leaf certificate against a SHA-256 pin of its
+    /// SubjectPublicKeyInfo, failing with `PermissionDenied` on mismatch.
+    ///
+    /// The chain was already validated during the handshake, so this only
+    /// narrows down which key is accepted.
+    pub fn verify_pin(&self, expected_spki_sha256: &[u8; 32]) -> io::Result<()> {
+        let certs = self.session.get_peer_certificates().unwrap_or_default();
+        let spki = certs.first()
+            .and_then(|cert| common::der::spki(&cert.0))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no server certificate to pin"))?;
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+        ring::constant_time::verify_slices_are_equal(digest.as_ref(), expected_spki_sha256)
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "server key does not match the pin"))
+    }
+
+    /// Validate the server's certificate chain again, against `roots` and the
+    /// current time, without renegotiating.
+    ///
+    /// Meant for long-lived connections whose trust anchors may have been
+    /// rotated or revoked since the handshake. Fails with `PermissionDenied`
+    /// if the chain is no longer trusted.
+    pub fn reverify_peer(&self, roots: &rustls::RootCertStore) -> io::Result<()> {
+        let certs = self.session.get_peer_certificates().unwrap_or_default();
+        common::verify::chain(&certs, roots, true)
+    }
+
+    /// The notBefore and notAfter of the server's leaf certificate.
+    ///
+    /// `None` while handshaking, if the server sent no certificate, or if its
+    /// validity could not be decoded.
+    pub fn peer_cert_validity(&self) -> Option<(SystemTime, SystemTime)> {
+        let certs = self.session.get_peer_certificates()?;
+        common::der::validity(&certs.first()?.0)
+    }
+
+    /// The server's certificate chain as a PEM bundle, leaf first, e.g. to
+    /// hand it on to a backend.
+    ///
+    /// The bundle spans several lines, so a header carrying it needs it
+    /// escaped, as with URL encoding. `None` while handshaking or if the
+    /// peer sent no certificate.
+    pub fn peer_certificates_pem(&self) -> Option<String> {
+        let certs = self.session.get_peer_certificates()?;
+        if certs.is_empty() {
+            return None;
+        }
+        Some(common::pem_bundle(&certs))
+    }
+
+    /// How the server's certificate matched the name connected to, e.g. to
+    /// flag wildcard certificates.
+    ///
+    /// An exact match wins over a wildcard one. `None` while handshaking,
+    /// if the certificate's names could not be decoded, or unless the
+    /// connector was set to `record_name_match`.
+    ///
+    /// This is worked out after the handshake from the leaf's
+    /// subjectAltNames, not by the verifier: with one installed through
+    /// `ClientConfig::dangerous` it tells how the names match even for a
+    /// chain that was never checked against the name.
+    pub fn name_match_kind(&self) -> Option<NameMatchKind> {
+        if self.session.is_handshaking() {
+            return None;
+        }
+        let name: &str = self.ctx.server_name.as_ref()?.as_ref().into();
+        let certs = self.session.get_peer_certificates()?;
+        let sans = common::der::dns_names(&certs.first()?.0)?;
+
+        let name = name.trim_end_matches('.').as_bytes();
+        let wildcard = |san: &[u8]| {
+            let mut labels = name.splitn(2, |&b| b == b'.');
+            let first = labels.next().unwrap_or_default();
+            match (san.strip_prefix(b"*."), labels.next()) {
+                (Some(suffix), Some(rest)) => !first.is_empty() && rest.eq_ignore_ascii_case(suffix),
+                _ => false,
+            }
+        };
+
+        if sans.iter().any(|san| san.eq_ignore_ascii_case(name)) {
+            Some(NameMatchKind::Exact)
+        } else if sans.iter().any(|san| wildcard(san)) {
+            Some(NameMatchKind::Wildcard)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the handshake sent the server a client certificate, as
+    /// opposed to it only being asked for one.
+    ///
+    /// `Some(false)` too when the config's certificates matched none of the
+    /// issuers the server accepts. `None` while handshaking or unless the
+    /// connector was set to `record_client_certificate`.
+    pub fn sent_client_certificate(&self) -> Option<bool> {
+        if self.session.is_handshaking() {
+            return None;
+        }
+        self.ctx.client_cert_sent.as_ref()
+            .map(|sent| sent.load(Ordering::Relaxed))
+    }
+
+    /// Whether the server's certificate chain was verified, rather than
+    /// only accepted, once the handshake is done; `None` while handshaking.
+    ///
+    /// Without the `dangerous_configuration` feature this is `Some(true)`
+    /// after any handshake, as rustls' own verifier only accepts chains it
+    /// verified. With it, a verifier installed through
+    /// `ClientConfig::dangerous` may accept chains rustls would not, so this
+    /// is `None` unless the connector was set to
+    /// `recheck_server_certificate`. Then each call checks the chain that
+    /// verifier accepted against the config's roots and the server name, as
+    /// of when the handshake finished, and reports that check; still `None`
+    /// on resumption, where the server presents no chain.
+    pub fn peer_verified(&self) -> Option<bool> {
+        if self.session.is_handshaking() {
+            return None;
+        }
+        self.recheck_peer()
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    fn recheck_peer(&self) -> Option<bool> {
+        let check = self.ctx.peer_check.as_ref()?;
+        let certs = self.session.get_peer_certificates()?;
+        let now = check.now.unwrap_or_else(|| {
+            let now = SystemTime::now();
+            self.ctx.established_at
+                .and_then(|at| now.checked_sub(at.elapsed()))
+                .unwrap_or(now)
+        });
+        Some(common::verify::server(&check.config.root_store, &certs, check.server_name.as_ref(), now).is_ok())
+    }
+
+    #[cfg(not(feature = "dangerous_configuration"))]
     #[inline]
-    pub fn into_inner(self) -> (IO, ClientSession) {
-        (self.io, self.session)
+    fn recheck_peer(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    /// Whether the server stapled an OCSP response as its certificate's
+    /// must-staple extension (RFC 7633) demands.
+    ///
+    /// `None` while handshaking, for a certificate without the extension,
+    /// or unless the connector was set to `record_ocsp_stapling`. Like
+    /// rustls, this does not check the response itself: `Some(true)` only
+    /// says that one was stapled.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn ocsp_stapling_satisfied(&self) -> Option<bool> {
+        if self.session.is_handshaking() {
+            return None;
+        }
+        let stapled = self.ctx.stapled_ocsp.as_ref()?
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_ref()
+            .map(|response| !response.is_empty())?;
+
+        let certs = self.session.get_peer_certificates()?;
+        if common::der::must_staple(&certs.first()?.0) {
+            Some(stapled)
+        } else {
+            None
+        }
+    }
+
+    /// A value bound to the handshake transcript, equal on both ends.
+    ///
+    /// rustls does not expose the transcript hash itself, so this is the
+    /// `tls-exporter` channel binding of RFC 9266: 32 bytes exported with the
+    /// label `EXPORTER-Channel-Binding` and no context. Use it wherever a
+    /// channel binding is wanted. `None` while handshaking.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        common::transcript_binding(&*self.session)
+    }
+
+    /// The signature scheme the peer authenticated with.
+    ///
+    /// rustls 0.16 checks the handshake signature internally and keeps no
+    /// record of the scheme, nor does it pass it to a custom verifier, so
+    /// this is always `None` for now.
+    pub fn peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        None
+    }
+
+    /// Whether the stream is still in the 0-RTT phase, where anything
+    /// written goes out as replayable early data.
+    ///
+    /// This stays `true` until a read or write has finished the handshake.
+    #[cfg(feature = "early-data")]
+    pub fn in_early_data_phase(&self) -> bool {
+        matches!(self.state, TlsState::EarlyData)
+    }
+
+    /// How many more bytes of early data the session will take, or `None`
+    /// outside the early-data phase.
+    ///
+    /// Needs `&mut self` because rustls only reports this through
+    /// `ClientSession::early_data`.
+    #[cfg(feature = "early-data")]
+    pub fn early_data_remaining(&mut self) -> Option<usize> {
+        if !self.in_early_data_phase() {
+            return None;
+        }
+        self.session.early_data().map(|data| data.bytes_left())
+    }
+
+    /// Whether any application data went out before the handshake finished,
+    /// accepted by the server or not.
+    ///
+    /// Only 0-RTT counts, as rustls does not do TLS 1.2 False Start.
+    #[cfg(feature = "early-data")]
+    pub fn sent_early_application_data(&self) -> bool {
+        self.sent_early_data
+    }
+
+    /// Whether 0-RTT data was sent but the server turned it down, so it was
+    /// replayed once the handshake finished.
+    ///
+    /// `false` while handshaking, and when no early data was sent at all,
+    /// unlike `!is_early_data_accepted()`.
+    #[cfg(feature = "early-data")]
+    pub fn early_data_rejected(&self) -> bool {
+        self.sent_early_data
+            && !self.session.is_handshaking()
+            && !self.session.is_early_data_accepted()
+    }
+
+    /// Note that plaintext still held by `poll_fill_buf` is dropped.
+    #[inline]
+    pub fn into_inner(mut self) -> (IO, ClientSession) {
+        self.on_drop = None;
+        (self.io.take(), self.session.take())
+    }
+
+    /// Give up the IO for plaintext use once TLS has been closed cleanly.
+    ///
+    /// This fails unless close_notify has been both sent and flushed, and
+    /// received from the peer with no plaintext left unread. Bytes the peer
+    /// sent right behind its close_notify may already sit in the session
+    /// and be lost, so the peer should wait for our close_notify first.
+    pub fn into_inner_after_close(self) -> io::Result<IO> {
+        let clean = matches!(self.state, TlsState::FullyShutdown)
+            && self.ctx.peer_closed
+            && !self.session.wants_write()
+            && self.read_buf.0 == self.read_buf.1.len();
+
+        if clean {
+            Ok(self.into_inner().0)
+        } else {
+            Err(io::Error::other("TLS stream was not cleanly closed"))
+        }
+    }
+}
+
+impl<IO> Drop for TlsStream<IO> {
+    fn drop(&mut self) {
+        if let Some(f) = self.on_drop.take() {
+            f(self)
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> TlsStream<IO> {
+    /// Queue a close_notify unless one was sent, and try once to write out
+    /// what is buffered, for `close_notify_on_drop`.
+    pub(crate) fn close_on_drop(&mut self) {
+        if self.session.is_handshaking() {
+            return;
+        }
+        let _ = self.push_coalesced(true);
+        if self.state.writeable() {
+            self.session.send_close_notify();
+            self.state.shutdown_write();
+        }
+        let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable())
+            .drain();
+    }
+}
+
+impl<R, W> TlsStream<Duplex<R, W>>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    /// Run `session` over separate read and write transports.
+    ///
+    /// The handshake is driven by the stream's first reads and writes.
+    pub fn from_split(read_io: R, write_io: W, session: ClientSession) -> TlsStream<Duplex<R, W>> {
+        TlsStream::new(Duplex::new(read_io, write_io), session)
+    }
+}
+
+impl<T> TlsStream<Framed<T>>
+where
+    T: futures::Stream<Item = Bytes, Error = io::Error> + Sink<SinkItem = Bytes, SinkError = io::Error>,
+{
+    /// Run `session` over a transport of frames rather than bytes.
+    ///
+    /// The handshake is driven by the stream's first reads and writes.
+    pub fn over_framed(transport: T, session: ClientSession) -> TlsStream<Framed<T>> {
+        TlsStream::new(Framed::new(transport), session)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    /// Pass every plaintext buffer read or written to `f`, for debugging.
+    pub fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        F: FnMut(Direction, &[u8]),
+    {
+        Inspect::new(self, f)
+    }
+
+    /// Fill the internal read buffer and return its contents.
+    ///
+    /// rustls does not lend out its own plaintext buffer, so data is copied
+    /// once into a buffer owned by the stream; parsing straight from the
+    /// returned slice avoids any further copy. The slice borrows the stream
+    /// and must be released before the next call, and nothing is removed
+    /// from the buffer until `consume` is called.
+    ///
+    /// An empty slice means the TLS stream has reached EOF.
+    pub fn poll_fill_buf(&mut self) -> Poll<&[u8], io::Error> {
+        if self.read_buf.0 == self.read_buf.1.len() {
+            let mut buf = mem::take(&mut self.read_buf.1);
+            buf.resize(common::READ_BUF_SIZE, 0);
+            let ret = io::Read::read(self, &mut buf);
+            buf.truncate(*ret.as_ref().unwrap_or(&0));
+            self.read_buf = (0, buf);
+            try_nb!(ret);
+        }
+
+        let (pos, buf) = &self.read_buf;
+        Ok(Async::Ready(&buf[*pos..]))
+    }
+
+    /// Drive the handshake, resolving once it is done and what it left to
+    /// write has been flushed.
+    pub fn poll_handshake(&mut self) -> Poll<(), io::Error> {
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        try_nb!(stream.handshake());
+        Ok(Async::Ready(()))
+    }
+
+    /// Resolve once a read would not block on the IO: there is plaintext
+    /// left from `poll_fill_buf`, or the IO has bytes or has hit EOF. Those
+    /// bytes are held for the next read rather than decrypted here.
+    ///
+    /// Plaintext the session decrypted but a short read didn't take isn't
+    /// seen, so wait on this only once a read has returned `WouldBlock`.
+    pub fn poll_read_ready(&mut self) -> Poll<(), io::Error> {
+        if self.read_buf.0 < self.read_buf.1.len() {
+            return Ok(Async::Ready(()));
+        }
+        common::poll_read_ready(&mut *self.io, &mut self.ctx)
+    }
+
+    /// Resolve once the TLS already buffered has all reached the IO, so the
+    /// next write has room to go out rather than being held by the session.
+    ///
+    /// This only knows the IO is full when writing to it blocks; with
+    /// nothing buffered it is `Ready` right away.
+    pub fn poll_writable(&mut self) -> Poll<(), io::Error> {
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        try_nb!(stream.drain());
+        Ok(Async::Ready(()))
+    }
+
+    /// Resolve once everything written so far has reached the IO, flushing
+    /// as needed.
+    ///
+    /// Unlike `poll_writable` this also pushes out plaintext held back for
+    /// coalescing, so a write buffer that is empty here stays empty until
+    /// the next write. The IO itself is not flushed.
+    pub fn poll_write_buffer_empty(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.push_coalesced(true));
+        self.poll_writable()
+    }
+
+    /// Fill `buf`, or report how much was read before the stream ended and
+    /// whether it ended with a close_notify.
+    pub fn read_exact_or_eof<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExactOrEof<'a, Self> {
+        ReadExactOrEof::new(self, buf, |stream| stream.ctx.peer_closed)
+    }
+
+    /// Write `buf` as the last plaintext with close_notify right behind it,
+    /// so both go out in the same flush, and in a single write for an IO
+    /// with a vectored `write_buf` such as `TcpStream`.
+    ///
+    /// `buf` goes through the same checks as `write`. The alert is only
+    /// queued once all of it has been taken; until then this returns how
+    /// much was, like `write`. Once it has been queued the
+    /// write side is shut down, and a retry after `NotReady`, which must pass
+    /// the same `buf`, only finishes the flush.
+    pub fn poll_write_close(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        try_nb!(self.push_coalesced(true));
+        let mut len = buf.len();
+        if self.state.writeable() {
+            let budget = self.transfer_budget(buf.len())?;
+            let ret = self.write_plaintext_with(&buf[..budget], true);
+            len = try_nb!(self.track_idle(ret));
+            self.ctx.bytes_written += len as u64;
+            if len == buf.len() {
+                self.session.send_close_notify();
+                self.state.shutdown_write();
+            }
+        }
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        match stream.flush() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 && len < buf.len() => {
+                if let WriteSemantics::OnWire = self.ctx.write_semantics {
+                    // Like `write`, only report the bytes once they're out;
+                    // the retry drains them and then reports them.
+                    self.ctx.unreported = Some(len);
+                    self.ctx.bytes_written -= len as u64;
+                    return Ok(Async::NotReady);
+                }
+            },
+            ret => try_nb!(ret),
+        }
+        Ok(Async::Ready(len))
+    }
+
+    /// Write `buf` and flush it at once, even while writes are coalesced,
+    /// e.g. for a control message that must not wait behind bulk data.
+    ///
+    /// What coalescing held back goes out first, as plaintext keeps its
+    /// order. This returns how much of `buf` was taken, like `write`; once
+    /// some was, a flush the IO blocks is left for the next write, flush
+    /// or `poll_writable` to finish.
+    pub fn poll_write_urgent(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        try_nb!(self.push_coalesced(true));
+        let len = self.transfer_budget(buf.len())?;
+        let ret = self.write_plaintext(&buf[..len]);
+        let len = try_nb!(self.track_idle(ret));
+        self.ctx.bytes_written += len as u64;
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        let ret = stream.flush().and_then(|()| common::retry_interrupted(|| self.io.flush()));
+        match ret {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => (),
+            ret => try_nb!(ret),
+        }
+        Ok(Async::Ready(len))
+    }
+
+    /// Read all plaintext until the peer sends close_notify or the IO
+    /// reaches EOF, failing with `ErrorKind::Other` past `max` bytes.
+    pub fn read_to_end_until_close(&mut self, max: usize) -> ReadToClose<'_, Self> {
+        ReadToClose::new(self, max)
+    }
+
+    /// Read and discard plaintext until the peer's close_notify, so that
+    /// closing doesn't leave unread data behind for the OS to answer with
+    /// a reset.
+    ///
+    /// Fails with `ErrorKind::UnexpectedEof` if the IO ends without one.
+    pub fn poll_drain_to_close(&mut self) -> Poll<(), io::Error> {
+        let mut buf = [0; common::READ_BUF_SIZE];
+        while try_nb!(io::Read::read(self, &mut buf)) != 0 {}
+
+        if self.ctx.peer_closed {
+            Ok(Async::Ready(()))
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Queue a close_notify, try once to write it out, and give up the IO.
+    pub(crate) fn abort(mut self) -> IO {
+        self.session.send_close_notify();
+        let _ = io::Write::flush(&mut Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx));
+        self.into_inner().0
+    }
+
+    /// Answer a TLS 1.3 post-handshake CertificateRequest with the
+    /// configured client certificate.
+    ///
+    /// rustls 0.16 does not implement post-handshake auth, so this always
+    /// fails with `ErrorKind::Unsupported`. A server asking for it anyway
+    /// makes the next read fail with the same kind rather than hang.
+    pub fn poll_provide_client_cert(&mut self) -> Poll<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "post-handshake client authentication is not supported"))
+    }
+
+    /// Send the early data written so far without waiting for more.
+    ///
+    /// Early data is otherwise held back until the handshake needs the IO.
+    /// Outside of the early data state there is nothing to send and this
+    /// returns `Ready` straight away.
+    #[cfg(feature = "early-data")]
+    pub fn poll_flush_early_data(&mut self) -> Poll<(), io::Error> {
+        if let TlsState::EarlyData = self.state {
+            let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx);
+            while stream.session.wants_write() {
+                try_nb!(stream.write_tls());
+            }
+            try_nb!(stream.io.flush());
+            self.sent_early_data |= !self.early_data.1.is_empty();
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    /// Mark `amt` bytes of the slice returned by `poll_fill_buf` as read.
+    pub fn consume(&mut self, amt: usize) {
+        let (pos, buf) = &mut self.read_buf;
+        *pos = std::cmp::min(*pos + amt, buf.len());
+    }
+}
+
+impl<IO> MidHandshake<IO> {
+    pub(crate) fn progress(&self) -> HandshakeProgress {
+        match self {
+            MidHandshake::Handshaking(stream) => HandshakeProgress::new(&*stream.session, &stream.ctx),
+            #[cfg(feature = "early-data")]
+            MidHandshake::EarlyData(stream) => HandshakeProgress::new(&*stream.session, &stream.ctx),
+            MidHandshake::End => HandshakeProgress::Complete,
+        }
     }
 }
 
@@ -49,16 +953,9 @@ where
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let MidHandshake::Handshaking(stream) = self {
             let state = stream.state;
-            let (io, session) = stream.get_mut();
-            let mut stream = Stream::new(io, session).set_eof(!state.readable());
-
-            if stream.session.is_handshaking() {
-                try_nb!(stream.complete_io());
-            }
-
-            if stream.session.wants_write() {
-                try_nb!(stream.complete_io());
-            }
+            let mut stream = Stream::new(&mut *stream.io, &mut *stream.session, &mut stream.ctx)
+                .set_eof(!state.readable());
+            try_nb!(stream.handshake());
         }
 
         match mem::replace(self, MidHandshake::End) {
@@ -75,16 +972,53 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            ret => ret?,
+        }
+
+        // what `poll_fill_buf` kept was counted when it was read
+        let buffered = self.read_buf.0 < self.read_buf.1.len();
+        let len = if buffered { buf.len() } else { self.transfer_budget(buf.len())? };
+        let ret = self.read_plaintext(&mut buf[..len]);
+        if let (false, Ok(n)) = (buffered, &ret) {
+            self.ctx.bytes_read += *n as u64;
+        }
+        self.track_idle(ret)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        common::read_vectored(self, bufs)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ctx.strict && self.session.is_handshaking() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "read before the handshake completed"));
+        }
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+
+        if let Some(len) = common::read_buffered(&mut self.read_buf, buf) {
+            return Ok(len);
+        }
+
         match self.state {
             #[cfg(feature = "early-data")]
             TlsState::EarlyData => {
                 {
-                    let mut stream = Stream::new(&mut self.io, &mut self.session);
+                    let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx);
                     let (pos, data) = &mut self.early_data;
 
                     // complete handshake
                     if stream.session.is_handshaking() {
                         stream.complete_io()?;
+                        self.sent_early_data |= !data.is_empty();
                     }
 
                     // write early data (fallback)
@@ -100,21 +1034,28 @@ where
                     data.clear();
                 }
 
-                self.read(buf)
+                self.read_plaintext(buf)
             }
             TlsState::Stream | TlsState::WriteShutdown => {
-                let mut stream = Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+                let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+                    .set_eof(!self.state.readable());
 
+                // An empty `buf` still pulls records in, and what they
+                // decrypt to waits in the session for the next read.
                 match stream.read(buf) {
-                    Ok(0) => {
+                    Ok(0) if !buf.is_empty() && stream.ctx.require_clean_close => Err(common::truncated()),
+                    Ok(0) if !buf.is_empty() => {
                         self.state.shutdown_read();
                         Ok(0)
                     }
                     Ok(n) => Ok(n),
                     Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
                         self.state.shutdown_read();
+                        stream.ctx.peer_closed = true;
                         if self.state.writeable() {
-                            stream.session.send_close_notify();
+                            if stream.ctx.close_notify_on_eof {
+                                stream.session.send_close_notify();
+                            }
                             self.state.shutdown_write();
                         }
                         Ok(0)
@@ -125,14 +1066,24 @@ where
             TlsState::ReadShutdown | TlsState::FullyShutdown => Ok(0),
         }
     }
-}
 
-impl<IO> io::Write for TlsStream<IO>
-where
-    IO: AsyncRead + AsyncWrite,
-{
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut stream = Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_plaintext_with(buf, false)
+    }
+
+    /// `write_plaintext`, leaving the records queued in the session when
+    /// `hold` is set, for the caller to add to before flushing.
+    fn write_plaintext_with(&mut self, buf: &[u8], hold: bool) -> io::Result<usize> {
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+        if !self.state.writeable() || !self.ctx.half_close && !self.state.readable() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable())
+            .set_hold(hold);
 
         match self.state {
             #[cfg(feature = "early-data")]
@@ -143,12 +1094,14 @@ where
                 if let Some(mut early_data) = stream.session.early_data() {
                     let len = early_data.write(buf)?;
                     data.extend_from_slice(&buf[..len]);
+                    stream.ctx.unflushed |= len != 0;
                     return Ok(len);
                 }
 
                 // complete handshake
                 if stream.session.is_handshaking() {
                     stream.complete_io()?;
+                    self.sent_early_data |= !data.is_empty();
                 }
 
                 // write early data (fallback)
@@ -168,11 +1121,160 @@ where
         }
     }
 
+    /// Cut `len` down to what the transfer limit still lets through,
+    /// closing the connection once that is nothing.
+    fn transfer_budget(&mut self, len: usize) -> io::Result<usize> {
+        match self.ctx.transfer_budget() {
+            None => Ok(len),
+            Some(0) => {
+                self.state.shutdown_read();
+                if self.state.writeable() {
+                    self.session.send_close_notify();
+                    self.state.shutdown_write();
+                }
+                let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx).flush();
+                Err(io::Error::other("transfer limit reached"))
+            }
+            Some(budget) => Ok(cmp::min(len, budget)),
+        }
+    }
+
+    /// Write out the writes held back by `set_write_coalesce`, if they are
+    /// due or `force`.
+    #[cfg(feature = "coalesce")]
+    fn push_coalesced(&mut self, force: bool) -> io::Result<()> {
+        let mut coalesce = match self.coalesce.take() {
+            Some(coalesce) => coalesce,
+            None => return Ok(()),
+        };
+        let ret = coalesce.push(force, |buf| self.write_plaintext(buf));
+        if !coalesce.is_spent() {
+            self.coalesce = Some(coalesce);
+        }
+        ret
+    }
+
+    #[cfg(not(feature = "coalesce"))]
+    #[inline]
+    fn push_coalesced(&mut self, _: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// `write_plaintext`, holding `buf` back instead while coalescing.
+    #[cfg(feature = "coalesce")]
+    fn write_coalesced(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.coalesce.is_none() || self.session.is_handshaking() || !self.state.writeable() {
+            return self.write_plaintext(buf);
+        }
+
+        // what is due goes first, but a full IO is no reason to refuse more
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            ret => ret?,
+        }
+        let coalesce = match &mut self.coalesce {
+            Some(coalesce) if !coalesce.is_spent() => coalesce,
+            _ => {
+                self.push_coalesced(true)?;
+                return self.write_plaintext(buf);
+            }
+        };
+
+        let len = coalesce.hold(buf);
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => (),
+            ret => ret?,
+        }
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(len)
+    }
+
+    #[cfg(not(feature = "coalesce"))]
+    #[inline]
+    fn write_coalesced(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_plaintext(buf)
+    }
+
+    /// Restart the idle timer after progress, or check it while blocked.
+    #[cfg(feature = "idle-timeout")]
+    fn track_idle<T>(&mut self, ret: io::Result<T>) -> io::Result<T> {
+        let idle = match &mut self.idle {
+            Some(idle) => idle,
+            None => return ret,
+        };
+
+        match ret {
+            Ok(_) => idle.reset(),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if idle.poll_expired()? {
+                    self.idle = None;
+                    self.state.shutdown_read();
+                    if self.state.writeable() {
+                        self.session.send_close_notify();
+                        self.state.shutdown_write();
+                    }
+                    let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx).flush();
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+            Err(_) => (),
+        }
+
+        ret
+    }
+
+    #[cfg(not(feature = "idle-timeout"))]
+    #[inline]
+    fn track_idle<T>(&mut self, ret: io::Result<T>) -> io::Result<T> {
+        ret
+    }
+
+    /// Give up on flushing close_notify once the close timeout is up.
+    #[cfg(feature = "idle-timeout")]
+    fn poll_close_timeout(&mut self) -> Poll<(), io::Error> {
+        let timer = match &mut self.close_timer {
+            Some(timer) => timer,
+            None => return Ok(Async::NotReady),
+        };
+        if !timer.poll_expired()? {
+            return Ok(Async::NotReady);
+        }
+        let action = timer.action;
+
+        futures::try_ready!(self.io.shutdown());
+        match action {
+            CloseTimeoutAction::Finish => Ok(Async::Ready(())),
+            CloseTimeoutAction::Fail => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl<IO> io::Write for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.transfer_budget(buf.len())?;
+        let wire = self.ctx.records_written.bytes;
+        let ret = self.write_coalesced(&buf[..len]);
+        self.ctx.last_write_wire_bytes = (self.ctx.records_written.bytes - wire) as usize;
+        if let Ok(n) = ret {
+            self.ctx.bytes_written += n as u64;
+        }
+        self.track_idle(ret)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
-        Stream::new(&mut self.io, &mut self.session)
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+        self.push_coalesced(true)?;
+        Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
             .set_eof(!self.state.readable())
             .flush()?;
-        self.io.flush()
+        common::retry_interrupted(|| self.io.flush())
     }
 }
 
@@ -190,13 +1292,21 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.push_coalesced(true));
         if self.state.writeable() {
             self.session.send_close_notify();
             self.state.shutdown_write();
         }
 
-        let mut stream = Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
-        try_nb!(stream.flush());
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        match stream.flush() {
+            #[cfg(feature = "idle-timeout")]
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && self.close_timer.is_some() => {
+                return self.poll_close_timeout();
+            }
+            ret => try_nb!(ret),
+        }
         stream.io.shutdown()
     }
 }
@@ -0,0 +1,92 @@
+//! Holding back small writes so that a burst of them goes out as fewer,
+//! larger records.
+
+use std::io;
+use std::time::{ Duration, Instant };
+use futures::{ task, Async, Future };
+use tokio_timer::Delay;
+
+/// The most plaintext a TLS record carries.
+const MAX_RECORD: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct Coalescer {
+    window: Duration,
+    pending: Vec<u8>,
+    deadline: Option<Instant>,
+    delay: Option<Delay>,
+}
+
+impl Coalescer {
+    pub(crate) fn new(window: Duration) -> Coalescer {
+        Coalescer { window, pending: Vec::new(), deadline: None, delay: None }
+    }
+
+    /// Change the window, a zero one turning coalescing off once what is
+    /// held has been written.
+    pub(crate) fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Whether coalescing is off and nothing is held, so the coalescer can go.
+    pub(crate) fn is_spent(&self) -> bool {
+        self.window == Duration::from_secs(0) && self.pending.is_empty()
+    }
+
+    /// Hold as much of `buf` as fits in one record, starting the window
+    /// with the first byte held.
+    pub(crate) fn hold(&mut self, buf: &[u8]) -> usize {
+        let len = buf.len().min(MAX_RECORD - self.pending.len());
+        if self.pending.is_empty() && len > 0 {
+            let deadline = Instant::now() + self.window;
+            self.deadline = Some(deadline);
+            self.delay = Some(Delay::new(deadline));
+        }
+        self.pending.extend_from_slice(&buf[..len]);
+        len
+    }
+
+    /// Whether what is held is to be written out now: it fills a record or
+    /// the window is up.
+    ///
+    /// Otherwise, from within a task, the task is woken once the window is
+    /// up. Without a tokio timer to do that nothing is held back.
+    fn is_due(&mut self) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        if self.pending.len() >= MAX_RECORD || self.deadline.is_none_or(|at| Instant::now() >= at) {
+            return true;
+        }
+
+        match &mut self.delay {
+            Some(delay) if task::is_in_task() => match delay.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) | Err(_) => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// Write out what is held through `write`, if it is due or `force`.
+    ///
+    /// What `write` does not take stays held, ahead of later writes.
+    pub(crate) fn push<F>(&mut self, force: bool, mut write: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<usize>,
+    {
+        if !force && !self.is_due() {
+            return Ok(());
+        }
+
+        while !self.pending.is_empty() {
+            match write(&self.pending)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => drop(self.pending.drain(..n)),
+            }
+        }
+        self.deadline = None;
+        self.delay = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! Just enough DER to pick fields out of an X.509 certificate.
+
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+const SEQUENCE: u8 = 0x30;
+const CONTEXT_0: u8 = 0xa0;
+const CONTEXT_3: u8 = 0xa3;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+#[cfg(feature = "dangerous_configuration")]
+const INTEGER: u8 = 0x02;
+const DNS_NAME: u8 = 0x82;
+/// id-ce-subjectAltName, 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+#[cfg(feature = "dangerous_configuration")]
+/// id-pe-tlsfeature, 1.3.6.1.5.5.7.1.24
+const TLS_FEATURE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+#[cfg(feature = "dangerous_configuration")]
+/// The status_request TLS extension, as a TLS feature.
+const STATUS_REQUEST: &[u8] = &[0x05];
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Split the first TLV off `input`, returning `(tag, tlv, rest)`.
+fn split(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)?;
+    let (header, len) = if first < 0x80 {
+        (2, usize::from(first))
+    } else {
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input.get(2..2 + n)?
+            .iter()
+            .fold(0, |len, &b| (len << 8) | usize::from(b));
+        (2 + n, len)
+    };
+
+    let end = header.checked_add(len)?;
+    let tlv = input.get(..end)?;
+    Some((tag, tlv, &input[end..]))
+}
+
+fn contents(tlv: &[u8]) -> &[u8] {
+    let header = if tlv[1] < 0x80 { 2 } else { 2 + usize::from(tlv[1] & 0x7f) };
+    &tlv[header..]
+}
+
+/// The fields of a certificate's TBSCertificate, starting at serialNumber.
+fn tbs_fields(cert: &[u8]) -> Option<&[u8]> {
+    let (tag, cert, _) = split(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, tbs, _) = split(contents(cert))?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    let fields = contents(tbs);
+    if fields.first() == Some(&CONTEXT_0) {
+        Some(split(fields)?.2)
+    } else {
+        Some(fields)
+    }
+}
+
+/// The DER encoded SubjectPublicKeyInfo of a certificate.
+pub fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let mut fields = tbs_fields(cert)?;
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        fields = split(fields)?.2;
+    }
+
+    match split(fields)? {
+        (SEQUENCE, spki, _) => Some(spki),
+        _ => None,
+    }
+}
+
+/// The extnValue contents of the certificate's extension `id`.
+fn extension<'a>(cert: &'a [u8], id: &[u8]) -> Option<&'a [u8]> {
+    let mut fields = tbs_fields(cert)?;
+    // serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo
+    for _ in 0..6 {
+        fields = split(fields)?.2;
+    }
+
+    // skip issuerUniqueID and subjectUniqueID
+    let extensions = loop {
+        let (tag, field, rest) = split(fields)?;
+        if tag == CONTEXT_3 {
+            break field;
+        }
+        fields = rest;
+    };
+    let (tag, extensions, _) = split(contents(extensions))?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    let mut extensions = contents(extensions);
+    while !extensions.is_empty() {
+        let (_, extension, rest) = split(extensions)?;
+        extensions = rest;
+        let (tag, oid, rest) = split(contents(extension))?;
+        if tag != OID || contents(oid) != id {
+            continue;
+        }
+
+        // skip critical
+        let (mut tag, mut value, rest) = split(rest)?;
+        if tag != OCTET_STRING {
+            let next = split(rest)?;
+            tag = next.0;
+            value = next.1;
+        }
+        return match tag {
+            OCTET_STRING => Some(contents(value)),
+            _ => None,
+        };
+    }
+    None
+}
+
+/// The dNSName entries of a certificate's subjectAltName, if it has one.
+pub fn dns_names(cert: &[u8]) -> Option<Vec<&[u8]>> {
+    let (tag, names, _) = split(extension(cert, SUBJECT_ALT_NAME)?)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    let mut names = contents(names);
+    let mut out = Vec::new();
+    while !names.is_empty() {
+        let (tag, name, rest) = split(names)?;
+        if tag == DNS_NAME {
+            out.push(contents(name));
+        }
+        names = rest;
+    }
+    Some(out)
+}
+
+#[cfg(feature = "dangerous_configuration")]
+/// Whether a certificate has the TLS feature extension asking for
+/// status_request, aka OCSP must-staple (RFC 7633).
+pub fn must_staple(cert: &[u8]) -> bool {
+    let features = match extension(cert, TLS_FEATURE).and_then(split) {
+        Some((SEQUENCE, features, _)) => features,
+        _ => return false,
+    };
+
+    let mut features = contents(features);
+    while let Some((tag, feature, rest)) = split(features) {
+        if tag == INTEGER && contents(feature) == STATUS_REQUEST {
+            return true;
+        }
+        features = rest;
+    }
+    false
+}
+
+/// The notBefore and notAfter of a certificate.
+pub fn validity(cert: &[u8]) -> Option<(SystemTime, SystemTime)> {
+    let mut fields = tbs_fields(cert)?;
+    // serialNumber, signature, issuer
+    for _ in 0..3 {
+        fields = split(fields)?.2;
+    }
+
+    let (tag, validity, _) = split(fields)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, not_before, rest) = split(contents(validity))?;
+    let not_before = time(tag, contents(not_before))?;
+    let (tag, not_after, _) = split(rest)?;
+    let not_after = time(tag, contents(not_after))?;
+    Some((not_before, not_after))
+}
+
+/// Decode a UTCTime or GeneralizedTime, which RFC 5280 restricts to
+/// `YYMMDDHHMMSSZ` and `YYYYMMDDHHMMSSZ`.
+fn time(tag: u8, value: &[u8]) -> Option<SystemTime> {
+    let digits = match (tag, value.split_last()) {
+        (UTC_TIME, Some((b'Z', digits))) if digits.len() == 12 => digits,
+        (GENERALIZED_TIME, Some((b'Z', digits))) if digits.len() == 14 => digits,
+        _ => return None,
+    };
+    let mut pairs = digits.chunks(2)
+        .map(|pair| match *pair {
+            [a @ b'0'..=b'9', b @ b'0'..=b'9'] => Some(u64::from(a - b'0') * 10 + u64::from(b - b'0')),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?
+        .into_iter();
+
+    let year = match tag {
+        // RFC 5280 4.1.2.5.1: two digit years are 1950 through 2049
+        UTC_TIME => match pairs.next()? {
+            yy if yy >= 50 => 1900 + yy,
+            yy => 2000 + yy,
+        },
+        _ => pairs.next()? * 100 + pairs.next()?,
+    };
+    let (month, day) = (pairs.next()?, pairs.next()?);
+    let (hour, minute, second) = (pairs.next()?, pairs.next()?, pairs.next()?);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+/// Days from 1970-01-01 to the given civil date, for years from 1970.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Howard Hinnant's days_from_civil, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
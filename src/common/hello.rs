@@ -0,0 +1,216 @@
+//! Keeping the ClientHello around after rustls consumed it, and the
+//! group a server's own hellos picked.
+
+use rustls::internal::msgs::codec::{ Codec, Reader };
+use rustls::internal::msgs::handshake::ClientHelloPayload;
+
+const CHANGE_CIPHER_SPEC: u8 = 0x14;
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_HELLO: u8 = 0x02;
+const SERVER_KEY_EXCHANGE: u8 = 0x0c;
+const SERVER_HELLO_DONE: u8 = 0x0e;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+const KEY_SHARE: u16 = 0x0033;
+const NAMED_CURVE: u8 = 3;
+/// The random of a ServerHello that is a HelloRetryRequest.
+const RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+/// Give up on a ClientHello larger than this.
+const LIMIT: usize = 64 * 1024;
+
+/// Collects the records read from a client until they carry the whole
+/// ClientHello, then keeps just its body.
+#[derive(Debug, Default)]
+pub struct HelloCapture {
+    bytes: Vec<u8>,
+    done: bool,
+}
+
+impl HelloCapture {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.done {
+            return;
+        }
+
+        self.bytes.extend_from_slice(bytes);
+        match body(&self.bytes) {
+            Ok(Some(body)) => {
+                self.bytes = body;
+                self.done = true;
+            }
+            Ok(None) if self.bytes.len() <= LIMIT => (),
+            _ => {
+                self.bytes = Vec::new();
+                self.done = true;
+            }
+        }
+    }
+
+    /// The ClientHello, once it was read whole.
+    pub fn get(&self) -> Option<ClientHelloPayload> {
+        if !self.done || self.bytes.is_empty() {
+            return None;
+        }
+        ClientHelloPayload::read(&mut Reader::init(&self.bytes))
+    }
+}
+
+/// The body of the ClientHello carried by `records`, `None` while some of
+/// it is still to come, and an error if they do not start with one.
+fn body(mut records: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+    let mut handshake = Vec::new();
+    while records.len() >= 5 {
+        if records[0] != HANDSHAKE {
+            return Err(());
+        }
+        let len = (usize::from(records[3]) << 8) | usize::from(records[4]);
+        let fragment = match records.get(5..5 + len) {
+            Some(fragment) => fragment,
+            None => break,
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + len..];
+
+        if handshake.len() >= 4 {
+            if handshake[0] != CLIENT_HELLO {
+                return Err(());
+            }
+            let len = (usize::from(handshake[1]) << 16)
+                | (usize::from(handshake[2]) << 8)
+                | usize::from(handshake[3]);
+            if handshake.len() >= 4 + len {
+                return Ok(Some(handshake[4..4 + len].to_vec()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Follows the records a server writes until its hellos show the group of
+/// the key exchange: the ServerHello key share for TLS 1.3 and the curve
+/// of the ServerKeyExchange for TLS 1.2.
+///
+/// Resumption without a key exchange, where neither is sent, leaves no
+/// group.
+#[derive(Debug, Default)]
+pub struct KexCapture {
+    records: Vec<u8>,
+    handshake: Vec<u8>,
+    /// A TLS 1.2 ServerHello was seen, the ServerKeyExchange is to come.
+    tls12: bool,
+    group: Option<u16>,
+    done: bool,
+}
+
+impl KexCapture {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.done {
+            return;
+        }
+
+        self.records.extend_from_slice(bytes);
+        let mut pos = 0;
+        while !self.done && self.records.len() - pos >= 5 {
+            let header = &self.records[pos..pos + 5];
+            let len = (usize::from(header[3]) << 8) | usize::from(header[4]);
+            let fragment = match self.records.get(pos + 5..pos + 5 + len) {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            match header[0] {
+                HANDSHAKE => self.handshake.extend_from_slice(fragment),
+                // a compatibility record after a HelloRetryRequest, or the
+                // end of the plaintext in TLS 1.2
+                CHANGE_CIPHER_SPEC => self.done = self.tls12,
+                _ => self.done = true,
+            }
+            pos += 5 + len;
+            self.parse();
+        }
+        self.records.drain(..pos);
+        if self.records.len() + self.handshake.len() > LIMIT {
+            self.done = true;
+        }
+        if self.done {
+            self.records = Vec::new();
+            self.handshake = Vec::new();
+        }
+    }
+
+    /// The codepoint of the group, once the hellos are through.
+    pub fn get(&self) -> Option<u16> {
+        if self.done { self.group } else { None }
+    }
+
+    /// Go through the whole handshake messages collected so far.
+    fn parse(&mut self) {
+        while !self.done && self.handshake.len() >= 4 {
+            let len = (usize::from(self.handshake[1]) << 16)
+                | (usize::from(self.handshake[2]) << 8)
+                | usize::from(self.handshake[3]);
+            if self.handshake.len() < 4 + len {
+                return;
+            }
+            let message: Vec<u8> = self.handshake.drain(..4 + len).collect();
+            let body = &message[4..];
+            match message[0] {
+                SERVER_HELLO => match server_hello(body) {
+                    // a HelloRetryRequest, the ServerHello is still to come
+                    Some(Hello::Retry) => (),
+                    Some(Hello::Tls13(group)) => {
+                        self.group = group;
+                        self.done = true;
+                    }
+                    Some(Hello::Tls12) => self.tls12 = true,
+                    None => self.done = true,
+                },
+                SERVER_KEY_EXCHANGE if self.tls12 => {
+                    if body.first() == Some(&NAMED_CURVE) && body.len() >= 3 {
+                        self.group = Some(u16::from_be_bytes([body[1], body[2]]));
+                    }
+                    self.done = true;
+                }
+                SERVER_HELLO_DONE => self.done = true,
+                _ => (),
+            }
+        }
+    }
+}
+
+enum Hello {
+    Retry,
+    Tls13(Option<u16>),
+    Tls12,
+}
+
+/// What a ServerHello body says about the key exchange, `None` if it is
+/// malformed.
+fn server_hello(body: &[u8]) -> Option<Hello> {
+    let random = body.get(2..34)?;
+    let session_id = usize::from(*body.get(34)?);
+    // the cipher suite and compression method
+    let mut rest = body.get(35 + session_id + 3..)?;
+    if random == RETRY_RANDOM {
+        return Some(Hello::Retry);
+    }
+
+    let (mut tls13, mut group) = (false, None);
+    if rest.len() >= 2 {
+        rest = &rest[2..];
+        while rest.len() >= 4 {
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+            let data = rest.get(4..4 + len)?;
+            match kind {
+                SUPPORTED_VERSIONS => tls13 = true,
+                KEY_SHARE if data.len() >= 2 => group = Some(u16::from_be_bytes([data[0], data[1]])),
+                _ => (),
+            }
+            rest = &rest[4 + len..];
+        }
+    }
+    Some(if tls13 { Hello::Tls13(group) } else { Hello::Tls12 })
+}
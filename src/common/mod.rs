@@ -1,14 +1,411 @@
 mod vecbuf;
+pub mod der;
+pub mod hello;
+pub mod verify;
 
+use std::cmp;
+use std::fmt;
+use std::convert::TryFrom;
 use std::io::{ self, Read, Write };
-use rustls::Session;
+use std::ops::{ Deref, DerefMut };
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::Instant;
+use rustls::{ Session, TLSError };
 use rustls::WriteV;
-use tokio_io::{ AsyncRead, AsyncWrite };
+use futures::{ Async, Poll };
+use tokio_io::{ try_nb, AsyncRead, AsyncWrite };
+use { HandshakeTooLarge, Interest, WritePolicy, WriteSemantics };
 
 pub struct Stream<'a, IO: 'a, S: 'a> {
     pub io: &'a mut IO,
     pub session: &'a mut S,
+    pub ctx: &'a mut Context,
     pub eof: bool,
+    /// Leave the written records queued in the session for the caller's
+    /// flush instead of draining them in `write`.
+    pub hold: bool,
+}
+
+/// A field of a `TlsStream` that `into_inner` can move out despite its
+/// `Drop`, and that is always full before that.
+#[derive(Debug)]
+pub struct Slot<T>(Option<T>);
+
+impl<T> Slot<T> {
+    pub fn new(value: T) -> Slot<T> {
+        Slot(Some(value))
+    }
+
+    pub fn take(&mut self) -> T {
+        self.0.take().expect("TlsStream field already taken")
+    }
+}
+
+impl<T> Deref for Slot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().expect("TlsStream field already taken")
+    }
+}
+
+impl<T> DerefMut for Slot<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().expect("TlsStream field already taken")
+    }
+}
+
+/// Per-connection state a `TlsStream` keeps across the `Stream`s built over it.
+#[derive(Debug)]
+pub struct Context {
+    /// Unique to the connection within the process.
+    pub id: u64,
+    pub error_kind: fn(&TLSError) -> io::ErrorKind,
+    pub strict: bool,
+    pub write_policy: WritePolicy,
+    pub write_semantics: WriteSemantics,
+    /// Plaintext accepted by a `WriteSemantics::OnWire` write that is yet
+    /// to be reported.
+    pub unreported: Option<usize>,
+    pub half_close: bool,
+    pub close_notify_on_eof: bool,
+    /// Set once the peer's close_notify has been read.
+    pub peer_closed: bool,
+    pub interest: Option<Interest>,
+    pub records_read: RecordCounter,
+    pub records_written: RecordCounter,
+    /// Plaintext bytes handed out by reads and accepted by writes.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Set by writes, and cleared once every record has been written out.
+    pub unflushed: bool,
+    /// Bytes read from the IO but held back from the session, which only
+    /// gets whole records: the start of an incomplete one, and with
+    /// `record_reads` the records after the first.
+    pub partial_tls: Vec<u8>,
+    /// Hand the session one record per read, so that each read returns
+    /// the plaintext of at most one record.
+    pub record_reads: bool,
+    /// Fail reads reaching EOF without a close_notify, rather than
+    /// returning `Ok(0)`.
+    pub require_clean_close: bool,
+    /// Keep from reading the IO, leaving what the peer sends to the OS.
+    pub reads_paused: bool,
+    /// The first records read, kept by servers recording hellos until they
+    /// hold the ClientHello.
+    pub client_hello: Option<hello::HelloCapture>,
+    /// The first records written, followed by servers recording hellos
+    /// until their hellos show the group of the key exchange.
+    pub server_kex: Option<hello::KexCapture>,
+    /// The error rustls last failed with.
+    pub last_tls_error: Option<TLSError>,
+    /// Plaintext bytes read and written together after which the
+    /// connection is closed.
+    pub transfer_limit: Option<u64>,
+    /// When the handshake finished.
+    pub established_at: Option<Instant>,
+    /// Refuse plaintext IO until `poll_handshake` has finished the handshake.
+    pub explicit_handshake: bool,
+    /// The limit last given to `Session::set_buffer_limit`, zero for none.
+    pub buffer_limit: usize,
+    /// Set by a client's certificate resolver once it gives rustls a
+    /// certificate to send, if the config has any.
+    pub client_cert_sent: Option<Arc<AtomicBool>>,
+    /// The name a client connected to, if its connector keeps it.
+    pub server_name: Option<::webpki::DNSName>,
+    /// The sessions a client's store was given, if its connector makes
+    /// them exportable.
+    pub session_record: Option<SessionRecord>,
+    /// TLS bytes read until the handshake is done after which it fails,
+    /// zero for no limit.
+    pub max_handshake_size: usize,
+    /// TLS bytes the last plaintext write put on the IO.
+    pub last_write_wire_bytes: usize,
+    /// Set by a client's certificate verifier to the OCSP response the
+    /// server stapled, if the connector records it.
+    #[cfg(feature = "dangerous_configuration")]
+    pub stapled_ocsp: Option<Arc<Mutex<Option<Vec<u8>>>>>,
+    /// What a client checks the server's chain against again, if its
+    /// connector rechecks it.
+    #[cfg(feature = "dangerous_configuration")]
+    pub peer_check: Option<verify::PeerCheck>,
+}
+
+impl Context {
+    /// How much plaintext the transfer limit still lets through, if there
+    /// is one.
+    pub fn transfer_budget(&self) -> Option<usize> {
+        let limit = self.transfer_limit?;
+        let left = limit.saturating_sub(self.bytes_read + self.bytes_written);
+        Some(usize::try_from(left).unwrap_or(usize::MAX))
+    }
+
+    /// Account for TLS `bytes` that went out on the IO.
+    fn note_written(&mut self, bytes: &[u8]) {
+        self.records_written.feed(bytes);
+        if let Some(kex) = &mut self.server_kex {
+            kex.feed(bytes);
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Context {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            error_kind: invalid_data,
+            strict: false,
+            write_policy: WritePolicy::Eager,
+            write_semantics: WriteSemantics::Buffered,
+            unreported: None,
+            half_close: true,
+            close_notify_on_eof: true,
+            peer_closed: false,
+            interest: None,
+            records_read: RecordCounter::default(),
+            records_written: RecordCounter::default(),
+            bytes_read: 0,
+            bytes_written: 0,
+            unflushed: false,
+            partial_tls: Vec::new(),
+            record_reads: false,
+            require_clean_close: false,
+            reads_paused: false,
+            client_hello: None,
+            server_kex: None,
+            last_tls_error: None,
+            transfer_limit: None,
+            established_at: None,
+            explicit_handshake: false,
+            buffer_limit: 0,
+            client_cert_sent: None,
+            server_name: None,
+            session_record: None,
+            max_handshake_size: 0,
+            last_write_wire_bytes: 0,
+            #[cfg(feature = "dangerous_configuration")]
+            stapled_ocsp: None,
+            #[cfg(feature = "dangerous_configuration")]
+            peer_check: None,
+        }
+    }
+}
+
+/// The keys a client's session put in its store, each with the value it
+/// put last.
+pub type SessionPuts = Arc<Mutex<Vec<(Vec<u8>, Vec<u8>)>>>;
+
+/// The sessions a client put in its store during one connection.
+pub struct SessionRecord {
+    pub server_name: ::webpki::DNSName,
+    pub puts: SessionPuts,
+}
+
+impl fmt::Debug for SessionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionRecord")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+/// Counts the TLS records passing through a byte stream by following their headers.
+#[derive(Debug, Default)]
+pub struct RecordCounter {
+    pub count: u64,
+    /// Bytes passed through, headers and all.
+    pub bytes: u64,
+    header: [u8; 5],
+    header_len: usize,
+    remaining: usize,
+}
+
+impl RecordCounter {
+    pub fn feed(&mut self, mut bytes: &[u8]) {
+        self.bytes += bytes.len() as u64;
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let len = cmp::min(self.remaining, bytes.len());
+                self.remaining -= len;
+                bytes = &bytes[len..];
+                continue;
+            }
+
+            let len = cmp::min(self.header.len() - self.header_len, bytes.len());
+            self.header[self.header_len..][..len].copy_from_slice(&bytes[..len]);
+            self.header_len += len;
+            bytes = &bytes[len..];
+
+            if self.header_len == self.header.len() {
+                self.count += 1;
+                self.remaining = (usize::from(self.header[3]) << 8) | usize::from(self.header[4]);
+                self.header_len = 0;
+            }
+        }
+    }
+}
+
+/// The length of the whole records `bytes` starts with, or of just the
+/// first one if `one`.
+fn whole_records(bytes: &[u8], one: bool) -> usize {
+    let mut len = 0;
+    while bytes.len() - len >= 5 {
+        let record = 5 + ((usize::from(bytes[len + 3]) << 8) | usize::from(bytes[len + 4]));
+        if len + record > bytes.len() {
+            break;
+        }
+        len += record;
+        if one {
+            break;
+        }
+    }
+    len
+}
+
+/// The error plaintext IO fails with while the handshake is unfinished, if
+/// it may not drive it.
+pub fn handshake_pending() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "handshake not completed, call poll_handshake first")
+}
+
+/// The error a read fails with when `require_clean_close` is set and the
+/// IO ends without a close_notify.
+pub fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed without a close_notify")
+}
+
+/// The default mapping of rustls errors, which reports all of them as `InvalidData`.
+pub fn invalid_data(_: &TLSError) -> io::ErrorKind {
+    io::ErrorKind::InvalidData
+}
+
+/// Whether `err` is a client refusing a post-handshake CertificateRequest,
+/// which rustls does not support.
+pub fn is_post_handshake_auth(err: &TLSError) -> bool {
+    use rustls::internal::msgs::enums::HandshakeType;
+
+    match err {
+        TLSError::InappropriateHandshakeMessage { got_type: HandshakeType::CertificateRequest, expect_types } =>
+            expect_types.contains(&HandshakeType::KeyUpdate),
+        _ => false,
+    }
+}
+
+/// `certs` as a bundle of PEM `CERTIFICATE` blocks, in order, each line
+/// ending with `\n`.
+pub fn pem_bundle(certs: &[rustls::Certificate]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut pem = String::new();
+    for cert in certs {
+        let mut encoded = Vec::with_capacity(cert.0.len().div_ceil(3) * 4);
+        for chunk in cert.0.chunks(3) {
+            let n = chunk.iter().enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..4 {
+                encoded.push(if i <= chunk.len() {
+                    ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]
+                } else {
+                    b'='
+                });
+            }
+        }
+
+        pem.push_str("-----BEGIN CERTIFICATE-----\n");
+        for line in encoded.chunks(64) {
+            pem.extend(line.iter().map(|&b| char::from(b)));
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+    }
+    pem
+}
+
+/// A stand-in for the handshake transcript hash, which rustls keeps to
+/// itself: the RFC 9266 `tls-exporter` channel binding, which is derived
+/// from it.
+pub fn transcript_binding(session: &dyn Session) -> Option<Vec<u8>> {
+    if session.is_handshaking() {
+        return None;
+    }
+
+    let mut out = vec![0; 32];
+    session.export_keying_material(&mut out, b"EXPORTER-Channel-Binding", None).ok()?;
+    Some(out)
+}
+
+/// Default cap on encrypted data buffered ahead of the IO.
+pub const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// Size of the buffer filled by `poll_fill_buf`, the largest TLS record payload.
+pub const READ_BUF_SIZE: usize = 16 * 1024;
+
+/// Copy plaintext left over from `poll_fill_buf` into `buf`.
+///
+/// Returns `None` once that buffer has been fully consumed.
+pub fn read_buffered(read_buf: &mut (usize, Vec<u8>), buf: &mut [u8]) -> Option<usize> {
+    let (pos, data) = read_buf;
+    if *pos < data.len() {
+        let len = cmp::min(buf.len(), data.len() - *pos);
+        buf[..len].copy_from_slice(&data[*pos..*pos + len]);
+        *pos += len;
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Fill `bufs` in turn with reads from `reader`.
+///
+/// Stops short at the first read that returns less than asked, would block,
+/// or fails, reporting only what was filled so far unless nothing was.
+pub fn read_vectored<R: Read>(reader: &mut R, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+    let mut total = 0;
+    for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+        match reader.read(buf) {
+            Ok(n) if n == buf.len() => total += n,
+            Ok(n) => return Ok(total + n),
+            Err(_) if total > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+/// Run `f` again for as long as it fails with `Interrupted`, which async
+/// IO is expected to hide.
+pub fn retry_interrupted<T, F: FnMut() -> io::Result<T>>(mut f: F) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            ret => return ret,
+        }
+    }
+}
+
+/// Resolve once a read has something to work on: TLS already held back in
+/// `ctx`, or bytes or EOF from `io`, which are kept undecrypted for the
+/// next read.
+pub fn poll_read_ready<IO: Read>(io: &mut IO, ctx: &mut Context) -> Poll<(), io::Error> {
+    if !ctx.partial_tls.is_empty() {
+        return Ok(Async::Ready(()));
+    }
+    if ctx.reads_paused {
+        return Ok(Async::NotReady);
+    }
+
+    let mut buf = [0; 4096];
+    let n = try_nb!(retry_interrupted(|| io.read(&mut buf)));
+    ctx.records_read.feed(&buf[..n]);
+    if let Some(hello) = &mut ctx.client_hello {
+        hello.feed(&buf[..n]);
+    }
+    ctx.partial_tls.extend_from_slice(&buf[..n]);
+    Ok(Async::Ready(()))
 }
 
 pub trait WriteTls<'a, IO: AsyncRead + AsyncWrite, S: Session>: Read + Write {
@@ -23,13 +420,15 @@ enum Focus {
 }
 
 impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
-    pub fn new(io: &'a mut IO, session: &'a mut S) -> Self {
+    pub fn new(io: &'a mut IO, session: &'a mut S, ctx: &'a mut Context) -> Self {
         Stream {
             io,
             session,
+            ctx,
             // The state so far is only used to detect EOF, so either Stream
             // or EarlyData state should both be all right.
             eof: false,
+            hold: false,
         }
     }
 
@@ -38,12 +437,98 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
         self
     }
 
+    pub fn set_hold(mut self, hold: bool) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    /// Forget about written plaintext once all of it has reached the IO.
+    fn note_drained(&mut self) {
+        if !self.session.wants_write() && !self.session.is_handshaking() {
+            self.ctx.unflushed = false;
+        }
+    }
+
     pub fn complete_io(&mut self) -> io::Result<(usize, usize)> {
         self.complete_inner_io(Focus::Empty)
     }
 
+    /// Drive the handshake, then flush whatever it left to be written.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        if self.session.is_handshaking() {
+            self.complete_io()?;
+        }
+
+        if self.session.wants_write() {
+            self.complete_io()?;
+        }
+
+        Ok(())
+    }
+
+    /// Push out the TLS the session is holding, without writing plaintext.
+    pub fn drain(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.complete_inner_io(Focus::Writable)?;
+        }
+        self.note_drained();
+        Ok(())
+    }
+
     fn complete_read_io(&mut self) -> io::Result<usize> {
-        let n = self.session.read_tls(self.io)?;
+        struct R<'a, IO: 'a>(&'a mut IO, &'a mut Context);
+
+        impl<'a, IO: Read> Read for R<'a, IO> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let R(io, ctx) = self;
+                let (held, one) = (&mut ctx.partial_tls, ctx.record_reads);
+
+                // Only hand whole records to the session, holding back the
+                // rest for later reads and `take_buffered_tls`.
+                loop {
+                    let fits = cmp::min(held.len(), buf.len());
+                    let ready = whole_records(&held[..fits], one);
+                    if ready > 0 || fits == buf.len() {
+                        let len = if ready > 0 { ready } else { fits };
+                        buf[..len].copy_from_slice(&held[..len]);
+                        held.drain(..len);
+                        return Ok(len);
+                    }
+                    if ctx.reads_paused {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+
+                    let start = held.len();
+                    buf[..start].copy_from_slice(held);
+
+                    let n = retry_interrupted(|| io.read(&mut buf[start..]))?;
+                    if n == 0 {
+                        held.clear();
+                        return Ok(start);
+                    }
+
+                    ctx.records_read.feed(&buf[start..][..n]);
+                    if let Some(hello) = &mut ctx.client_hello {
+                        hello.feed(&buf[start..][..n]);
+                    }
+                    let end = start + n;
+                    let ready = whole_records(&buf[..end], one);
+                    held.clear();
+                    held.extend_from_slice(&buf[ready..end]);
+
+                    if ready > 0 {
+                        return Ok(ready);
+                    }
+                }
+            }
+        }
+
+        let n = self.session.read_tls(&mut R(self.io, self.ctx))?;
+
+        let limit = self.ctx.max_handshake_size;
+        if limit != 0 && self.session.is_handshaking() && self.ctx.records_read.bytes > limit as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, HandshakeTooLarge { limit }));
+        }
 
         self.session.process_new_packets()
             .map_err(|err| {
@@ -52,9 +537,19 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
                 // error.
                 let _ = self.write_tls();
 
-                io::Error::new(io::ErrorKind::InvalidData, err)
+                let kind = if is_post_handshake_auth(&err) {
+                    io::ErrorKind::Unsupported
+                } else {
+                    (self.ctx.error_kind)(&err)
+                };
+                self.ctx.last_tls_error = Some(err.clone());
+                io::Error::new(kind, err)
             })?;
 
+        if self.ctx.established_at.is_none() && !self.session.is_handshaking() {
+            self.ctx.established_at = Some(Instant::now());
+        }
+
         Ok(n)
     }
 
@@ -65,6 +560,7 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
     fn complete_inner_io(&mut self, focus: Focus) -> io::Result<(usize, usize)> {
         let mut wrlen = 0;
         let mut rdlen = 0;
+        self.ctx.interest = None;
 
         loop {
             let mut write_would_block = false;
@@ -85,6 +581,7 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
                 if !write_would_block {
                     return Ok((rdlen, wrlen));
                 } else {
+                    self.ctx.interest = Some(Interest::Write);
                     return Err(io::ErrorKind::WouldBlock.into());
                 }
             }
@@ -120,13 +617,20 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
                     };
 
                     return if would_block {
+                        self.ctx.interest = Interest::from_blocked(read_would_block, write_would_block);
                         Err(io::ErrorKind::WouldBlock.into())
                     } else {
                         Ok((rdlen, wrlen))
                     };
                 },
                 (_, false, _) => return Ok((rdlen, wrlen)),
-                (_, true, true) => return Err(io::ErrorKind::WouldBlock.into()),
+                (_, true, true) => {
+                    self.ctx.interest = Interest::from_blocked(read_would_block, write_would_block);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                },
+                // Plaintext is waiting to be read before the session takes
+                // more records, so going round again would only spin.
+                (..) if !self.session.wants_read() => return Ok((rdlen, wrlen)),
                 (..) => ()
             }
         }
@@ -138,20 +642,37 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> WriteTls<'a, IO, S> for Stream<
         use futures::Async;
         use self::vecbuf::VecBuf;
 
-        struct V<'a, IO: 'a>(&'a mut IO);
+        struct V<'a, IO: 'a>(&'a mut IO, &'a mut Context);
 
         impl<'a, IO: AsyncWrite> WriteV for V<'a, IO> {
             fn writev(&mut self, vbytes: &[&[u8]]) -> io::Result<usize> {
-                let mut vbytes = VecBuf::new(vbytes);
-                match self.0.write_buf(&mut vbytes) {
-                    Ok(Async::Ready(n)) => Ok(n),
+                // A single record, as a small write leaves, needs no
+                // gathering, so skip the `Buf` machinery.
+                if let [bytes] = vbytes {
+                    let n = retry_interrupted(|| self.0.write(bytes))?;
+                    self.1.note_written(&bytes[..n]);
+                    return Ok(n);
+                }
+
+                let mut buf = VecBuf::new(vbytes);
+                let io = &mut *self.0;
+                match retry_interrupted(|| io.write_buf(&mut buf)) {
+                    Ok(Async::Ready(n)) => {
+                        let mut rest = n;
+                        for bytes in vbytes {
+                            let len = cmp::min(rest, bytes.len());
+                            self.1.note_written(&bytes[..len]);
+                            rest -= len;
+                        }
+                        Ok(n)
+                    },
                     Ok(Async::NotReady) => Err(io::ErrorKind::WouldBlock.into()),
                     Err(err) => Err(err)
                 }
             }
         }
 
-        let mut vecio = V(self.io);
+        let mut vecio = V(self.io, self.ctx);
         self.session.writev_tls(&mut vecio)
     }
 }
@@ -159,8 +680,18 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> WriteTls<'a, IO, S> for Stream<
 impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Read for Stream<'a, IO, S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         while self.session.wants_read() {
-            if let (0, _) = self.complete_inner_io(Focus::Readable)? {
-                break
+            match self.complete_inner_io(Focus::Readable) {
+                Ok((0, _)) => break,
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // A close_notify read earlier is only reported by the
+                    // session, so look there before blocking.
+                    return match self.session.read(buf) {
+                        Ok(0) => Err(io::ErrorKind::WouldBlock.into()),
+                        ret => ret,
+                    };
+                },
+                Err(err) => return Err(err)
             }
         }
         self.session.read(buf)
@@ -169,25 +700,51 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Read for Stream<'a, IO, S> {
 
 impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(len) = self.ctx.unreported {
+            while self.session.wants_write() {
+                self.complete_inner_io(Focus::Writable)?;
+            }
+            self.ctx.unreported = None;
+            return Ok(len);
+        }
+
+        if let WritePolicy::DrainFirst = self.ctx.write_policy {
+            while self.session.wants_write() {
+                self.complete_inner_io(Focus::Writable)?;
+            }
+        }
+
         let len = self.session.write(buf)?;
+        self.ctx.unflushed |= len != 0;
+        if self.hold && (len != 0 || buf.is_empty()) {
+            return Ok(len);
+        }
         while self.session.wants_write() {
             match self.complete_inner_io(Focus::Writable) {
                 Ok(_) => (),
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => break,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => {
+                    if let WriteSemantics::OnWire = self.ctx.write_semantics {
+                        self.ctx.unreported = Some(len);
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    break
+                },
                 Err(err) => return Err(err)
             }
         }
+        self.note_drained();
 
         if len != 0 || buf.is_empty() {
             Ok(len)
         } else {
             // not write zero
-            self.session.write(buf)
-                .and_then(|len| if len != 0 {
+            match self.session.write(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                len => {
+                    self.ctx.unflushed = true;
                     Ok(len)
-                } else {
-                    Err(io::ErrorKind::WouldBlock.into())
-                })
+                }
+            }
         }
     }
 
@@ -196,9 +753,10 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
         while self.session.wants_write() {
             self.complete_inner_io(Focus::Writable)?;
         }
+        self.note_drained();
         Ok(())
     }
 }
 
 #[cfg(test)]
-mod test_stream;
+pub(crate) mod test_stream;
@@ -5,13 +5,14 @@ use rustls::internal::pemfile::{ certs, rsa_private_keys };
 use rustls::{
     ServerConfig, ClientConfig,
     ServerSession, ClientSession,
-    Session, NoClientAuth
+    Session, NoClientAuth,
+    Certificate, PrivateKey
 };
 use futures::{ Async, Poll };
 use tokio_io::{ AsyncRead, AsyncWrite };
-use super::Stream;
+use super::{ Context, RecordCounter, Stream };
 
-struct Good<'a>(&'a mut Session);
+pub(crate) struct Good<'a>(pub(crate) &'a mut dyn Session);
 
 impl<'a> Read for Good<'a> {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
@@ -71,7 +72,7 @@ impl AsyncWrite for Bad {
 
 #[test]
 fn stream_good() -> io::Result<()> {
-    const FILE: &'static [u8] = include_bytes!("../../README.md");
+    const FILE: &[u8] = include_bytes!("../../README.md");
 
     let (mut server, mut client) = make_pair();
     do_handshake(&mut client, &mut server);
@@ -79,7 +80,8 @@ fn stream_good() -> io::Result<()> {
 
     {
         let mut good = Good(&mut server);
-        let mut stream = Stream::new(&mut good, &mut client);
+        let mut ctx = Context::default();
+        let mut stream = Stream::new(&mut good, &mut client, &mut ctx);
 
         let mut buf = Vec::new();
         stream.read_to_end(&mut buf)?;
@@ -101,7 +103,8 @@ fn stream_bad() -> io::Result<()> {
     client.set_buffer_limit(1024);
 
     let mut bad = Bad(true);
-    let mut stream = Stream::new(&mut bad, &mut client);
+    let mut ctx = Context::default();
+    let mut stream = Stream::new(&mut bad, &mut client, &mut ctx);
     assert_eq!(stream.write(&[0x42; 8])?, 8);
     assert_eq!(stream.write(&[0x42; 8])?, 8);
     let r = stream.write(&[0x00; 1024])?; // fill buffer
@@ -120,7 +123,8 @@ fn stream_handshake() -> io::Result<()> {
 
     {
         let mut good = Good(&mut server);
-        let mut stream = Stream::new(&mut good, &mut client);
+        let mut ctx = Context::default();
+        let mut stream = Stream::new(&mut good, &mut client, &mut ctx);
         let (r, w) = stream.complete_io()?;
 
         assert!(r > 0);
@@ -140,7 +144,8 @@ fn stream_handshake_eof() -> io::Result<()> {
     let (_, mut client) = make_pair();
 
     let mut bad = Bad(false);
-    let mut stream = Stream::new(&mut bad, &mut client);
+    let mut ctx = Context::default();
+    let mut stream = Stream::new(&mut bad, &mut client, &mut ctx);
     let r = stream.complete_io();
 
     assert_eq!(r.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
@@ -154,36 +159,67 @@ fn stream_eof() -> io::Result<()> {
     do_handshake(&mut client, &mut server);
     {
         let mut good = Good(&mut server);
-        let mut stream = Stream::new(&mut good, &mut client).set_eof(true);
+        let mut ctx = Context::default();
+        let mut stream = Stream::new(&mut good, &mut client, &mut ctx).set_eof(true);
         let (r, _) = stream.complete_io()?;
         assert!(r == 0);
     }
     Ok(())
 }
 
-fn make_pair() -> (ServerSession, ClientSession) {
+#[test]
+fn record_counter_split() {
+    let records = [0x17, 3, 3, 0, 2, 0xaa, 0xbb, 0x17, 3, 3, 0, 0, 0x15, 3];
+    let mut counter = RecordCounter::default();
+    for chunk in records.chunks(3) {
+        counter.feed(chunk);
+    }
+    assert_eq!(counter.count, 2);
+}
+
+pub(crate) fn load_cert_key() -> (Vec<Certificate>, PrivateKey) {
     const CERT: &str = include_str!("../../tests/end.cert");
-    const CHAIN: &str = include_str!("../../tests/end.chain");
     const RSA: &str = include_str!("../../tests/end.rsa");
 
     let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
     let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    (cert, keys.pop().unwrap())
+}
+
+pub(crate) fn make_configs() -> (ServerConfig, ClientConfig) {
+    const CHAIN: &str = include_str!("../../tests/end.chain");
+
+    let (cert, key) = load_cert_key();
     let mut sconfig = ServerConfig::new(NoClientAuth::new());
-    sconfig.set_single_cert(cert, keys.pop().unwrap()).unwrap();
-    let server = ServerSession::new(&Arc::new(sconfig));
+    sconfig.set_single_cert(cert, key).unwrap();
 
-    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
     let mut cconfig = ClientConfig::new();
     let mut chain = BufReader::new(Cursor::new(CHAIN));
     cconfig.root_store.add_pem_file(&mut chain).unwrap();
+
+    (sconfig, cconfig)
+}
+
+/// A server session, and a client config trusting its certificate.
+pub(crate) fn make_server() -> (ServerSession, ClientConfig) {
+    let (sconfig, cconfig) = make_configs();
+    (ServerSession::new(&Arc::new(sconfig)), cconfig)
+}
+
+pub(crate) fn make_pair() -> (ServerSession, ClientSession) {
+    let (sconfig, cconfig) = make_configs();
+    let server = ServerSession::new(&Arc::new(sconfig));
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
     let client = ClientSession::new(&Arc::new(cconfig), domain);
 
     (server, client)
 }
 
-fn do_handshake(client: &mut ClientSession, server: &mut ServerSession) {
+pub(crate) fn do_handshake(client: &mut ClientSession, server: &mut ServerSession) {
     let mut good = Good(server);
-    let mut stream = Stream::new(&mut good, client);
+    let mut ctx = Context::default();
+    let mut stream = Stream::new(&mut good, client, &mut ctx);
     stream.complete_io().unwrap();
     stream.complete_io().unwrap();
 }
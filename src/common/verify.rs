@@ -0,0 +1,125 @@
+//! Re-running WebPKI validation of a peer chain rustls has already accepted.
+
+#[cfg(feature = "dangerous_configuration")]
+use std::fmt;
+use std::io;
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::Arc;
+use std::time::SystemTime;
+use rustls::{ Certificate, RootCertStore };
+#[cfg(feature = "dangerous_configuration")]
+use rustls::TLSError;
+use webpki;
+
+/// The algorithms rustls itself accepts in certificate signatures.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Check that `chain`, end-entity first, leads to one of `roots` as of now.
+///
+/// `server` selects whether the end entity is checked as a server or a
+/// client certificate. The DNS name is not checked again.
+pub fn chain(chain: &[Certificate], roots: &RootCertStore, server: bool) -> io::Result<()> {
+    let (end, intermediates) = chain.split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no peer certificate to verify"))?;
+    let now = webpki::Time::try_from(SystemTime::now())
+        .map_err(|_| io::Error::other("system time before the UNIX epoch"))?;
+
+    verify(end, intermediates, roots, server, now, None)
+        .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, format!("peer certificate rejected: {:?}", err)))
+}
+
+fn verify(
+    end: &Certificate,
+    intermediates: &[Certificate],
+    roots: &RootCertStore,
+    server: bool,
+    now: webpki::Time,
+    dns_name: Option<webpki::DNSNameRef>,
+) -> Result<(), webpki::Error> {
+    let intermediates = intermediates.iter()
+        .map(|cert| cert.0.as_ref())
+        .collect::<Vec<_>>();
+    let anchors = roots.roots.iter()
+        .map(|root| root.to_trust_anchor())
+        .collect::<Vec<_>>();
+
+    let end = webpki::EndEntityCert::from(&end.0)?;
+    if server {
+        end.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS, &webpki::TLSServerTrustAnchors(&anchors), &intermediates, now)?;
+    } else {
+        end.verify_is_valid_tls_client_cert(SUPPORTED_SIG_ALGS, &webpki::TLSClientTrustAnchors(&anchors), &intermediates, now)?;
+    }
+    match dns_name {
+        Some(dns_name) => end.verify_is_valid_for_dns_name(dns_name),
+        None => Ok(()),
+    }
+}
+
+/// Check a server's chain and name like rustls' own verifier does, as of
+/// `now`.
+#[cfg(feature = "dangerous_configuration")]
+pub fn server(
+    roots: &RootCertStore,
+    presented_certs: &[Certificate],
+    dns_name: webpki::DNSNameRef,
+    now: SystemTime,
+) -> Result<(), TLSError> {
+    let (end, intermediates) = presented_certs.split_first()
+        .ok_or(TLSError::NoCertificatesPresented)?;
+    let now = webpki::Time::try_from(now)
+        .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+    verify(end, intermediates, roots, true, now, Some(dns_name))
+        .map_err(TLSError::WebPKIError)
+}
+
+/// Verifies server certificates like rustls does, but as of a fixed time
+/// rather than the system clock.
+#[cfg(feature = "dangerous_configuration")]
+pub struct FixedTime(pub SystemTime);
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for FixedTime {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, TLSError> {
+        server(roots, presented_certs, dns_name, self.0)?;
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// What `client::TlsStream::peer_verified` checks the server's chain
+/// against, for a connector set to `recheck_server_certificate`.
+#[cfg(feature = "dangerous_configuration")]
+pub struct PeerCheck {
+    pub config: Arc<rustls::ClientConfig>,
+    pub server_name: webpki::DNSName,
+    /// The time the chain was verified as of, if not the system clock's.
+    pub now: Option<SystemTime>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl fmt::Debug for PeerCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerCheck")
+            .field("server_name", &self.server_name)
+            .field("now", &self.now)
+            .finish()
+    }
+}
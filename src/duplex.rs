@@ -0,0 +1,64 @@
+//! Joining separate read and write transports into one IO.
+
+use std::io;
+use futures::Poll;
+use tokio_io::{ AsyncRead, AsyncWrite };
+
+/// An IO that reads from `R` and writes to `W`.
+///
+/// Built by `TlsStream::from_split` for connections carried over two
+/// unidirectional transports. Shutting it down only shuts down `W`.
+#[derive(Debug)]
+pub struct Duplex<R, W> {
+    read: R,
+    write: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    pub fn new(read: R, write: W) -> Duplex<R, W> {
+        Duplex { read, write }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> (&R, &W) {
+        (&self.read, &self.write)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> (&mut R, &mut W) {
+        (&mut self.read, &mut self.write)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> (R, W) {
+        (self.read, self.write)
+    }
+}
+
+impl<R: io::Read, W> io::Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf)
+    }
+}
+
+impl<R, W: io::Write> io::Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+impl<R: AsyncRead, W> AsyncRead for Duplex<R, W> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.read.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<R, W: AsyncWrite> AsyncWrite for Duplex<R, W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.shutdown()
+    }
+}
@@ -0,0 +1,99 @@
+//! Running TLS over a message oriented transport.
+
+use std::{ cmp, io };
+use bytes::Bytes;
+use futures::{ Async, AsyncSink, Poll, Sink, Stream };
+use tokio_io::{ AsyncRead, AsyncWrite };
+
+/// An IO over a transport of frames: reads go through the frames received
+/// in order, and each write is sent as a frame of its own.
+///
+/// Built by `TlsStream::over_framed`. Records are written one at a time, so
+/// each frame sent carries whole records, usually just one. Frames received
+/// need not line up with records.
+#[derive(Debug)]
+pub struct Framed<T> {
+    transport: T,
+    frame: Bytes,
+}
+
+impl<T> Framed<T> {
+    pub fn new(transport: T) -> Framed<T> {
+        Framed { transport, frame: Bytes::new() }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Note that any of the frame being read is dropped.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+fn nb<T>(poll: Poll<T, io::Error>) -> io::Result<T> {
+    match poll? {
+        Async::Ready(t) => Ok(t),
+        Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+impl<T> io::Read for Framed<T>
+where
+    T: Stream<Item = Bytes, Error = io::Error>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.frame.is_empty() {
+            match nb(self.transport.poll())? {
+                Some(frame) => self.frame = frame,
+                None => return Ok(0),
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.frame.len());
+        buf[..len].copy_from_slice(&self.frame.split_to(len));
+        Ok(len)
+    }
+}
+
+impl<T> io::Write for Framed<T>
+where
+    T: Sink<SinkItem = Bytes, SinkError = io::Error>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.transport.start_send(Bytes::from(buf))? {
+            AsyncSink::Ready => Ok(buf.len()),
+            AsyncSink::NotReady(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        nb(self.transport.poll_complete())
+    }
+}
+
+impl<T> AsyncRead for Framed<T>
+where
+    T: Stream<Item = Bytes, Error = io::Error>,
+{}
+
+impl<T> AsyncWrite for Framed<T>
+where
+    T: Sink<SinkItem = Bytes, SinkError = io::Error>,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.transport.close()
+    }
+}
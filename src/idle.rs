@@ -0,0 +1,102 @@
+//! Closing or flagging connections that have gone quiet, and bounding how
+//! long a close waits on a peer that has.
+
+use std::fmt;
+use std::io;
+use std::time::{ Duration, Instant };
+use futures::{ Async, Future };
+use tokio_timer::Delay;
+
+/// What to do once a stream has seen no traffic for its idle timeout.
+pub enum IdleAction {
+    /// Send close_notify and fail the pending read or write with `TimedOut`.
+    Close,
+    /// Call back, then start timing again.
+    Notify(Box<dyn FnMut() + Send>),
+}
+
+impl fmt::Debug for IdleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdleAction::Close => f.write_str("Close"),
+            IdleAction::Notify(_) => f.write_str("Notify(..)"),
+        }
+    }
+}
+
+/// What `shutdown` does once its close timeout passes with the
+/// close_notify still not written out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CloseTimeoutAction {
+    /// Shut the IO down anyway and report success.
+    Finish,
+    /// Shut the IO down anyway and fail with `TimedOut`.
+    Fail,
+}
+
+#[derive(Debug)]
+pub(crate) struct IdleTimer {
+    timeout: Duration,
+    delay: Delay,
+    action: IdleAction,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(timeout: Duration, action: IdleAction) -> IdleTimer {
+        IdleTimer {
+            timeout,
+            delay: Delay::new(Instant::now() + timeout),
+            action,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay.reset(Instant::now() + self.timeout);
+    }
+
+    /// Check the timer while IO is blocked, returning `true` when the
+    /// connection is to be closed.
+    ///
+    /// Must be called from within a task, which is woken at the deadline.
+    pub(crate) fn poll_expired(&mut self) -> io::Result<bool> {
+        loop {
+            match self.delay.poll() {
+                Ok(Async::Ready(())) => match &mut self.action {
+                    IdleAction::Close => return Ok(true),
+                    IdleAction::Notify(f) => {
+                        f();
+                        self.reset();
+                    }
+                },
+                Ok(Async::NotReady) => return Ok(false),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+}
+
+/// The deadline for flushing close_notify, started by the first `shutdown`.
+#[derive(Debug)]
+pub(crate) struct CloseTimer {
+    timeout: Duration,
+    delay: Option<Delay>,
+    pub(crate) action: CloseTimeoutAction,
+}
+
+impl CloseTimer {
+    pub(crate) fn new(timeout: Duration, action: CloseTimeoutAction) -> CloseTimer {
+        CloseTimer { timeout, delay: None, action }
+    }
+
+    /// Check the deadline while the flush is blocked, starting it first if
+    /// need be. Must be called from within a task.
+    pub(crate) fn poll_expired(&mut self) -> io::Result<bool> {
+        let timeout = self.timeout;
+        let delay = self.delay.get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        match delay.poll() {
+            Ok(Async::Ready(())) => Ok(true),
+            Ok(Async::NotReady) => Ok(false),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Turning a stream of accepted connections into a stream of TLS streams.
+
+use std::io;
+use futures::{ Async, Future, Poll, Stream };
+use tokio_io::{ AsyncRead, AsyncWrite };
+use { server, Accept, TlsAcceptor };
+
+/// Accept TLS on every connection from `incoming`, e.g. a
+/// `TcpListener::incoming()`, yielding each stream once its handshake is
+/// done.
+///
+/// Handshakes run side by side, so a slow client holds up no one else.
+/// A connection failing, in its handshake or when the listener accepted
+/// it, is yielded as an `Err` item and the loop carries on: back off on
+/// errors such as running out of file descriptors before polling again.
+/// The stream ends once `incoming` has and every handshake is done.
+pub fn acceptor_stream<S>(incoming: S, acceptor: TlsAcceptor) -> AcceptorStream<S>
+where
+    S: Stream<Error = io::Error>,
+    S::Item: AsyncRead + AsyncWrite,
+{
+    AcceptorStream { incoming: Some(incoming), acceptor, handshakes: Vec::new() }
+}
+
+/// Stream returned from `acceptor_stream`.
+pub struct AcceptorStream<S: Stream> {
+    incoming: Option<S>,
+    acceptor: TlsAcceptor,
+    handshakes: Vec<Accept<S::Item>>,
+}
+
+impl<S> Stream for AcceptorStream<S>
+where
+    S: Stream<Error = io::Error>,
+    S::Item: AsyncRead + AsyncWrite,
+{
+    type Item = io::Result<server::TlsStream<S::Item>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        // take in every connection the listener has ready
+        while let Some(incoming) = &mut self.incoming {
+            match incoming.poll() {
+                Ok(Async::Ready(Some(io))) => self.handshakes.push(self.acceptor.accept(io)),
+                Ok(Async::Ready(None)) => self.incoming = None,
+                Ok(Async::NotReady) => break,
+                Err(err) => return Ok(Async::Ready(Some(Err(err)))),
+            }
+        }
+
+        for i in 0..self.handshakes.len() {
+            let ret = match self.handshakes[i].poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(stream)) => Ok(stream),
+                Err(err) => Err(err),
+            };
+            drop(self.handshakes.swap_remove(i));
+            return Ok(Async::Ready(Some(ret)));
+        }
+
+        if self.incoming.is_none() && self.handshakes.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
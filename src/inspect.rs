@@ -0,0 +1,98 @@
+//! Tapping the plaintext of a TLS stream, for debugging.
+
+use std::io;
+use futures::Poll;
+use tokio_io::{ AsyncRead, AsyncWrite };
+
+/// Which way a plaintext buffer passed through the stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A stream that hands every plaintext buffer read or written to a callback.
+///
+/// Returned by `TlsStream::inspect`; unwrap it with `into_inner` to stop
+/// inspecting.
+#[derive(Debug)]
+pub struct Inspect<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S, F> Inspect<S, F>
+where
+    F: FnMut(Direction, &[u8]),
+{
+    pub(crate) fn new(stream: S, f: F) -> Inspect<S, F> {
+        Inspect { stream, f }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, F> io::Read for Inspect<S, F>
+where
+    S: io::Read,
+    F: FnMut(Direction, &[u8]),
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if n != 0 {
+            (self.f)(Direction::Read, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S, F> io::Write for Inspect<S, F>
+where
+    S: io::Write,
+    F: FnMut(Direction, &[u8]),
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        if n != 0 {
+            (self.f)(Direction::Write, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S, F> AsyncRead for Inspect<S, F>
+where
+    S: AsyncRead,
+    F: FnMut(Direction, &[u8]),
+{
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.stream.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S, F> AsyncWrite for Inspect<S, F>
+where
+    S: AsyncWrite,
+    F: FnMut(Direction, &[u8]),
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
@@ -1,4 +1,9 @@
 //! Asynchronous TLS/SSL streams for Tokio using [Rustls](https://github.com/ctz/rustls).
+//!
+//! A few accessors stand in for what rustls 0.16 does not report or
+//! implement, and are constant until it does: `used_psk`,
+//! `client::TlsStream::used_ech` and `compression_in_use` always return
+//! `false`, and `PeerAuthKind::Psk` is never produced.
 
 pub extern crate rustls;
 pub extern crate webpki;
@@ -6,17 +11,48 @@ pub extern crate webpki;
 extern crate bytes;
 extern crate futures;
 extern crate iovec;
+extern crate ring;
 extern crate tokio_io;
+#[cfg(any(feature = "idle-timeout", feature = "coalesce", feature = "rate-limit"))]
+extern crate tokio_timer;
+#[cfg(all(unix, feature = "unix"))]
+extern crate tokio_uds;
 
 pub mod client;
+#[cfg(feature = "coalesce")]
+mod coalesce;
 mod common;
+pub mod duplex;
+pub mod framed;
+#[cfg(feature = "idle-timeout")]
+pub mod idle;
+#[cfg(feature = "incoming")]
+pub mod incoming;
+pub mod inspect;
+pub mod limit;
+pub mod prefixed;
+pub mod proxy;
+#[cfg(feature = "rate-limit")]
+pub mod rate;
+pub mod resilient;
 pub mod server;
+#[cfg(feature = "sink")]
+pub mod sink;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 use common::Stream;
+use prefixed::Prefixed;
+use resilient::ResilientTlsStream;
 use futures::{Async, Future, Poll};
-use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
+use futures::sync::oneshot;
+use rustls::sign::CertifiedKey;
+use rustls::{CipherSuite, ClientConfig, ClientSession, ProtocolVersion, ServerConfig, ServerSession, Session, StoresClientSessions, TLSError};
 use std::sync::Arc;
-use std::{io, mem};
+use std::sync::atomic::AtomicBool;
+use std::{cmp, fmt, io, mem};
+#[cfg(feature = "dangerous_configuration")]
+use std::time::SystemTime;
 use tokio_io::{try_nb, AsyncRead, AsyncWrite};
 use webpki::DNSNameRef;
 
@@ -46,16 +82,294 @@ impl TlsState {
     }
 
     pub(crate) fn writeable(&self) -> bool {
-        match *self {
-            TlsState::WriteShutdown | TlsState::FullyShutdown => false,
-            _ => true,
-        }
+        !matches!(*self, TlsState::WriteShutdown | TlsState::FullyShutdown)
     }
 
     pub(crate) fn readable(self) -> bool {
-        match self {
-            TlsState::ReadShutdown | TlsState::FullyShutdown => false,
-            _ => true,
+        !matches!(self, TlsState::ReadShutdown | TlsState::FullyShutdown)
+    }
+}
+
+/// An IO that is both `AsyncRead` and `AsyncWrite`, so the two can be
+/// named together in a trait object.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite + ?Sized> AsyncReadWrite for T {}
+
+/// A transport whose type is only known at runtime, e.g. one handed over by
+/// a plugin. Both `connect` and `accept` take it as their IO.
+pub type BoxedIo<'a> = Box<dyn AsyncReadWrite + Send + 'a>;
+
+/// The direction of IO a pending handshake is waiting on.
+///
+/// Useful when driving the handshake from a selector that must arm the
+/// matching readiness interest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interest {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Interest {
+    pub(crate) fn from_blocked(read: bool, write: bool) -> Option<Interest> {
+        match (read, write) {
+            (true, true) => Some(Interest::ReadWrite),
+            (true, false) => Some(Interest::Read),
+            (false, true) => Some(Interest::Write),
+            (false, false) => None,
+        }
+    }
+}
+
+/// How far a handshake has got.
+///
+/// rustls keeps its handshake state to itself, so this is worked out from
+/// the records that have crossed the IO and can only tell the first flights
+/// apart. It is meant for reporting where a handshake stalled, e.g. when it
+/// timed out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandshakeProgress {
+    /// Nothing has been sent or received yet.
+    NotStarted,
+    /// Our hello has been sent and the peer has not answered it.
+    HelloSent,
+    /// The peer's hello has arrived, and the rest of the handshake is pending.
+    HelloReceived,
+    Complete,
+}
+
+impl HandshakeProgress {
+    pub(crate) fn new(session: &dyn Session, ctx: &common::Context) -> HandshakeProgress {
+        if !session.is_handshaking() {
+            HandshakeProgress::Complete
+        } else if ctx.records_read.count > 0 {
+            HandshakeProgress::HelloReceived
+        } else if ctx.records_written.count > 0 {
+            HandshakeProgress::HelloSent
+        } else {
+            HandshakeProgress::NotStarted
+        }
+    }
+}
+
+impl fmt::Display for HandshakeProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HandshakeProgress::NotStarted => "nothing exchanged yet",
+            HandshakeProgress::HelloSent => "sent hello, awaiting the peer's",
+            HandshakeProgress::HelloReceived => "received the peer's hello, awaiting the rest of the handshake",
+            HandshakeProgress::Complete => "handshake complete",
+        })
+    }
+}
+
+/// The error a handshake fails with once the peer sent more than
+/// `max_handshake_size` allows, inside an `io::Error` of kind `InvalidData`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HandshakeTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for HandshakeTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "handshake exceeds the size limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for HandshakeTooLarge {}
+
+/// When a write may encrypt new plaintext.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Accept new data even while earlier records wait on the IO; the
+    /// backlog is only bounded by the buffer limit.
+    Eager,
+    /// Write out earlier records first, and block until there are none.
+    ///
+    /// rustls does not report how many bytes are waiting, so the backlog
+    /// has to drain completely rather than below some threshold.
+    DrainFirst,
+}
+
+/// When a write reports its plaintext as written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WriteSemantics {
+    /// As soon as the plaintext is encrypted into the session's buffer,
+    /// whether or not the records have reached the IO.
+    Buffered,
+    /// Only once the records carrying it have been written to the IO.
+    ///
+    /// A write that got encrypted but could not be written out returns
+    /// `WouldBlock`, and the retry, which must pass the same data, reports
+    /// it once it is out without encrypting it again.
+    OnWire,
+}
+
+/// Number of TLS records read from and written to the underlying IO.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RecordStats {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// The resumption state a client keeps for one server, exported from a
+/// connection with `export_resumption` to resume from another connector.
+///
+/// This holds the session's resumption secret, so keep it, and its bytes,
+/// as confidential as the connection itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumptionData {
+    server_name: String,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ResumptionData {
+    /// The server the session belongs to.
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Serialize, e.g. to hand the session to another process.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(self.server_name.len() as u8);
+        bytes.extend_from_slice(self.server_name.as_bytes());
+        for (key, value) in &self.entries {
+            bytes.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /// Read back what `to_bytes` wrote, failing with `InvalidData` on
+    /// anything else.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<ResumptionData> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed resumption data");
+        let (&len, rest) = bytes.split_first().ok_or_else(invalid)?;
+        let len = usize::from(len);
+        if rest.len() < len {
+            return Err(invalid());
+        }
+
+        let server_name = DNSNameRef::try_from_ascii_str(std::str::from_utf8(&rest[..len]).map_err(|_| invalid())?)
+            .map_err(|_| invalid())?;
+
+        // then each entry the session stored, as a u16 length and key and
+        // a u32 length and value
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            if bytes.len() < len {
+                return None;
+            }
+            let (taken, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Some(taken)
+        }
+
+        let mut rest = &rest[len..];
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            let len = take(&mut rest, 2).ok_or_else(invalid)?;
+            let key = take(&mut rest, usize::from(u16::from_be_bytes([len[0], len[1]]))).ok_or_else(invalid)?;
+            let len = take(&mut rest, 4).ok_or_else(invalid)?;
+            let value = take(&mut rest, u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+                .ok_or_else(invalid)?;
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        if entries.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ResumptionData {
+            server_name: <&str>::from(server_name).to_owned(),
+            entries,
+        })
+    }
+}
+
+/// What a finished handshake negotiated, captured in one go for logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub protocol_version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Whether the handshake resumed an earlier session, `None` where
+    /// that can't be told.
+    ///
+    /// A client knows from the server's certificate, which rustls keeps
+    /// only from full handshakes. rustls 0.16 gives a server no way to
+    /// tell, so it always has `None`.
+    pub resumed: Option<bool>,
+    pub peer_certificates: usize,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(session: &dyn Session, resumed: Option<bool>) -> Option<ConnectionInfo> {
+        if session.is_handshaking() {
+            return None;
+        }
+
+        Some(ConnectionInfo {
+            protocol_version: session.get_protocol_version()?,
+            cipher_suite: session.get_negotiated_ciphersuite()?.suite,
+            alpn_protocol: session.get_alpn_protocol().map(<[u8]>::to_vec),
+            resumed,
+            peer_certificates: session.get_peer_certificates().map_or(0, |certs| certs.len()),
+        })
+    }
+}
+
+/// How the peer proved who it is, for authorization decisions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PeerAuthKind {
+    /// With a certificate chain that was verified.
+    Certificate,
+    /// It did not authenticate.
+    Anonymous,
+    /// With a pre-shared key.
+    ///
+    /// Never produced with rustls 0.16, which has no external PSKs and
+    /// does not report them, see `used_psk`. It is kept for when rustls
+    /// does.
+    Psk,
+}
+
+/// How the server's certificate matched the name connected to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NameMatchKind {
+    /// A subjectAltName dNSName equal to the name.
+    Exact,
+    /// A `*.` subjectAltName covering the name's first label.
+    Wildcard,
+}
+
+/// A rough grade of a cipher suite, for scorecards and policy checks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CipherStrength {
+    /// Anything not listed below: no forward secrecy, no AEAD, or a
+    /// truncated tag.
+    Weak,
+    /// A TLS 1.2 ECDHE suite with AES-GCM or ChaCha20-Poly1305.
+    Compatible,
+    /// A TLS 1.3 suite with a full-length tag.
+    Modern,
+}
+
+impl CipherStrength {
+    pub fn of(suite: CipherSuite) -> CipherStrength {
+        match suite {
+            CipherSuite::TLS13_AES_128_GCM_SHA256
+            | CipherSuite::TLS13_AES_256_GCM_SHA384
+            | CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS13_AES_128_CCM_SHA256 => CipherStrength::Modern,
+            CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => CipherStrength::Compatible,
+            _ => CipherStrength::Weak,
         }
     }
 }
@@ -64,33 +378,174 @@ impl TlsState {
 #[derive(Clone)]
 pub struct TlsConnector {
     inner: Arc<ClientConfig>,
+    error_kind: fn(&TLSError) -> io::ErrorKind,
+    buffer_limit: usize,
+    max_handshake_size: usize,
+    strict: bool,
+    write_policy: WritePolicy,
+    write_semantics: WriteSemantics,
+    half_close: bool,
+    close_notify_on_eof: bool,
+    close_notify_on_drop: bool,
+    record_client_cert: bool,
+    record_name_match: bool,
+    exportable_resumption: bool,
     #[cfg(feature = "early-data")]
     early_data: bool,
+    #[cfg(feature = "dangerous_configuration")]
+    record_ocsp: bool,
+    #[cfg(feature = "dangerous_configuration")]
+    recheck_server_cert: bool,
+    #[cfg(feature = "dangerous_configuration")]
+    verify_time: Option<SystemTime>,
 }
 
 /// A wrapper around a `rustls::ServerConfig`, providing an async `accept` method.
 #[derive(Clone)]
 pub struct TlsAcceptor {
     inner: Arc<ServerConfig>,
+    error_kind: fn(&TLSError) -> io::ErrorKind,
+    buffer_limit: usize,
+    max_handshake_size: usize,
+    strict: bool,
+    write_policy: WritePolicy,
+    write_semantics: WriteSemantics,
+    half_close: bool,
+    close_notify_on_eof: bool,
+    close_notify_on_drop: bool,
+    on_handshake_error: Option<Arc<HandshakeErrorHook>>,
+    record_hellos: bool,
+}
+
+type HandshakeErrorHook = dyn Fn(&HandshakeFailure) + Send + Sync;
+
+/// A failed `accept`, as reported to `TlsAcceptor::on_handshake_error`.
+#[derive(Debug)]
+pub struct HandshakeFailure<'a> {
+    pub error: &'a io::Error,
+    /// The rustls error behind `error`, if the handshake failed at the TLS
+    /// level rather than in the IO.
+    pub tls_error: Option<&'a TLSError>,
+    /// The server name the client asked for, if it got as far as saying.
+    pub sni_hostname: Option<&'a str>,
+}
+
+/// What a failed handshake comes down to, for picking the response a
+/// reverse proxy gives, e.g. 496 for `CertificateRequired`, 495 for
+/// `UnknownCa` and `BadCertificate`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandshakeErrorClass {
+    /// The client sent no certificate where one was required.
+    CertificateRequired,
+    /// A certificate chain does not lead to a trusted root.
+    UnknownCa,
+    /// A certificate was presented but rejected otherwise, e.g. expired or
+    /// not valid for the name.
+    BadCertificate,
+    /// The peers share no version, cipher suite or ALPN protocol, or the
+    /// peer is not speaking TLS at all.
+    ProtocolMismatch,
+    /// The IO failed or closed; no TLS error is behind it.
+    Transport,
+    Other,
+}
+
+/// Classify an error a handshake failed with, whichever side reported it:
+/// our own rustls errors and the alerts the peer sent count alike.
+pub fn handshake_error_hint(err: &io::Error) -> HandshakeErrorClass {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    let err = match err.get_ref().and_then(|err| err.downcast_ref()) {
+        Some(err) => err,
+        None => return HandshakeErrorClass::Transport,
+    };
+
+    match err {
+        TLSError::NoCertificatesPresented
+            | TLSError::AlertReceived(AlertDescription::CertificateRequired) =>
+            HandshakeErrorClass::CertificateRequired,
+        TLSError::WebPKIError(webpki::Error::UnknownIssuer)
+            | TLSError::AlertReceived(AlertDescription::UnknownCA) =>
+            HandshakeErrorClass::UnknownCa,
+        TLSError::WebPKIError(_)
+            | TLSError::AlertReceived(AlertDescription::BadCertificate)
+            | TLSError::AlertReceived(AlertDescription::UnsupportedCertificate)
+            | TLSError::AlertReceived(AlertDescription::CertificateRevoked)
+            | TLSError::AlertReceived(AlertDescription::CertificateExpired)
+            | TLSError::AlertReceived(AlertDescription::CertificateUnknown) =>
+            HandshakeErrorClass::BadCertificate,
+        TLSError::PeerIncompatibleError(_)
+            | TLSError::InappropriateMessage { .. }
+            | TLSError::InappropriateHandshakeMessage { .. }
+            | TLSError::CorruptMessage
+            | TLSError::CorruptMessagePayload(_)
+            | TLSError::AlertReceived(AlertDescription::ProtocolVersion)
+            | TLSError::AlertReceived(AlertDescription::HandshakeFailure)
+            | TLSError::AlertReceived(AlertDescription::InsufficientSecurity)
+            | TLSError::AlertReceived(AlertDescription::NoApplicationProtocol) =>
+            HandshakeErrorClass::ProtocolMismatch,
+        _ => HandshakeErrorClass::Other,
+    }
 }
 
 impl From<Arc<ClientConfig>> for TlsConnector {
     fn from(inner: Arc<ClientConfig>) -> TlsConnector {
         TlsConnector {
             inner,
+            error_kind: common::invalid_data,
+            buffer_limit: common::DEFAULT_BUFFER_LIMIT,
+            max_handshake_size: 0,
+            strict: false,
+            write_policy: WritePolicy::Eager,
+            write_semantics: WriteSemantics::Buffered,
+            half_close: true,
+            close_notify_on_eof: true,
+            close_notify_on_drop: false,
+            record_client_cert: false,
+            record_name_match: false,
+            exportable_resumption: false,
             #[cfg(feature = "early-data")]
             early_data: false,
+            #[cfg(feature = "dangerous_configuration")]
+            record_ocsp: false,
+            #[cfg(feature = "dangerous_configuration")]
+            recheck_server_cert: false,
+            #[cfg(feature = "dangerous_configuration")]
+            verify_time: None,
         }
     }
 }
 
 impl From<Arc<ServerConfig>> for TlsAcceptor {
     fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
-        TlsAcceptor { inner }
+        TlsAcceptor {
+            inner,
+            error_kind: common::invalid_data,
+            buffer_limit: common::DEFAULT_BUFFER_LIMIT,
+            max_handshake_size: 0,
+            strict: false,
+            write_policy: WritePolicy::Eager,
+            write_semantics: WriteSemantics::Buffered,
+            half_close: true,
+            close_notify_on_eof: true,
+            close_notify_on_drop: false,
+            on_handshake_error: None,
+            record_hellos: false,
+        }
     }
 }
 
 impl TlsConnector {
+    /// Build a connector trusting the roots in `pem`, with an otherwise
+    /// default `ClientConfig`.
+    pub fn from_pem_roots(pem: &[u8]) -> io::Result<TlsConnector> {
+        let mut config = ClientConfig::new();
+        match config.root_store.add_pem_file(&mut &pem[..]) {
+            Ok((valid, 0)) if valid > 0 => Ok(TlsConnector::from(Arc::new(config))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid PEM trust roots")),
+        }
+    }
+
     /// Enable 0-RTT.
     ///
     /// Note that you want to use 0-RTT.
@@ -101,6 +556,139 @@ impl TlsConnector {
         self
     }
 
+    /// Choose the `io::ErrorKind` reported for rustls protocol errors.
+    ///
+    /// By default every `TLSError` is surfaced as `InvalidData`.
+    pub fn tls_error_kind(mut self, f: fn(&TLSError) -> io::ErrorKind) -> TlsConnector {
+        self.error_kind = f;
+        self
+    }
+
+    /// Cap the encrypted data buffered ahead of what the IO has accepted.
+    ///
+    /// Once the cap is reached writes return a short count, so a large write
+    /// is encrypted as the IO drains rather than all at once. Defaults to
+    /// 64 KiB; zero removes the cap.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsConnector {
+        self.buffer_limit = limit;
+        self
+    }
+
+    /// Fail the handshake with `HandshakeTooLarge` once more than `bytes`
+    /// of TLS have been read for it, e.g. a huge certificate chain, rather
+    /// than buffer them until rustls' own limits are hit.
+    ///
+    /// The check is made as the IO is read, so at most one read's worth
+    /// beyond `bytes` is taken in. Zero, the default, removes the limit.
+    pub fn max_handshake_size(mut self, bytes: usize) -> TlsConnector {
+        self.max_handshake_size = bytes;
+        self
+    }
+
+    /// Fail reads made before the handshake is complete with `NotConnected`,
+    /// instead of driving the handshake from them.
+    ///
+    /// Only 0-RTT streams are handed out before the handshake is done. Meant
+    /// to catch such reads during development; off by default.
+    pub fn strict(mut self, flag: bool) -> TlsConnector {
+        self.strict = flag;
+        self
+    }
+
+    /// Choose whether writes wait for earlier records to reach the IO.
+    ///
+    /// Defaults to `WritePolicy::Eager`.
+    pub fn write_policy(mut self, policy: WritePolicy) -> TlsConnector {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Choose when a write reports its plaintext as written.
+    ///
+    /// Defaults to `WriteSemantics::Buffered`.
+    pub fn write_semantics(mut self, semantics: WriteSemantics) -> TlsConnector {
+        self.write_semantics = semantics;
+        self
+    }
+
+    /// Whether writes are still allowed once the read side has shut down.
+    ///
+    /// Defaults to `true`, like a TCP half-close; with `false` such writes
+    /// fail with `BrokenPipe`. This only covers a read side ended by EOF:
+    /// the peer's close_notify shuts the write side down too, and writes
+    /// past that always fail.
+    pub fn half_close(mut self, flag: bool) -> TlsConnector {
+        self.half_close = flag;
+        self
+    }
+
+    /// Whether the peer's close_notify is answered with ours.
+    ///
+    /// Defaults to `true`. With `false` reading the close_notify still shuts
+    /// the stream down both ways, but nothing is written, for transports
+    /// whose write half may already be gone.
+    pub fn close_notify_on_eof(mut self, flag: bool) -> TlsConnector {
+        self.close_notify_on_eof = flag;
+        self
+    }
+
+    /// Try once to send a close_notify when an established stream is
+    /// dropped without having been shut down, so the peer does not see a
+    /// truncated connection.
+    ///
+    /// This is best-effort: the alert, and anything still buffered, is only
+    /// written as far as the IO takes it without blocking, and errors are
+    /// ignored. Off by default; `shutdown` remains the way to close cleanly.
+    pub fn send_close_notify_on_drop(mut self, flag: bool) -> TlsConnector {
+        self.close_notify_on_drop = flag;
+        self
+    }
+
+    /// Keep the name connected to, for
+    /// `client::TlsStream::name_match_kind`. Off by default.
+    pub fn record_name_match(mut self, flag: bool) -> TlsConnector {
+        self.record_name_match = flag;
+        self
+    }
+
+    /// Note the sessions each connection gives the config's
+    /// `session_persistence`, for `client::TlsStream::export_resumption`.
+    /// Off by default.
+    ///
+    /// This clones the `ClientConfig` for every connection, to wrap its
+    /// store.
+    pub fn exportable_resumption(mut self, flag: bool) -> TlsConnector {
+        self.exportable_resumption = flag;
+        self
+    }
+
+    /// Note whether the handshake sends a client certificate, for
+    /// `client::TlsStream::sent_client_certificate`. Off by default.
+    ///
+    /// If the config has client certificates this clones the
+    /// `ClientConfig` for every connection, to wrap its resolver.
+    pub fn record_client_certificate(mut self, flag: bool) -> TlsConnector {
+        self.record_client_cert = flag;
+        self
+    }
+
+    /// Note the OCSP response servers staple, for
+    /// `client::TlsStream::ocsp_stapling_satisfied`. Off by default.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn record_ocsp_stapling(mut self, flag: bool) -> TlsConnector {
+        self.record_ocsp = flag;
+        self
+    }
+
+    /// Check the chain the config's certificate verifier accepted once
+    /// more against the config's roots and the server name, for
+    /// `client::TlsStream::peer_verified`. Off by default.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn recheck_server_certificate(mut self, flag: bool) -> TlsConnector {
+        self.recheck_server_cert = flag;
+        self
+    }
+
     pub fn connect<IO>(&self, domain: DNSNameRef, stream: IO) -> Connect<IO>
     where
         IO: AsyncRead + AsyncWrite,
@@ -108,46 +696,455 @@ impl TlsConnector {
         self.connect_with(domain, stream, |_| ())
     }
 
+    /// Connect over an IO that `prefix` was already read from, handing
+    /// those bytes to the session before anything new from the IO.
+    pub fn connect_with_prefix<IO>(
+        &self,
+        domain: DNSNameRef,
+        stream: IO,
+        prefix: Vec<u8>,
+    ) -> Connect<Prefixed<IO>>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        self.connect(domain, Prefixed::new(prefix, stream))
+    }
+
+    /// Connect over an already connected Unix domain socket.
+    ///
+    /// There is no host name to take SNI and certificate verification from,
+    /// so `domain` must be the name the server's certificate was issued
+    /// for, e.g. the sidecar's.
+    #[cfg(all(unix, feature = "unix"))]
+    pub fn connect_unix(&self, domain: DNSNameRef, stream: tokio_uds::UnixStream) -> Connect<tokio_uds::UnixStream> {
+        self.connect(domain, stream)
+    }
+
     #[inline]
     pub fn connect_with<IO, F>(&self, domain: DNSNameRef, stream: IO, f: F) -> Connect<IO>
     where
         IO: AsyncRead + AsyncWrite,
         F: FnOnce(&mut ClientSession),
     {
-        let mut session = ClientSession::new(&self.inner, domain);
+        let mut config = self.inner.clone();
+
+        // watch for the client certificate going out, see
+        // `sent_client_certificate`
+        let client_cert_sent = if self.record_client_cert {
+            let sent = Arc::new(AtomicBool::new(false));
+            if self.inner.client_auth_cert_resolver.has_certs() {
+                Arc::make_mut(&mut config).client_auth_cert_resolver = Arc::new(client::RecordClientCert {
+                    inner: self.inner.client_auth_cert_resolver.clone(),
+                    sent: sent.clone(),
+                });
+            }
+            Some(sent)
+        } else {
+            None
+        };
+
+        // see `ocsp_stapling_satisfied`
+        #[cfg(feature = "dangerous_configuration")]
+        let stapled_ocsp = if self.record_ocsp {
+            let stapled = Arc::new(std::sync::Mutex::new(None));
+            Arc::make_mut(&mut config).dangerous().set_certificate_verifier(Arc::new(client::RecordOcsp {
+                config: self.inner.clone(),
+                stapled: stapled.clone(),
+            }));
+            Some(stapled)
+        } else {
+            None
+        };
+
+        // see `export_resumption`
+        let session_record = if self.exportable_resumption {
+            let puts = Arc::new(std::sync::Mutex::new(Vec::new()));
+            Arc::make_mut(&mut config).session_persistence = Arc::new(client::RecordSessions {
+                inner: self.inner.session_persistence.clone(),
+                puts: puts.clone(),
+            });
+            Some(common::SessionRecord {
+                server_name: domain.to_owned(),
+                puts,
+            })
+        } else {
+            None
+        };
+
+        let mut session = ClientSession::new(&config, domain);
+        session.set_buffer_limit(self.buffer_limit);
         f(&mut session);
 
-        #[cfg(not(feature = "early-data"))]
+        let mut stream = client::TlsStream::new(stream, session);
+        stream.ctx.client_cert_sent = client_cert_sent;
+        stream.ctx.session_record = session_record;
+        if self.record_name_match {
+            stream.ctx.server_name = Some(domain.to_owned());
+        }
+        #[cfg(feature = "dangerous_configuration")]
         {
-            Connect(client::MidHandshake::Handshaking(client::TlsStream {
-                session,
-                io: stream,
-                state: TlsState::Stream,
-            }))
+            stream.ctx.stapled_ocsp = stapled_ocsp;
+            if self.recheck_server_cert {
+                stream.ctx.peer_check = Some(common::verify::PeerCheck {
+                    config: self.inner.clone(),
+                    server_name: domain.to_owned(),
+                    now: self.verify_time,
+                });
+            }
+        }
+        stream.ctx.error_kind = self.error_kind;
+        stream.ctx.buffer_limit = self.buffer_limit;
+        stream.ctx.max_handshake_size = self.max_handshake_size;
+        stream.ctx.strict = self.strict;
+        stream.ctx.write_policy = self.write_policy;
+        stream.ctx.write_semantics = self.write_semantics;
+        stream.ctx.half_close = self.half_close;
+        stream.ctx.close_notify_on_eof = self.close_notify_on_eof;
+        if self.close_notify_on_drop {
+            stream.on_drop = Some(|stream| stream.close_on_drop());
         }
 
         #[cfg(feature = "early-data")]
         {
-            Connect(if self.early_data {
-                client::MidHandshake::EarlyData(client::TlsStream {
-                    session,
-                    io: stream,
-                    state: TlsState::EarlyData,
-                    early_data: (0, Vec::new()),
-                })
-            } else {
-                client::MidHandshake::Handshaking(client::TlsStream {
-                    session,
-                    io: stream,
-                    state: TlsState::Stream,
-                    early_data: (0, Vec::new()),
-                })
-            })
+            if self.early_data {
+                stream.state = TlsState::EarlyData;
+                return Connect(client::MidHandshake::EarlyData(stream), Vec::new());
+            }
         }
+
+        Connect(client::MidHandshake::Handshaking(stream), Vec::new())
+    }
+
+    /// Connect keeping resumption state in `store` instead of the config's
+    /// `session_persistence`, e.g. to isolate tenants from each other.
+    ///
+    /// This clones the `ClientConfig` for the connection, which copies its
+    /// cipher suite, ALPN and version lists and root store.
+    pub fn connect_with_session_store<IO>(
+        &self,
+        domain: DNSNameRef,
+        stream: IO,
+        store: Arc<dyn StoresClientSessions>,
+    ) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let mut config = (*self.inner).clone();
+        config.session_persistence = store;
+
+        TlsConnector {
+            inner: Arc::new(config),
+            ..self.clone()
+        }
+        .connect(domain, stream)
+    }
+
+    /// Connect with a full handshake, whatever sessions the config's
+    /// `session_persistence` holds for `domain`.
+    ///
+    /// The store is left as it is, and still gets the sessions this
+    /// connection brings for later ones to resume.
+    pub fn connect_full_handshake<IO>(&self, domain: DNSNameRef, stream: IO) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let store = Arc::new(client::NoResumption(self.inner.session_persistence.clone()));
+        self.connect_with_session_store(domain, stream, store)
+    }
+
+    /// Connect trusting the roots in `extra` on top of the config's, without
+    /// changing the shared config.
+    ///
+    /// This clones the `ClientConfig` for the connection, which copies its
+    /// cipher suite, ALPN and version lists and root store, and then copies
+    /// `extra` in.
+    pub fn connect_with_extra_roots<IO>(
+        &self,
+        domain: DNSNameRef,
+        stream: IO,
+        extra: &rustls::RootCertStore,
+    ) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let mut config = (*self.inner).clone();
+        config.root_store.roots.extend(extra.roots.iter().cloned());
+
+        TlsConnector {
+            inner: Arc::new(config),
+            ..self.clone()
+        }
+        .connect(domain, stream)
+    }
+
+    /// Connect offering to resume the session in `data`, in place of any
+    /// the config's `session_persistence` has.
+    ///
+    /// The session is only offered to the server it came from, so any other
+    /// `domain` gets a full handshake. Like `connect_with_session_store` this
+    /// clones the `ClientConfig`, and the connection keeps later tickets in
+    /// a store of its own.
+    pub fn connect_resuming<IO>(&self, domain: DNSNameRef, stream: IO, data: &ResumptionData) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let store = rustls::ClientSessionMemoryCache::new(cmp::max(data.entries.len(), 4));
+        for (key, value) in &data.entries {
+            store.put(key.clone(), value.clone());
+        }
+        self.connect_with_session_store(domain, stream, store)
+    }
+
+    /// Connect, verifying the server certificate as of `now` instead of
+    /// the system clock.
+    ///
+    /// Meant for testing validity windows reproducibly. This replaces the
+    /// config's certificate verifier with one checking the chain and name
+    /// like rustls does, and clones the `ClientConfig` for the connection.
+    /// `recheck_server_certificate` checks as of `now` too.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn connect_with_time<IO>(&self, domain: DNSNameRef, stream: IO, now: SystemTime) -> Connect<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let mut config = (*self.inner).clone();
+        config.dangerous().set_certificate_verifier(Arc::new(common::verify::FixedTime(now)));
+
+        TlsConnector {
+            inner: Arc::new(config),
+            verify_time: Some(now),
+            ..self.clone()
+        }
+        .connect(domain, stream)
+    }
+
+    /// Connect, then fail if the negotiated version is older than `min`.
+    ///
+    /// The check runs once the handshake is done: a connection below `min`
+    /// is closed with a close_notify and reported as a
+    /// `TLSError::PeerIncompatibleError`.
+    pub fn connect_min_version<IO>(
+        &self,
+        domain: DNSNameRef,
+        stream: IO,
+        min: ProtocolVersion,
+    ) -> ConnectMinVersion<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        ConnectMinVersion {
+            connect: self.connect(domain, stream),
+            min,
+            error_kind: self.error_kind,
+        }
+    }
+
+    /// Connect, then fail with `InvalidData` unless the negotiated ALPN
+    /// protocol is `expected`, to rule out protocol confusion.
+    ///
+    /// This only checks: the config's `alpn_protocols` must offer
+    /// `expected`. A connection that fails the check, including one that
+    /// negotiated no protocol, is closed with a close_notify.
+    pub fn connect_expect_alpn<IO>(
+        &self,
+        domain: DNSNameRef,
+        stream: IO,
+        expected: &[u8],
+    ) -> ConnectExpectAlpn<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        ConnectExpectAlpn {
+            connect: self.connect(domain, stream),
+            expected: expected.to_vec(),
+        }
+    }
+
+    /// Connect over transports made by `factory`, starting over with a new
+    /// transport and session whenever an attempt fails with a transient IO
+    /// error (see `RetryPolicy::is_transient`).
+    ///
+    /// TLS errors such as a bad certificate are returned straight away. With
+    /// 0-RTT enabled the handshake finishes on the stream rather than in the
+    /// returned future, so only transport errors are retried.
+    pub fn connect_with_retries<F, Fut>(
+        &self,
+        domain: DNSNameRef,
+        mut factory: F,
+        policy: RetryPolicy,
+    ) -> ConnectWithRetries<F, Fut>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Error = io::Error>,
+        Fut::Item: AsyncRead + AsyncWrite,
+    {
+        let state = Retrying::Transport(factory());
+
+        ConnectWithRetries {
+            connector: self.clone(),
+            domain: domain.to_owned(),
+            factory,
+            policy,
+            attempts: 0,
+            state,
+        }
+    }
+
+    /// Connect over the transports `factory` dials, dialling and resuming
+    /// again whenever one fails mid-stream; see `ResilientTlsStream`.
+    ///
+    /// The first connection is made by the first read or write. Each one
+    /// is made as with `exportable_resumption`, for the next to resume.
+    pub fn connect_resilient<F, Fut>(
+        &self,
+        domain: DNSNameRef,
+        factory: F,
+        policy: RetryPolicy,
+    ) -> ResilientTlsStream<F, Fut>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Error = io::Error>,
+        Fut::Item: AsyncRead + AsyncWrite,
+    {
+        ResilientTlsStream::new(self.clone().exportable_resumption(true), domain.to_owned(), factory, policy)
     }
 }
 
 impl TlsAcceptor {
+    /// Build an acceptor presenting the certificate chain in `cert_pem` and
+    /// the PKCS#8 or RSA private key in `key_pem`, without client auth.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<TlsAcceptor> {
+        use rustls::internal::pemfile::{ certs, pkcs8_private_keys, rsa_private_keys };
+
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        let chain = match certs(&mut &cert_pem[..]) {
+            Ok(ref chain) if chain.is_empty() => return Err(invalid("no certificate in PEM")),
+            Ok(chain) => chain,
+            Err(()) => return Err(invalid("invalid PEM certificate")),
+        };
+        let key = pkcs8_private_keys(&mut &key_pem[..]).ok()
+            .and_then(|mut keys| keys.pop())
+            .or_else(|| rsa_private_keys(&mut &key_pem[..]).ok().and_then(|mut keys| keys.pop()))
+            .ok_or_else(|| invalid("no private key in PEM"))?;
+
+        let mut config = ServerConfig::new(rustls::NoClientAuth::new());
+        config.set_single_cert(chain, key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Choose the `io::ErrorKind` reported for rustls protocol errors.
+    ///
+    /// By default every `TLSError` is surfaced as `InvalidData`.
+    pub fn tls_error_kind(mut self, f: fn(&TLSError) -> io::ErrorKind) -> TlsAcceptor {
+        self.error_kind = f;
+        self
+    }
+
+    /// Cap the encrypted data buffered ahead of what the IO has accepted.
+    ///
+    /// Once the cap is reached writes return a short count, so a large write
+    /// is encrypted as the IO drains rather than all at once. Defaults to
+    /// 64 KiB; zero removes the cap.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsAcceptor {
+        self.buffer_limit = limit;
+        self
+    }
+
+    /// Fail the handshake with `HandshakeTooLarge` once more than `bytes`
+    /// of TLS have been read for it, e.g. a huge certificate chain, rather
+    /// than buffer them until rustls' own limits are hit.
+    ///
+    /// The check is made as the IO is read, so at most one read's worth
+    /// beyond `bytes` is taken in. Zero, the default, removes the limit.
+    pub fn max_handshake_size(mut self, bytes: usize) -> TlsAcceptor {
+        self.max_handshake_size = bytes;
+        self
+    }
+
+    /// Fail reads made before the handshake is complete with `NotConnected`,
+    /// instead of driving the handshake from them.
+    ///
+    /// Only 0-RTT streams are handed out before the handshake is done. Meant
+    /// to catch such reads during development; off by default.
+    pub fn strict(mut self, flag: bool) -> TlsAcceptor {
+        self.strict = flag;
+        self
+    }
+
+    /// Choose whether writes wait for earlier records to reach the IO.
+    ///
+    /// Defaults to `WritePolicy::Eager`.
+    pub fn write_policy(mut self, policy: WritePolicy) -> TlsAcceptor {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Choose when a write reports its plaintext as written.
+    ///
+    /// Defaults to `WriteSemantics::Buffered`.
+    pub fn write_semantics(mut self, semantics: WriteSemantics) -> TlsAcceptor {
+        self.write_semantics = semantics;
+        self
+    }
+
+    /// Whether writes are still allowed once the read side has shut down.
+    ///
+    /// Defaults to `true`, like a TCP half-close; with `false` such writes
+    /// fail with `BrokenPipe`. This only covers a read side ended by EOF:
+    /// the peer's close_notify shuts the write side down too, and writes
+    /// past that always fail.
+    pub fn half_close(mut self, flag: bool) -> TlsAcceptor {
+        self.half_close = flag;
+        self
+    }
+
+    /// Whether the peer's close_notify is answered with ours.
+    ///
+    /// Defaults to `true`. With `false` reading the close_notify still shuts
+    /// the stream down both ways, but nothing is written, for transports
+    /// whose write half may already be gone.
+    pub fn close_notify_on_eof(mut self, flag: bool) -> TlsAcceptor {
+        self.close_notify_on_eof = flag;
+        self
+    }
+
+    /// Try once to send a close_notify when an established stream is
+    /// dropped without having been shut down, so the peer does not see a
+    /// truncated connection.
+    ///
+    /// This is best-effort: the alert, and anything still buffered, is only
+    /// written as far as the IO takes it without blocking, and errors are
+    /// ignored. Off by default; `shutdown` remains the way to close cleanly.
+    pub fn send_close_notify_on_drop(mut self, flag: bool) -> TlsAcceptor {
+        self.close_notify_on_drop = flag;
+        self
+    }
+
+    /// Call `f` whenever an `accept` fails, for security logging.
+    ///
+    /// The IO is generic, so the peer address is not known here; capture it
+    /// around the `Accept` future when it is needed.
+    pub fn on_handshake_error<F>(mut self, f: F) -> TlsAcceptor
+    where
+        F: Fn(&HandshakeFailure) + Send + Sync + 'static,
+    {
+        self.on_handshake_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Keep the client's ClientHello once read, for
+    /// `server::TlsStream::offered_alpn_protocols` and `offered_versions`,
+    /// and follow the server's own hellos for `selected_key_share_group`.
+    ///
+    /// This copies the records carrying the ClientHello, up to 64 KiB, and
+    /// keeps it for as long as the connection; the server's hellos are
+    /// copied until they show the group. Off by default.
+    pub fn record_hellos(mut self, flag: bool) -> TlsAcceptor {
+        self.record_hellos = flag;
+        self
+    }
+
     pub fn accept<IO>(&self, stream: IO) -> Accept<IO>
     where
         IO: AsyncRead + AsyncWrite,
@@ -155,6 +1152,22 @@ impl TlsAcceptor {
         self.accept_with(stream, |_| ())
     }
 
+    /// Accept over an IO that `prefix` was already read from, typically
+    /// while sniffing for a ClientHello, handing those bytes to the session
+    /// before anything new from the IO.
+    pub fn accept_with_prefix<IO>(&self, stream: IO, prefix: Vec<u8>) -> Accept<Prefixed<IO>>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        self.accept(Prefixed::new(prefix, stream))
+    }
+
+    /// Accept over an already connected Unix domain socket.
+    #[cfg(all(unix, feature = "unix"))]
+    pub fn accept_unix(&self, stream: tokio_uds::UnixStream) -> Accept<tokio_uds::UnixStream> {
+        self.accept(stream)
+    }
+
     #[inline]
     pub fn accept_with<IO, F>(&self, stream: IO, f: F) -> Accept<IO>
     where
@@ -162,48 +1175,242 @@ impl TlsAcceptor {
         F: FnOnce(&mut ServerSession),
     {
         let mut session = ServerSession::new(&self.inner);
+        session.set_buffer_limit(self.buffer_limit);
         f(&mut session);
 
-        Accept(server::MidHandshake::Handshaking(server::TlsStream {
-            session,
-            io: stream,
-            state: TlsState::Stream,
-        }))
+        let mut stream = server::TlsStream::new(stream, session);
+        stream.ctx.error_kind = self.error_kind;
+        stream.ctx.buffer_limit = self.buffer_limit;
+        stream.ctx.max_handshake_size = self.max_handshake_size;
+        stream.ctx.strict = self.strict;
+        stream.ctx.write_policy = self.write_policy;
+        stream.ctx.write_semantics = self.write_semantics;
+        stream.ctx.half_close = self.half_close;
+        stream.ctx.close_notify_on_eof = self.close_notify_on_eof;
+        if self.record_hellos {
+            stream.ctx.client_hello = Some(common::hello::HelloCapture::default());
+            stream.ctx.server_kex = Some(common::hello::KexCapture::default());
+        }
+        if self.close_notify_on_drop {
+            stream.on_drop = Some(|stream| stream.close_on_drop());
+        }
+
+        Accept(server::MidHandshake::Handshaking(stream), self.on_handshake_error.clone())
+    }
+
+    /// Accept using `cert_key`, bypassing the config's certificate resolver
+    /// and so ignoring SNI.
+    ///
+    /// This clones the `ServerConfig` for the connection, which copies its
+    /// cipher suite, ALPN and version lists.
+    pub fn accept_with_cert<IO>(&self, stream: IO, cert_key: Arc<CertifiedKey>) -> Accept<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let mut config = (*self.inner).clone();
+        config.cert_resolver = Arc::new(server::ForcedCert(cert_key));
+
+        TlsAcceptor {
+            inner: Arc::new(config),
+            ..self.clone()
+        }
+        .accept(stream)
+    }
+
+    /// Accept issuing no session tickets or session IDs, so that the client
+    /// cannot resume the connection later, e.g. to limit resumption for
+    /// some clients.
+    ///
+    /// The client may still resume a session it got before. This clones the
+    /// `ServerConfig` for the connection, like `accept_with_cert`.
+    pub fn accept_without_tickets<IO>(&self, stream: IO) -> Accept<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let mut config = (*self.inner).clone();
+        config.ticketer = Arc::new(server::NoNewTickets(config.ticketer.clone()));
+        config.session_storage = Arc::new(server::NoNewSessions(config.session_storage.clone()));
+
+        TlsAcceptor {
+            inner: Arc::new(config),
+            ..self.clone()
+        }
+        .accept(stream)
     }
 }
 
 /// Future returned from `ClientConfigExt::connect_async` which will resolve
 /// once the connection handshake has finished.
-pub struct Connect<IO>(client::MidHandshake<IO>);
+///
+/// Polling drives the handshake until the IO reports `WouldBlock`, then
+/// returns `NotReady`, leaving the wakeup to the IO as usual for tokio. It
+/// never notifies the task itself and never loops without making progress.
+pub struct Connect<IO>(client::MidHandshake<IO>, Vec<oneshot::Sender<io::Result<()>>>);
 
 /// Future returned from `ServerConfigExt::accept_async` which will resolve
 /// once the accept handshake has finished.
-pub struct Accept<IO>(server::MidHandshake<IO>);
+///
+/// Polls the same way as `Connect`.
+pub struct Accept<IO>(server::MidHandshake<IO>, Option<Arc<HandshakeErrorHook>>);
 
 impl<IO> Connect<IO> {
+    /// A receiver sent the outcome once this future resolves, for waiting
+    /// on a handshake polled elsewhere.
+    ///
+    /// A failure is passed on with the same kind and message. The receiver
+    /// gets `Canceled` if the future is dropped before it resolves.
+    pub fn completion_signal(&mut self) -> oneshot::Receiver<io::Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        self.1.push(tx);
+        rx
+    }
+
+    /// The session of the handshake in progress, or `None` once it is done.
+    pub fn get_session(&self) -> Option<&ClientSession> {
+        match &self.0 {
+            client::MidHandshake::Handshaking(stream) => Some(&stream.session),
+            #[cfg(feature = "early-data")]
+            client::MidHandshake::EarlyData(stream) => Some(&stream.session),
+            client::MidHandshake::End => None,
+        }
+    }
+
+    /// Which IO the handshake was blocked on when it last returned `NotReady`.
+    pub fn interest(&self) -> Option<Interest> {
+        match &self.0 {
+            client::MidHandshake::Handshaking(stream) => stream.ctx.interest,
+            _ => None
+        }
+    }
+
+    /// How far the handshake has got, for reporting where it stalled.
+    pub fn progress(&self) -> HandshakeProgress {
+        self.0.progress()
+    }
+
+    /// The `TLSError` the handshake failed with, if rustls reported one.
+    pub fn last_tls_error(&self) -> Option<&TLSError> {
+        match &self.0 {
+            client::MidHandshake::Handshaking(stream) => stream.last_tls_error(),
+            #[cfg(feature = "early-data")]
+            client::MidHandshake::EarlyData(stream) => stream.last_tls_error(),
+            client::MidHandshake::End => None,
+        }
+    }
+
     pub fn take_inner(&mut self) -> Option<IO> {
         match mem::replace(&mut self.0, client::MidHandshake::End) {
-            client::MidHandshake::Handshaking(client::TlsStream { io, .. }) => Some(io),
+            client::MidHandshake::Handshaking(stream) => Some(stream.into_inner().0),
             _ => None
         }
     }
 }
 
 impl<IO> Accept<IO> {
+    /// The session of the handshake in progress, or `None` once it is done.
+    pub fn get_session(&self) -> Option<&ServerSession> {
+        match &self.0 {
+            server::MidHandshake::Handshaking(stream) => Some(&stream.session),
+            server::MidHandshake::End => None,
+        }
+    }
+
+    /// Which IO the handshake was blocked on when it last returned `NotReady`.
+    pub fn interest(&self) -> Option<Interest> {
+        match &self.0 {
+            server::MidHandshake::Handshaking(stream) => stream.ctx.interest,
+            _ => None
+        }
+    }
+
+    /// How far the handshake has got, for reporting where it stalled.
+    pub fn progress(&self) -> HandshakeProgress {
+        self.0.progress()
+    }
+
+    /// The `TLSError` the handshake failed with, if rustls reported one.
+    pub fn last_tls_error(&self) -> Option<&TLSError> {
+        match &self.0 {
+            server::MidHandshake::Handshaking(stream) => stream.last_tls_error(),
+            server::MidHandshake::End => None,
+        }
+    }
+
+    /// The protocol versions the client offered, once its ClientHello was
+    /// read and if the acceptor was set to `record_hellos`; see
+    /// `server::TlsStream::offered_versions`.
+    pub fn offered_versions(&self) -> Option<Vec<ProtocolVersion>> {
+        match &self.0 {
+            server::MidHandshake::Handshaking(stream) => stream.offered_versions(),
+            server::MidHandshake::End => None,
+        }
+    }
+
     pub fn take_inner(&mut self) -> Option<IO> {
         match mem::replace(&mut self.0, server::MidHandshake::End) {
-            server::MidHandshake::Handshaking(server::TlsStream { io, .. }) => Some(io),
+            server::MidHandshake::Handshaking(stream) => Some(stream.into_inner().0),
             _ => None
         }
     }
 }
 
+impl<IO: AsyncRead + AsyncWrite> Connect<IO> {
+    /// Abandon the handshake and hand back the underlying IO.
+    ///
+    /// A close_notify alert is written on a best-effort basis so the peer
+    /// sees an orderly cancellation (rustls offers no way to send
+    /// `user_canceled`). Use `take_inner` to skip the alert.
+    ///
+    /// Returns `None` if the handshake has already completed.
+    pub fn abort(self) -> Option<IO> {
+        match self.0 {
+            client::MidHandshake::Handshaking(stream) => Some(stream.abort()),
+            #[cfg(feature = "early-data")]
+            client::MidHandshake::EarlyData(stream) => Some(stream.abort()),
+            client::MidHandshake::End => None,
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> Accept<IO> {
+    /// Abandon the handshake and hand back the underlying IO.
+    ///
+    /// A close_notify alert is written on a best-effort basis so the peer
+    /// sees an orderly cancellation (rustls offers no way to send
+    /// `user_canceled`). Use `take_inner` to skip the alert.
+    ///
+    /// Returns `None` if the handshake has already completed.
+    pub fn abort(self) -> Option<IO> {
+        match self.0 {
+            server::MidHandshake::Handshaking(stream) => Some(stream.abort()),
+            server::MidHandshake::End => None,
+        }
+    }
+
+    /// Keep the IO if the handshake fails, e.g. to answer a client that is
+    /// not speaking TLS with a plaintext error before closing.
+    ///
+    /// Any alert rustls had for the failure has already been written.
+    pub fn fallible(self) -> FallibleAccept<IO> {
+        FallibleAccept(self)
+    }
+}
+
 impl<IO: AsyncRead + AsyncWrite> Future for Connect<IO> {
     type Item = client::TlsStream<IO>;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+        let ret = self.0.poll();
+        let outcome = match &ret {
+            Ok(Async::NotReady) => return ret,
+            Ok(Async::Ready(_)) => Ok(()),
+            Err(err) => Err((err.kind(), err.to_string())),
+        };
+        for tx in self.1.drain(..) {
+            let _ = tx.send(outcome.clone().map_err(|(kind, msg)| io::Error::new(kind, msg)));
+        }
+        ret
     }
 }
 
@@ -212,7 +1419,246 @@ impl<IO: AsyncRead + AsyncWrite> Future for Accept<IO> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+        let err = match self.0.poll() {
+            Err(err) => err,
+            ret => return ret,
+        };
+
+        if let (Some(hook), server::MidHandshake::Handshaking(stream)) = (&self.1, &self.0) {
+            hook(&HandshakeFailure {
+                error: &err,
+                tls_error: err.get_ref().and_then(|err| err.downcast_ref()),
+                sni_hostname: stream.session.get_sni_hostname(),
+            });
+        }
+
+        Err(err)
+    }
+}
+
+/// Future returned from `Accept::fallible`, which hands the IO back along
+/// with the error if the handshake fails.
+pub struct FallibleAccept<IO>(Accept<IO>);
+
+impl<IO: AsyncRead + AsyncWrite> Future for FallibleAccept<IO> {
+    type Item = server::TlsStream<IO>;
+    type Error = (io::Error, IO);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll() {
+            Err(err) => match self.0.take_inner() {
+                Some(io) => Err((err, io)),
+                None => panic!("FallibleAccept polled after completion"),
+            },
+            Ok(ret) => Ok(ret),
+        }
+    }
+}
+
+/// Future returned from `TlsConnector::connect_min_version` which will
+/// resolve once the handshake has finished at an acceptable version.
+pub struct ConnectMinVersion<IO> {
+    connect: Connect<IO>,
+    min: ProtocolVersion,
+    error_kind: fn(&TLSError) -> io::ErrorKind,
+}
+
+impl<IO: AsyncRead + AsyncWrite> Future for ConnectMinVersion<IO> {
+    type Item = client::TlsStream<IO>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let stream = futures::try_ready!(self.connect.poll());
+
+        match stream.session.get_protocol_version() {
+            Some(version) if version.get_u16() >= self.min.get_u16() => Ok(Async::Ready(stream)),
+            version => {
+                drop(stream.abort());
+                let err = TLSError::PeerIncompatibleError(format!(
+                    "negotiated {:?}, require at least {:?}",
+                    version, self.min
+                ));
+                Err(io::Error::new((self.error_kind)(&err), err))
+            }
+        }
+    }
+}
+
+/// Future returned from `TlsConnector::connect_expect_alpn` which will
+/// resolve once the handshake has finished with the expected protocol.
+pub struct ConnectExpectAlpn<IO> {
+    connect: Connect<IO>,
+    expected: Vec<u8>,
+}
+
+impl<IO: AsyncRead + AsyncWrite> Future for ConnectExpectAlpn<IO> {
+    type Item = client::TlsStream<IO>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let stream = futures::try_ready!(self.connect.poll());
+
+        match stream.session.get_alpn_protocol() {
+            Some(protocol) if protocol == &self.expected[..] => Ok(Async::Ready(stream)),
+            protocol => {
+                let msg = format!(
+                    "negotiated ALPN protocol {:?}, expected {:?}",
+                    protocol.map(String::from_utf8_lossy),
+                    String::from_utf8_lossy(&self.expected)
+                );
+                drop(stream.abort());
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+}
+
+/// Future returned from `TlsStream::read_to_end_until_close` which will
+/// resolve with all the plaintext once the peer closes the connection.
+pub struct ReadToClose<'a, S: 'a> {
+    stream: &'a mut S,
+    buf: Vec<u8>,
+    max: usize,
+}
+
+impl<'a, S> ReadToClose<'a, S> {
+    pub(crate) fn new(stream: &'a mut S, max: usize) -> ReadToClose<'a, S> {
+        ReadToClose { stream, buf: Vec::new(), max }
+    }
+}
+
+impl<'a, S: AsyncRead> Future for ReadToClose<'a, S> {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            // read one byte past `max` so that overflowing it is noticed
+            let len = self.buf.len();
+            let room = cmp::min(common::READ_BUF_SIZE, (self.max - len).saturating_add(1));
+            self.buf.resize(len + room, 0);
+            let ret = self.stream.read(&mut self.buf[len..]);
+            self.buf.truncate(len + *ret.as_ref().unwrap_or(&0));
+
+            if try_nb!(ret) == 0 {
+                return Ok(Async::Ready(mem::take(&mut self.buf)));
+            }
+            if self.buf.len() > self.max {
+                return Err(io::Error::other("plaintext exceeds the size limit"));
+            }
+        }
+    }
+}
+
+/// How `TlsStream::read_exact_or_eof` ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadExactOutcome {
+    /// The buffer was filled.
+    Complete,
+    /// The stream ended after `read` bytes, with a close_notify from the
+    /// peer or, if `close_notify` is `false`, with the IO just reaching EOF.
+    PartialThenEof { read: usize, close_notify: bool },
+}
+
+/// Future returned from `TlsStream::read_exact_or_eof`.
+pub struct ReadExactOrEof<'a, S: 'a> {
+    stream: &'a mut S,
+    buf: &'a mut [u8],
+    pos: usize,
+    peer_closed: fn(&S) -> bool,
+}
+
+impl<'a, S> ReadExactOrEof<'a, S> {
+    pub(crate) fn new(stream: &'a mut S, buf: &'a mut [u8], peer_closed: fn(&S) -> bool) -> ReadExactOrEof<'a, S> {
+        ReadExactOrEof { stream, buf, pos: 0, peer_closed }
+    }
+}
+
+impl<'a, S: AsyncRead> Future for ReadExactOrEof<'a, S> {
+    type Item = ReadExactOutcome;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while self.pos < self.buf.len() {
+            match try_nb!(self.stream.read(&mut self.buf[self.pos..])) {
+                0 => return Ok(Async::Ready(ReadExactOutcome::PartialThenEof {
+                    read: self.pos,
+                    close_notify: (self.peer_closed)(self.stream),
+                })),
+                n => self.pos += n,
+            }
+        }
+        Ok(Async::Ready(ReadExactOutcome::Complete))
+    }
+}
+
+/// How `TlsConnector::connect_with_retries` gives up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made in total, the first one included.
+    pub max_attempts: usize,
+}
+
+impl RetryPolicy {
+    /// Whether `err` is a transient network error worth another attempt.
+    ///
+    /// TLS errors carry the kind chosen by `tls_error_kind`, so mapping them
+    /// onto `ConnectionReset` or `Interrupted` makes them retried as well.
+    pub fn is_transient(err: &io::Error) -> bool {
+        matches!(err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted)
+    }
+}
+
+/// Future returned from `TlsConnector::connect_with_retries` which will
+/// resolve once a handshake has finished or the retries are used up.
+pub struct ConnectWithRetries<F, Fut: Future> {
+    connector: TlsConnector,
+    domain: webpki::DNSName,
+    factory: F,
+    policy: RetryPolicy,
+    attempts: usize,
+    state: Retrying<Fut, Fut::Item>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Retrying<Fut, IO> {
+    Transport(Fut),
+    Handshake(Connect<IO>),
+}
+
+impl<F, Fut> Future for ConnectWithRetries<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+    type Item = client::TlsStream<Fut::Item>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let err = match &mut self.state {
+                Retrying::Transport(transport) => match transport.poll() {
+                    Ok(Async::Ready(io)) => {
+                        let connect = self.connector.connect(self.domain.as_ref(), io);
+                        self.state = Retrying::Handshake(connect);
+                        continue;
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => err,
+                },
+                Retrying::Handshake(connect) => match connect.poll() {
+                    Err(err) => err,
+                    ret => return ret,
+                },
+            };
+
+            self.attempts += 1;
+            if self.attempts >= self.policy.max_attempts || !RetryPolicy::is_transient(&err) {
+                return Err(err);
+            }
+            self.state = Retrying::Transport((self.factory)());
+        }
     }
 }
 
@@ -231,11 +1677,11 @@ impl<T> TlsStream<T> {
         match self {
             Client(io) => {
                 let (io, session) = io.get_ref();
-                (io, &*session)
+                (io, session)
             }
             Server(io) => {
                 let (io, session) = io.get_ref();
-                (io, &*session)
+                (io, session)
             }
         }
     }
@@ -319,3 +1765,6 @@ where
 #[cfg(feature = "early-data")]
 #[cfg(test)]
 mod test_0rtt;
+
+#[cfg(test)]
+mod test_tls_stream;
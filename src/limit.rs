@@ -0,0 +1,116 @@
+//! Bounding how many handshakes run at once.
+
+use std::{ io, mem };
+use std::sync::{ Arc, Mutex };
+use futures::{ Async, Future, Poll };
+use futures::task::{ self, Task };
+use tokio_io::{ AsyncRead, AsyncWrite };
+use { server, Accept, TlsAcceptor };
+
+/// A `TlsAcceptor` that runs at most `limit` handshakes at a time, to keep
+/// the CPU they take bounded under a connection storm.
+///
+/// Accepts beyond the limit wait for a running one to finish, without
+/// touching their IO, while connections keep being accepted at the TCP
+/// layer. Clones share the limit.
+#[derive(Clone)]
+pub struct AcceptLimiter {
+    acceptor: TlsAcceptor,
+    permits: Arc<Mutex<Permits>>,
+}
+
+struct Permits {
+    available: usize,
+    limit: usize,
+    waiters: Vec<Task>,
+}
+
+impl AcceptLimiter {
+    pub fn new(acceptor: TlsAcceptor, limit: usize) -> AcceptLimiter {
+        let permits = Permits { available: limit, limit, waiters: Vec::new() };
+        AcceptLimiter { acceptor, permits: Arc::new(Mutex::new(permits)) }
+    }
+
+    pub fn accept<IO>(&self, stream: IO) -> LimitedAccept<IO>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        LimitedAccept(State::Waiting(self.clone(), stream))
+    }
+
+    /// How many handshakes are running.
+    pub fn in_flight(&self) -> usize {
+        let permits = self.permits.lock().unwrap();
+        permits.limit - permits.available
+    }
+
+    fn try_acquire(&self) -> Option<Permit> {
+        let mut permits = self.permits.lock().unwrap();
+        if permits.available > 0 {
+            permits.available -= 1;
+            Some(Permit(self.permits.clone()))
+        } else {
+            if !permits.waiters.iter().any(Task::will_notify_current) {
+                permits.waiters.push(task::current());
+            }
+            None
+        }
+    }
+}
+
+/// Held by a running handshake; dropping it wakes the waiting accepts.
+struct Permit(Arc<Mutex<Permits>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut permits = self.0.lock().unwrap();
+            permits.available += 1;
+            mem::take(&mut permits.waiters)
+        };
+
+        // they race for the permit, and the losers queue up again
+        for waiter in waiters {
+            waiter.notify();
+        }
+    }
+}
+
+/// Future returned from `AcceptLimiter::accept`.
+pub struct LimitedAccept<IO>(State<IO>);
+
+#[allow(clippy::large_enum_variant)]
+enum State<IO> {
+    Waiting(AcceptLimiter, IO),
+    Accepting { accept: Accept<IO>, _permit: Permit },
+    End,
+}
+
+impl<IO: AsyncRead + AsyncWrite> Future for LimitedAccept<IO> {
+    type Item = server::TlsStream<IO>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let State::Waiting(limiter, _) = &self.0 {
+            let permit = match limiter.try_acquire() {
+                Some(permit) => permit,
+                None => return Ok(Async::NotReady),
+            };
+            if let State::Waiting(limiter, stream) = mem::replace(&mut self.0, State::End) {
+                self.0 = State::Accepting { accept: limiter.acceptor.accept(stream), _permit: permit };
+            }
+        }
+
+        let ret = match &mut self.0 {
+            State::Accepting { accept, .. } => accept.poll(),
+            _ => panic!("unexpected polling after handshake"),
+        };
+        if let Ok(Async::NotReady) = ret {
+            return ret;
+        }
+
+        // done either way, so let the next handshake run
+        self.0 = State::End;
+        ret
+    }
+}
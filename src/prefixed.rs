@@ -0,0 +1,76 @@
+//! Replaying bytes already read from a transport.
+
+use std::io;
+use futures::Poll;
+use tokio_io::{ AsyncRead, AsyncWrite };
+
+/// An IO that reads `prefix` before reading from `io`.
+///
+/// Built by `connect_with_prefix` and `accept_with_prefix`, for sockets that
+/// were sniffed before deciding to speak TLS on them.
+#[derive(Debug)]
+pub struct Prefixed<IO> {
+    prefix: Vec<u8>,
+    pos: usize,
+    io: IO,
+}
+
+impl<IO> Prefixed<IO> {
+    pub fn new(prefix: Vec<u8>, io: IO) -> Prefixed<IO> {
+        Prefixed { prefix, pos: 0, io }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Note that any of the prefix not yet read is dropped.
+    #[inline]
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+}
+
+impl<IO: io::Read> io::Read for Prefixed<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let len = (&self.prefix[self.pos..]).read(buf)?;
+            self.pos += len;
+            if self.pos == self.prefix.len() {
+                self.prefix = Vec::new();
+                self.pos = 0;
+            }
+            return Ok(len);
+        }
+
+        self.io.read(buf)
+    }
+}
+
+impl<IO: io::Write> io::Write for Prefixed<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for Prefixed<IO> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<IO: AsyncWrite> AsyncWrite for Prefixed<IO> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
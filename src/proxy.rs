@@ -0,0 +1,139 @@
+//! Relaying plaintext between two streams, for a terminating proxy.
+
+use std::io;
+use futures::{ try_ready, Async, Future, Poll };
+use tokio_io::{ try_nb, AsyncRead, AsyncWrite };
+use common;
+
+/// Copy plaintext from `a` to `b` and from `b` to `a` until both ends have
+/// closed, resolving to how many bytes went each way.
+///
+/// Once one side's peer closes, with a close_notify or by reaching EOF,
+/// the other side is shut down, so its peer gets a close_notify, and the
+/// copy the other way runs until that peer closes in turn. Between
+/// `TlsStream`s this is a full close rather than a half-close: a
+/// `TlsStream` answers the peer's close_notify with its own and stops
+/// writing, so the peer that closes first should have nothing left to
+/// receive, or the copy toward it fails with `BrokenPipe`.
+///
+/// Writes are flushed whenever reading would block, so nothing is held
+/// back waiting for more.
+///
+/// Meant for `TlsStream`s, but any pair of streams works.
+pub fn copy_bidirectional<A, B>(a: A, b: B) -> CopyBidirectional<A, B>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    CopyBidirectional {
+        a,
+        b,
+        a_to_b: Half::default(),
+        b_to_a: Half::default(),
+    }
+}
+
+/// Future returned from `copy_bidirectional`.
+pub struct CopyBidirectional<A, B> {
+    a: A,
+    b: B,
+    a_to_b: Half,
+    b_to_a: Half,
+}
+
+impl<A, B> CopyBidirectional<A, B> {
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    pub fn get_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.a, &mut self.b)
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+/// One direction of the copy.
+struct Half {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    unflushed: bool,
+    eof: bool,
+    done: bool,
+}
+
+impl Default for Half {
+    fn default() -> Half {
+        Half {
+            buf: vec![0; common::READ_BUF_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+            unflushed: false,
+            eof: false,
+            done: false,
+        }
+    }
+}
+
+impl Half {
+    fn poll_copy<R: AsyncRead, W: AsyncWrite>(&mut self, reader: &mut R, writer: &mut W) -> Poll<(), io::Error> {
+        while !self.done {
+            if self.pos == self.cap && !self.eof {
+                match reader.poll_read(&mut self.buf)? {
+                    Async::Ready(0) => self.eof = true,
+                    Async::Ready(n) => {
+                        self.pos = 0;
+                        self.cap = n;
+                    }
+                    Async::NotReady => {
+                        if self.unflushed {
+                            try_nb!(writer.flush());
+                            self.unflushed = false;
+                        }
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+
+            while self.pos < self.cap {
+                let n = try_nb!(writer.write(&self.buf[self.pos..self.cap]));
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                self.unflushed = true;
+            }
+
+            if self.eof {
+                try_ready!(writer.shutdown());
+                self.done = true;
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<A, B> Future for CopyBidirectional<A, B>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    type Item = (u64, u64);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let a_to_b = self.a_to_b.poll_copy(&mut self.a, &mut self.b)?;
+        let b_to_a = self.b_to_a.poll_copy(&mut self.b, &mut self.a)?;
+
+        match (a_to_b, b_to_a) {
+            (Async::Ready(()), Async::Ready(())) => Ok(Async::Ready((self.a_to_b.amt, self.b_to_a.amt))),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Pacing the plaintext a stream carries.
+
+use std::io;
+use std::time::{ Duration, Instant };
+use futures::{ task, Async, Future, Poll };
+use tokio_io::{ AsyncRead, AsyncWrite };
+use tokio_timer::Delay;
+
+/// A token bucket: up to `burst` bytes go through at once, and the bucket
+/// fills back up at `bytes_per_sec`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    pub burst: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    delay: Option<Delay>,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Bucket {
+        assert!(limit.bytes_per_sec != 0 && limit.burst != 0, "a rate limit lets no bytes through");
+        Bucket { limit, tokens: limit.burst as f64, refilled: Instant::now(), delay: None }
+    }
+
+    /// How much of `want` bytes may go through now, failing with
+    /// `WouldBlock` if none may.
+    ///
+    /// From within a task, the task is then woken once some may.
+    fn take(&mut self, want: usize) -> io::Result<usize> {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.limit.bytes_per_sec as f64)
+                .min(self.limit.burst as f64);
+            self.refilled = now;
+
+            let available = self.tokens as usize;
+            if available != 0 || want == 0 {
+                self.delay = None;
+                return Ok(want.min(available));
+            }
+            if !task::is_in_task() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            let wait = (1.0 - self.tokens) / self.limit.bytes_per_sec as f64;
+            let mut delay = Delay::new(now + Duration::from_secs_f64(wait));
+            match delay.poll() {
+                Ok(Async::NotReady) => {
+                    self.delay = Some(delay);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(Async::Ready(())) => (),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+
+    fn spend(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// A stream whose reads and writes are each paced by their own token
+/// bucket, e.g. to cap the throughput of one tenant's connection.
+///
+/// Wrapping a `TlsStream` rather than its IO counts plaintext, so record
+/// overhead and the handshake don't eat into the limit. Once a bucket is
+/// empty, reads or writes fail with `WouldBlock` and the task is woken
+/// when it has refilled; outside a task they just fail.
+#[derive(Debug)]
+pub struct RateLimited<S> {
+    inner: S,
+    read: Bucket,
+    write: Bucket,
+}
+
+impl<S> RateLimited<S> {
+    /// Pace both reads and writes with `limit`, each direction having a
+    /// bucket of its own that starts full.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` has a zero rate or burst.
+    pub fn new(inner: S, limit: RateLimit) -> RateLimited<S> {
+        RateLimited { inner, read: Bucket::new(limit), write: Bucket::new(limit) }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: io::Read> io::Read for RateLimited<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read.take(buf.len())?;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.read.spend(n);
+        Ok(n)
+    }
+}
+
+impl<S: io::Write> io::Write for RateLimited<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.write.take(buf.len())?;
+        let n = self.inner.write(&buf[..len])?;
+        self.write.spend(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for RateLimited<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for RateLimited<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,258 @@
+//! A client stream that outlives the transport it runs over.
+
+use std::io::{ self, Write };
+use futures::{ Async, Future, Poll };
+use tokio_io::{ AsyncRead, AsyncWrite };
+use { client, Connect, ResumptionData, RetryPolicy, TlsConnector };
+
+/// A client `TlsStream` that, when its transport fails with a transient
+/// error, dials a new one, resumes the session over it and replays what
+/// was not acknowledged, all within the read or write that saw the error.
+///
+/// TLS acknowledges nothing, so what counts as delivered is up to the
+/// application: every byte written stays in the replay buffer until it is
+/// passed to `acknowledge`, typically once the peer's answer to it has been
+/// read. After a reconnect that buffer goes out again ahead of anything
+/// new, so a peer that got some of it before the failure gets it twice, and
+/// the protocol on top has to tolerate that. Plaintext the peer sent over
+/// the old connection and was not read yet is lost.
+///
+/// Errors are transient as per `RetryPolicy::is_transient`. The connection
+/// is dialled up to `max_attempts` times each time it drops, the count
+/// starting over once a new connection has carried plaintext; anything
+/// else, or running out of attempts, fails the call as usual.
+pub struct ResilientTlsStream<F, Fut: Future> {
+    connector: TlsConnector,
+    domain: webpki::DNSName,
+    factory: F,
+    policy: RetryPolicy,
+    state: Link<Fut, Fut::Item>,
+    resumption: Option<ResumptionData>,
+    replay: Vec<u8>,
+    /// How much of `replay` the current connection was given, `None` once
+    /// all of it has been flushed.
+    replayed: Option<usize>,
+    attempts: usize,
+    reconnects: u64,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Link<Fut, IO> {
+    Transport(Fut),
+    Handshake(Connect<IO>),
+    Connected(client::TlsStream<IO>),
+}
+
+impl<F, Fut> ResilientTlsStream<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+    pub(crate) fn new(
+        connector: TlsConnector,
+        domain: webpki::DNSName,
+        mut factory: F,
+        policy: RetryPolicy,
+    ) -> ResilientTlsStream<F, Fut> {
+        let state = Link::Transport(factory());
+
+        ResilientTlsStream {
+            connector,
+            domain,
+            factory,
+            policy,
+            state,
+            resumption: None,
+            replay: Vec::new(),
+            replayed: None,
+            attempts: 0,
+            reconnects: 0,
+        }
+    }
+
+    /// The stream of the current connection, `None` while connecting.
+    pub fn get_ref(&self) -> Option<&client::TlsStream<Fut::Item>> {
+        match &self.state {
+            Link::Connected(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// The bytes written and not acknowledged yet, which a reconnect
+    /// replays.
+    pub fn unacknowledged(&self) -> &[u8] {
+        &self.replay
+    }
+
+    /// Drop the first `n` unacknowledged bytes from the replay buffer, as
+    /// the peer is known to have them.
+    pub fn acknowledge(&mut self, n: usize) {
+        let n = n.min(self.replay.len());
+        self.replay.drain(..n);
+        self.replayed = self.replayed.map(|replayed| replayed.saturating_sub(n));
+    }
+
+    /// How many times the connection was dialled again after failing.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Connect if not connected, and hand the connection the replay
+    /// buffer if it is new.
+    fn poll_connected(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let err = match &mut self.state {
+                Link::Transport(transport) => match transport.poll() {
+                    Ok(Async::Ready(io)) => {
+                        let domain = self.domain.as_ref();
+                        let connect = match &self.resumption {
+                            Some(data) => self.connector.connect_resuming(domain, io, data),
+                            None => self.connector.connect(domain, io),
+                        };
+                        self.state = Link::Handshake(connect);
+                        continue;
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => err,
+                },
+                Link::Handshake(connect) => match connect.poll() {
+                    Ok(Async::Ready(stream)) => {
+                        self.state = Link::Connected(stream);
+                        continue;
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => err,
+                },
+                Link::Connected(stream) => match replay(stream, &self.replay, &mut self.replayed) {
+                    Ok(()) => return Ok(Async::Ready(())),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(err) => err,
+                },
+            };
+            self.reconnect(err)?;
+        }
+    }
+
+    /// Dial again after `err`, or give up with it.
+    fn reconnect(&mut self, err: io::Error) -> io::Result<()> {
+        if self.attempts >= self.policy.max_attempts || !RetryPolicy::is_transient(&err) {
+            return Err(err);
+        }
+
+        if let Link::Connected(stream) = &self.state {
+            self.resumption = stream.export_resumption().or_else(|| self.resumption.take());
+        }
+        self.attempts += 1;
+        self.reconnects += 1;
+        self.replayed = Some(0);
+        self.state = Link::Transport((self.factory)());
+        Ok(())
+    }
+}
+
+/// Write what `stream` has not had of `buf` yet, then flush it.
+fn replay<S: Write>(stream: &mut S, buf: &[u8], replayed: &mut Option<usize>) -> io::Result<()> {
+    let pos = match replayed {
+        Some(pos) => pos,
+        None => return Ok(()),
+    };
+    while *pos < buf.len() {
+        match stream.write(&buf[*pos..])? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => *pos += n,
+        }
+    }
+    stream.flush()?;
+    *replayed = None;
+    Ok(())
+}
+
+impl<F, Fut> io::Read for ResilientTlsStream<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let ret = match (self.poll_connected()?, &mut self.state) {
+                (Async::Ready(()), Link::Connected(stream)) => stream.read(buf),
+                _ => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            match ret {
+                Ok(n) => {
+                    if n != 0 {
+                        self.attempts = 0;
+                    }
+                    return Ok(n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return ret,
+                Err(err) => self.reconnect(err)?,
+            }
+        }
+    }
+}
+
+impl<F, Fut> io::Write for ResilientTlsStream<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let ret = match (self.poll_connected()?, &mut self.state) {
+                (Async::Ready(()), Link::Connected(stream)) => stream.write(buf),
+                _ => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            match ret {
+                Ok(n) => {
+                    if n != 0 {
+                        self.attempts = 0;
+                    }
+                    self.replay.extend_from_slice(&buf[..n]);
+                    return Ok(n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return ret,
+                Err(err) => self.reconnect(err)?,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            let ret = match (self.poll_connected()?, &mut self.state) {
+                (Async::Ready(()), Link::Connected(stream)) => stream.flush(),
+                _ => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            match ret {
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => self.reconnect(err)?,
+                ret => return ret,
+            }
+        }
+    }
+}
+
+impl<F, Fut> AsyncRead for ResilientTlsStream<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+}
+
+impl<F, Fut> AsyncWrite for ResilientTlsStream<F, Fut>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Error = io::Error>,
+    Fut::Item: AsyncRead + AsyncWrite,
+{
+    /// Shut down the current connection; failing to is not retried.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match (self.poll_connected()?, &mut self.state) {
+            (Async::Ready(()), Link::Connected(stream)) => stream.shutdown(),
+            _ => Ok(Async::NotReady),
+        }
+    }
+}
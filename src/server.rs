@@ -1,34 +1,710 @@
 use super::*;
-use rustls::Session;
+use bytes::Bytes;
+use duplex::Duplex;
+use framed::Framed;
+use futures::Sink;
+use inspect::{ Direction, Inspect };
+#[cfg(feature = "idle-timeout")]
+use idle::{ CloseTimeoutAction, CloseTimer, IdleAction, IdleTimer };
+#[cfg(feature = "coalesce")]
+use coalesce::Coalescer;
+#[cfg(any(feature = "idle-timeout", feature = "coalesce"))]
+use std::time::Duration;
+use rustls::sign::CertifiedKey;
+use rustls::{ ProducesTickets, ResolvesServerCert, Session, SignatureScheme, StoresServerSessions };
+use std::any::Any;
+use std::io::{ Read, Write };
+use std::time::{ Instant, SystemTime };
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
 #[derive(Debug)]
 pub struct TlsStream<IO> {
-    pub(crate) io: IO,
-    pub(crate) session: ServerSession,
+    pub(crate) io: common::Slot<IO>,
+    pub(crate) session: common::Slot<ServerSession>,
     pub(crate) state: TlsState,
+    pub(crate) ctx: common::Context,
+    pub(crate) read_buf: (usize, Vec<u8>),
+    pub(crate) context: Option<Box<dyn Any + Send>>,
+    #[cfg(feature = "idle-timeout")]
+    pub(crate) idle: Option<IdleTimer>,
+    #[cfg(feature = "idle-timeout")]
+    pub(crate) close_timer: Option<CloseTimer>,
+    #[cfg(feature = "coalesce")]
+    pub(crate) coalesce: Option<Coalescer>,
+    pub(crate) on_drop: Option<fn(&mut TlsStream<IO>)>,
 }
 
+/// Serves the same certificate whatever the client asks for.
+pub(crate) struct ForcedCert(pub(crate) Arc<CertifiedKey>);
+
+impl ResolvesServerCert for ForcedCert {
+    fn resolve(&self, _: Option<DNSNameRef>, _: &[SignatureScheme]) -> Option<CertifiedKey> {
+        Some((*self.0).clone())
+    }
+}
+
+/// Resumes with the wrapped ticketer, but never issues a ticket.
+pub(crate) struct NoNewTickets(pub(crate) Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for NoNewTickets {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.0.get_lifetime()
+    }
+
+    fn encrypt(&self, _: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
+}
+
+/// Resumes from the wrapped store, but keeps no new session in it.
+pub(crate) struct NoNewSessions(pub(crate) Arc<dyn StoresServerSessions + Send + Sync>);
+
+impl StoresServerSessions for NoNewSessions {
+    fn put(&self, _: Vec<u8>, _: Vec<u8>) -> bool {
+        false
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.take(key)
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum MidHandshake<IO> {
     Handshaking(TlsStream<IO>),
     End,
 }
 
 impl<IO> TlsStream<IO> {
+    pub(crate) fn new(io: IO, session: ServerSession) -> TlsStream<IO> {
+        TlsStream {
+            io: common::Slot::new(io),
+            session: common::Slot::new(session),
+            state: TlsState::Stream,
+            ctx: common::Context::default(),
+            read_buf: (0, Vec::new()),
+            context: None,
+            #[cfg(feature = "idle-timeout")]
+            idle: None,
+            #[cfg(feature = "idle-timeout")]
+            close_timer: None,
+            #[cfg(feature = "coalesce")]
+            coalesce: None,
+            on_drop: None,
+        }
+    }
+
     #[inline]
     pub fn get_ref(&self) -> (&IO, &ServerSession) {
-        (&self.io, &self.session)
+        (&*self.io, &*self.session)
     }
 
     #[inline]
     pub fn get_mut(&mut self) -> (&mut IO, &mut ServerSession) {
-        (&mut self.io, &mut self.session)
+        (&mut *self.io, &mut *self.session)
+    }
+
+    /// Attach a value to the connection, replacing any previous one.
+    pub fn set_context<T: Any + Send>(&mut self, value: T) {
+        self.context = Some(Box::new(value));
+    }
+
+    /// The value attached with `set_context`, if it is a `T`.
+    pub fn get_context<T: Any>(&self) -> Option<&T> {
+        self.context.as_ref()?.downcast_ref()
+    }
+
+    /// TLS records seen on the wire so far, handshake included.
+    pub fn record_stats(&self) -> RecordStats {
+        RecordStats {
+            read: self.ctx.records_read.count,
+            written: self.ctx.records_written.count,
+        }
+    }
+
+    /// Plaintext bytes `(read, written)` so far, leaving out TLS overhead.
+    ///
+    /// Plaintext from `poll_fill_buf` counts as read once it is buffered.
+    pub fn byte_counts(&self) -> (u64, u64) {
+        (self.ctx.bytes_read, self.ctx.bytes_written)
+    }
+
+    /// An ID for the connection, unique within the process and increasing
+    /// in the order streams were created, to correlate log lines by.
+    pub fn connection_id(&self) -> u64 {
+        self.ctx.id
+    }
+
+    /// When the handshake finished, `None` while handshaking.
+    pub fn established_at(&self) -> Option<Instant> {
+        self.ctx.established_at
+    }
+
+    /// The `TLSError` behind the last error that came from rustls, to match
+    /// on instead of the `io::Error` wrapping it.
+    pub fn last_tls_error(&self) -> Option<&TLSError> {
+        self.ctx.last_tls_error.as_ref()
+    }
+
+    /// How many bytes of TLS the last `write` put on the IO, to see what
+    /// records add to the plaintext.
+    ///
+    /// These are the records that went out during that call: records held
+    /// back by an IO that blocked count towards the call that writes them
+    /// out, and so does what a coalesced write held back.
+    pub fn last_write_wire_bytes(&self) -> usize {
+        self.ctx.last_write_wire_bytes
+    }
+
+    /// The ALPN protocols the client offered, in its order of preference.
+    ///
+    /// `None` until the ClientHello was read, if it had no ALPN extension,
+    /// or unless the acceptor was set to `record_hellos`. The protocol
+    /// picked from these is `get_alpn_protocol`'s.
+    pub fn offered_alpn_protocols(&self) -> Option<Vec<Vec<u8>>> {
+        let hello = self.ctx.client_hello.as_ref()?.get()?;
+        let protocols = hello.get_alpn_extension()?;
+        Some(protocols.iter().map(|protocol| protocol.0.clone()).collect())
+    }
+
+    /// The protocol versions the client offered in its supported_versions
+    /// extension, in its order of preference.
+    ///
+    /// `None` until the ClientHello was read, if it had no such extension,
+    /// as a client that only speaks TLS 1.2 and below sends none, or unless
+    /// the acceptor was set to `record_hellos`.
+    pub fn offered_versions(&self) -> Option<Vec<ProtocolVersion>> {
+        let hello = self.ctx.client_hello.as_ref()?.get()?;
+        hello.get_versions_extension().cloned()
+    }
+
+    /// The IANA codepoint of the group the key exchange used, e.g.
+    /// `0x001d` for X25519, once the handshake is done.
+    ///
+    /// rustls keeps this to itself, so it is read off the hellos written
+    /// to the client: the key share of the ServerHello for TLS 1.3, the
+    /// curve of the ServerKeyExchange for TLS 1.2. `None` for a resumption
+    /// that sent neither, as when a TLS 1.2 session is resumed, and unless
+    /// the acceptor was set to `record_hellos`.
+    pub fn selected_key_share_group(&self) -> Option<u16> {
+        if self.session.is_handshaking() {
+            return None;
+        }
+        self.ctx.server_kex.as_ref()?.get()
+    }
+
+    /// Whether the records are compressed, the precondition for CRIME-style
+    /// attacks.
+    ///
+    /// rustls implements no TLS compression and only offers the null
+    /// method, so this is always `false`.
+    pub fn compression_in_use(&self) -> bool {
+        false
+    }
+
+    /// Whether plaintext has been written that has not all reached the IO
+    /// as records yet, so that a flush is still needed.
+    ///
+    /// Plaintext written during the handshake counts until the handshake
+    /// is done.
+    pub fn has_unflushed_plaintext(&self) -> bool {
+        self.ctx.unflushed && (self.session.wants_write() || self.session.is_handshaking())
+    }
+
+    /// Make each read return the plaintext of at most one TLS record, for
+    /// consumers that want to follow the peer's record boundaries.
+    ///
+    /// A read into a buffer smaller than the record still returns less, and
+    /// `poll_fill_buf` fills its buffer one record at a time too.
+    pub fn set_record_reads(&mut self, flag: bool) {
+        self.ctx.record_reads = flag;
+    }
+
+    /// Make reads fail with `ErrorKind::UnexpectedEof` when the IO ends
+    /// without the peer's close_notify, rather than return `Ok(0)`, so that
+    /// a truncated stream is told from a complete one.
+    ///
+    /// Protocols framing their own messages can do without; off by default.
+    pub fn set_require_clean_close(&mut self, flag: bool) {
+        self.ctx.require_clean_close = flag;
+    }
+
+    /// Stop reading the IO, so that the OS buffers and then the peer are
+    /// held back until `resume_reads`.
+    ///
+    /// Reads still return plaintext that was already decrypted or read, and
+    /// fail with `WouldBlock` once that runs out. Nothing wakes the task for
+    /// the IO while paused, so poll again after resuming.
+    ///
+    /// The handshake reads too, so it stalls while reads are paused.
+    pub fn pause_reads(&mut self) {
+        self.ctx.reads_paused = true;
+    }
+
+    /// Read the IO again after `pause_reads`.
+    pub fn resume_reads(&mut self) {
+        self.ctx.reads_paused = false;
     }
 
+    /// The cap on plaintext and TLS the session buffers ahead of the IO,
+    /// or `None` if it has none.
+    ///
+    /// Limits set on the session directly through `get_mut` are not seen.
+    pub fn buffer_limit(&self) -> Option<usize> {
+        Some(self.ctx.buffer_limit).filter(|&limit| limit != 0)
+    }
+
+    /// Change the session's buffer limit, e.g. to shrink it on a busy
+    /// connection. `None`, like `Some(0)`, removes it.
+    ///
+    /// What is already buffered stays; writes take nothing more until the
+    /// backlog drops below the new limit.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        self.ctx.buffer_limit = limit.unwrap_or(0);
+        self.session.set_buffer_limit(self.ctx.buffer_limit);
+    }
+
+    /// Close the connection once `bytes` of plaintext have been read and
+    /// written in all, counting from its start.
+    ///
+    /// Reads and writes are cut short at the limit. The next one sends
+    /// close_notify, shuts both directions and fails with `Other`, as does
+    /// every one after it.
+    pub fn set_transfer_limit(&mut self, bytes: u64) {
+        self.ctx.transfer_limit = Some(bytes);
+    }
+
+    /// Make reads, writes and flushes fail with `NotConnected` while the
+    /// handshake is unfinished, instead of driving it, so that it only
+    /// progresses through `poll_handshake`.
+    ///
+    /// Off by default. Streams from a `TlsConnector` or `TlsAcceptor` have
+    /// finished the handshake already, unless they started in 0-RTT.
+    pub fn set_require_explicit_handshake(&mut self, flag: bool) {
+        self.ctx.explicit_handshake = flag;
+    }
+
+    /// Take the encrypted bytes read from the IO but not yet handed to the
+    /// session: the start of a record still waiting for the rest of it, and
+    /// with `set_record_reads` whole records queued behind the current one.
+    ///
+    /// Once the handshake is done these are all that is buffered, so they
+    /// can be replayed ahead of the IO, together with the session from
+    /// `into_inner`, to carry the connection on elsewhere.
+    pub fn take_buffered_tls(&mut self) -> Vec<u8> {
+        mem::take(&mut self.ctx.partial_tls)
+    }
+
+    /// A snapshot of what the handshake negotiated, or `None` while it is
+    /// still in progress.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        ConnectionInfo::new(&*self.session, None)
+    }
+
+    /// The grade of the negotiated cipher suite, see `CipherStrength::of`.
+    pub fn cipher_strength(&self) -> Option<CipherStrength> {
+        self.session.get_negotiated_ciphersuite()
+            .map(|suite| CipherStrength::of(suite.suite))
+    }
+
+    /// Whether the handshake was keyed with a pre-shared key.
+    ///
+    /// rustls 0.16 has no external PSK support and does not report whether
+    /// a session was resumed, so this is always `false` for now. It should
+    /// be wired to the session once rustls exposes that.
+    pub fn used_psk(&self) -> bool {
+        false
+    }
+
+    /// How the client authenticated: `Certificate` once it presented a
+    /// chain the config's verifier accepted, else `Anonymous`.
+    ///
+    /// `Anonymous` while handshaking too. `Psk` is never returned with
+    /// rustls 0.16, as `used_psk` is always `false`.
+    pub fn peer_auth_kind(&self) -> PeerAuthKind {
+        if self.session.get_peer_certificates().is_some_and(|certs| !certs.is_empty()) {
+            PeerAuthKind::Certificate
+        } else if self.used_psk() {
+            PeerAuthKind::Psk
+        } else {
+            PeerAuthKind::Anonymous
+        }
+    }
+
+    /// Act on a connection that has neither read nor written anything for
+    /// `timeout`.
+    ///
+    /// The timer is only checked while a read or write is blocked, so it
+    /// needs the stream to be polled from a task on a runtime with a timer.
+    #[cfg(feature = "idle-timeout")]
+    pub fn set_idle_timeout(&mut self, timeout: Duration, action: IdleAction) {
+        self.idle = Some(IdleTimer::new(timeout, action));
+    }
+
+    /// Stop `shutdown` from waiting more than `timeout` for the close_notify
+    /// to be written out, e.g. to a peer that stopped reading.
+    ///
+    /// The clock starts with the first `shutdown` call. Once it runs out the
+    /// IO is shut down regardless, and `action` picks what is reported.
+    #[cfg(feature = "idle-timeout")]
+    pub fn set_close_timeout(&mut self, timeout: Duration, action: CloseTimeoutAction) {
+        self.close_timer = Some(CloseTimer::new(timeout, action));
+    }
+
+    /// Hold small writes back for up to `window`, so that a burst of them
+    /// goes out in fewer, larger records. `None` stops holding writes back.
+    ///
+    /// What is held goes out once it fills a record, with the first write,
+    /// read or flush after the window is up, and on `flush` and `shutdown`.
+    /// The task writing is woken when the window is up, which takes a
+    /// runtime with a timer; without one writes are not held back.
+    /// `into_inner` drops what is still held.
+    #[cfg(feature = "coalesce")]
+    pub fn set_write_coalesce(&mut self, window: Option<Duration>) {
+        let window = window.unwrap_or_default();
+        match &mut self.coalesce {
+            Some(coalesce) => coalesce.set_window(window),
+            None if window > Duration::from_secs(0) => self.coalesce = Some(Coalescer::new(window)),
+            None => (),
+        }
+    }
+
+    /// Validate the client's certificate chain again, against `roots` and the
+    /// current time, without renegotiating.
+    ///
+    /// Meant for long-lived connections whose trust anchors may have been
+    /// rotated or revoked since the handshake. Fails with `PermissionDenied`
+    /// if the chain is no longer trusted.
+    pub fn reverify_peer(&self, roots: &rustls::RootCertStore) -> io::Result<()> {
+        let certs = self.session.get_peer_certificates().unwrap_or_default();
+        common::verify::chain(&certs, roots, false)
+    }
+
+    /// The notBefore and notAfter of the client's leaf certificate.
+    ///
+    /// `None` while handshaking, if the client sent no certificate, or if its
+    /// validity could not be decoded.
+    pub fn peer_cert_validity(&self) -> Option<(SystemTime, SystemTime)> {
+        let certs = self.session.get_peer_certificates()?;
+        common::der::validity(&certs.first()?.0)
+    }
+
+    /// The client's certificate chain as a PEM bundle, leaf first, e.g. to
+    /// hand it on to a backend.
+    ///
+    /// The bundle spans several lines, so a header carrying it needs it
+    /// escaped, as with URL encoding. `None` while handshaking or if the
+    /// peer sent no certificate.
+    pub fn peer_certificates_pem(&self) -> Option<String> {
+        let certs = self.session.get_peer_certificates()?;
+        if certs.is_empty() {
+            return None;
+        }
+        Some(common::pem_bundle(&certs))
+    }
+
+    /// A value bound to the handshake transcript, equal on both ends.
+    ///
+    /// rustls does not expose the transcript hash itself, so this is the
+    /// `tls-exporter` channel binding of RFC 9266: 32 bytes exported with the
+    /// label `EXPORTER-Channel-Binding` and no context. Use it wherever a
+    /// channel binding is wanted. `None` while handshaking.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        common::transcript_binding(&*self.session)
+    }
+
+    /// The signature scheme the peer authenticated with.
+    ///
+    /// rustls 0.16 checks the handshake signature internally and keeps no
+    /// record of the scheme, nor does it pass it to a custom verifier, so
+    /// this is always `None` for now.
+    pub fn peer_signature_scheme(&self) -> Option<SignatureScheme> {
+        None
+    }
+
+    /// Note that plaintext still held by `poll_fill_buf` is dropped.
     #[inline]
-    pub fn into_inner(self) -> (IO, ServerSession) {
-        (self.io, self.session)
+    pub fn into_inner(mut self) -> (IO, ServerSession) {
+        self.on_drop = None;
+        (self.io.take(), self.session.take())
+    }
+
+    /// Give up the IO for plaintext use once TLS has been closed cleanly.
+    ///
+    /// This fails unless close_notify has been both sent and flushed, and
+    /// received from the peer with no plaintext left unread. Bytes the peer
+    /// sent right behind its close_notify may already sit in the session
+    /// and be lost, so the peer should wait for our close_notify first.
+    pub fn into_inner_after_close(self) -> io::Result<IO> {
+        let clean = matches!(self.state, TlsState::FullyShutdown)
+            && self.ctx.peer_closed
+            && !self.session.wants_write()
+            && self.read_buf.0 == self.read_buf.1.len();
+
+        if clean {
+            Ok(self.into_inner().0)
+        } else {
+            Err(io::Error::other("TLS stream was not cleanly closed"))
+        }
+    }
+}
+
+impl<IO> Drop for TlsStream<IO> {
+    fn drop(&mut self) {
+        if let Some(f) = self.on_drop.take() {
+            f(self)
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> TlsStream<IO> {
+    /// Queue a close_notify unless one was sent, and try once to write out
+    /// what is buffered, for `close_notify_on_drop`.
+    pub(crate) fn close_on_drop(&mut self) {
+        if self.session.is_handshaking() {
+            return;
+        }
+        let _ = self.push_coalesced(true);
+        if self.state.writeable() {
+            self.session.send_close_notify();
+            self.state.shutdown_write();
+        }
+        let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable())
+            .drain();
+    }
+}
+
+impl<R, W> TlsStream<Duplex<R, W>>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    /// Run `session` over separate read and write transports.
+    ///
+    /// The handshake is driven by the stream's first reads and writes.
+    pub fn from_split(read_io: R, write_io: W, session: ServerSession) -> TlsStream<Duplex<R, W>> {
+        TlsStream::new(Duplex::new(read_io, write_io), session)
+    }
+}
+
+impl<T> TlsStream<Framed<T>>
+where
+    T: futures::Stream<Item = Bytes, Error = io::Error> + Sink<SinkItem = Bytes, SinkError = io::Error>,
+{
+    /// Run `session` over a transport of frames rather than bytes.
+    ///
+    /// The handshake is driven by the stream's first reads and writes.
+    pub fn over_framed(transport: T, session: ServerSession) -> TlsStream<Framed<T>> {
+        TlsStream::new(Framed::new(transport), session)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    /// Pass every plaintext buffer read or written to `f`, for debugging.
+    pub fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        F: FnMut(Direction, &[u8]),
+    {
+        Inspect::new(self, f)
+    }
+
+    /// Fill the internal read buffer and return its contents.
+    ///
+    /// rustls does not lend out its own plaintext buffer, so data is copied
+    /// once into a buffer owned by the stream; parsing straight from the
+    /// returned slice avoids any further copy. The slice borrows the stream
+    /// and must be released before the next call, and nothing is removed
+    /// from the buffer until `consume` is called.
+    ///
+    /// An empty slice means the TLS stream has reached EOF.
+    pub fn poll_fill_buf(&mut self) -> Poll<&[u8], io::Error> {
+        if self.read_buf.0 == self.read_buf.1.len() {
+            let mut buf = mem::take(&mut self.read_buf.1);
+            buf.resize(common::READ_BUF_SIZE, 0);
+            let ret = io::Read::read(self, &mut buf);
+            buf.truncate(*ret.as_ref().unwrap_or(&0));
+            self.read_buf = (0, buf);
+            try_nb!(ret);
+        }
+
+        let (pos, buf) = &self.read_buf;
+        Ok(Async::Ready(&buf[*pos..]))
+    }
+
+    /// Drive the handshake, resolving once it is done and what it left to
+    /// write has been flushed.
+    pub fn poll_handshake(&mut self) -> Poll<(), io::Error> {
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        try_nb!(stream.handshake());
+        Ok(Async::Ready(()))
+    }
+
+    /// Resolve once a read would not block on the IO: there is plaintext
+    /// left from `poll_fill_buf`, or the IO has bytes or has hit EOF. Those
+    /// bytes are held for the next read rather than decrypted here.
+    ///
+    /// Plaintext the session decrypted but a short read didn't take isn't
+    /// seen, so wait on this only once a read has returned `WouldBlock`.
+    pub fn poll_read_ready(&mut self) -> Poll<(), io::Error> {
+        if self.read_buf.0 < self.read_buf.1.len() {
+            return Ok(Async::Ready(()));
+        }
+        common::poll_read_ready(&mut *self.io, &mut self.ctx)
+    }
+
+    /// Resolve once the TLS already buffered has all reached the IO, so the
+    /// next write has room to go out rather than being held by the session.
+    ///
+    /// This only knows the IO is full when writing to it blocks; with
+    /// nothing buffered it is `Ready` right away.
+    pub fn poll_writable(&mut self) -> Poll<(), io::Error> {
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        try_nb!(stream.drain());
+        Ok(Async::Ready(()))
+    }
+
+    /// Resolve once everything written so far has reached the IO, flushing
+    /// as needed.
+    ///
+    /// Unlike `poll_writable` this also pushes out plaintext held back for
+    /// coalescing, so a write buffer that is empty here stays empty until
+    /// the next write. The IO itself is not flushed.
+    pub fn poll_write_buffer_empty(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.push_coalesced(true));
+        self.poll_writable()
+    }
+
+    /// Fill `buf`, or report how much was read before the stream ended and
+    /// whether it ended with a close_notify.
+    pub fn read_exact_or_eof<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExactOrEof<'a, Self> {
+        ReadExactOrEof::new(self, buf, |stream| stream.ctx.peer_closed)
+    }
+
+    /// Write `buf` as the last plaintext with close_notify right behind it,
+    /// so both go out in the same flush, and in a single write for an IO
+    /// with a vectored `write_buf` such as `TcpStream`.
+    ///
+    /// `buf` goes through the same checks as `write`. The alert is only
+    /// queued once all of it has been taken; until then this returns how
+    /// much was, like `write`. Once it has been queued the
+    /// write side is shut down, and a retry after `NotReady`, which must pass
+    /// the same `buf`, only finishes the flush.
+    pub fn poll_write_close(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        try_nb!(self.push_coalesced(true));
+        let mut len = buf.len();
+        if self.state.writeable() {
+            let budget = self.transfer_budget(buf.len())?;
+            let ret = self.write_plaintext_with(&buf[..budget], true);
+            len = try_nb!(self.track_idle(ret));
+            self.ctx.bytes_written += len as u64;
+            if len == buf.len() {
+                self.session.send_close_notify();
+                self.state.shutdown_write();
+            }
+        }
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        match stream.flush() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 && len < buf.len() => {
+                if let WriteSemantics::OnWire = self.ctx.write_semantics {
+                    // Like `write`, only report the bytes once they're out;
+                    // the retry drains them and then reports them.
+                    self.ctx.unreported = Some(len);
+                    self.ctx.bytes_written -= len as u64;
+                    return Ok(Async::NotReady);
+                }
+            },
+            ret => try_nb!(ret),
+        }
+        Ok(Async::Ready(len))
+    }
+
+    /// Write `buf` and flush it at once, even while writes are coalesced,
+    /// e.g. for a control message that must not wait behind bulk data.
+    ///
+    /// What coalescing held back goes out first, as plaintext keeps its
+    /// order. This returns how much of `buf` was taken, like `write`; once
+    /// some was, a flush the IO blocks is left for the next write, flush
+    /// or `poll_writable` to finish.
+    pub fn poll_write_urgent(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        try_nb!(self.push_coalesced(true));
+        let len = self.transfer_budget(buf.len())?;
+        let ret = self.write_plaintext(&buf[..len]);
+        let len = try_nb!(self.track_idle(ret));
+        self.ctx.bytes_written += len as u64;
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        let ret = stream.flush().and_then(|()| common::retry_interrupted(|| self.io.flush()));
+        match ret {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => (),
+            ret => try_nb!(ret),
+        }
+        Ok(Async::Ready(len))
+    }
+
+    /// Read all plaintext until the peer sends close_notify or the IO
+    /// reaches EOF, failing with `ErrorKind::Other` past `max` bytes.
+    pub fn read_to_end_until_close(&mut self, max: usize) -> ReadToClose<'_, Self> {
+        ReadToClose::new(self, max)
+    }
+
+    /// Read and discard plaintext until the peer's close_notify, so that
+    /// closing doesn't leave unread data behind for the OS to answer with
+    /// a reset.
+    ///
+    /// Fails with `ErrorKind::UnexpectedEof` if the IO ends without one.
+    pub fn poll_drain_to_close(&mut self) -> Poll<(), io::Error> {
+        let mut buf = [0; common::READ_BUF_SIZE];
+        while try_nb!(io::Read::read(self, &mut buf)) != 0 {}
+
+        if self.ctx.peer_closed {
+            Ok(Async::Ready(()))
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Queue a close_notify, try once to write it out, and give up the IO.
+    pub(crate) fn abort(mut self) -> IO {
+        self.session.send_close_notify();
+        let _ = io::Write::flush(&mut Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx));
+        self.into_inner().0
+    }
+
+    /// Mark `amt` bytes of the slice returned by `poll_fill_buf` as read.
+    pub fn consume(&mut self, amt: usize) {
+        let (pos, buf) = &mut self.read_buf;
+        *pos = std::cmp::min(*pos + amt, buf.len());
+    }
+}
+
+impl<IO> MidHandshake<IO> {
+    pub(crate) fn progress(&self) -> HandshakeProgress {
+        match self {
+            MidHandshake::Handshaking(stream) => HandshakeProgress::new(&*stream.session, &stream.ctx),
+            MidHandshake::End => HandshakeProgress::Complete,
+        }
     }
 }
 
@@ -43,16 +719,9 @@ where
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let MidHandshake::Handshaking(stream) = self {
             let state = stream.state;
-            let (io, session) = stream.get_mut();
-            let mut stream = Stream::new(io, session).set_eof(!state.readable());
-
-            if stream.session.is_handshaking() {
-                try_nb!(stream.complete_io());
-            }
-
-            if stream.session.wants_write() {
-                try_nb!(stream.complete_io());
-            }
+            let mut stream = Stream::new(&mut *stream.io, &mut *stream.session, &mut stream.ctx)
+                .set_eof(!state.readable());
+            try_nb!(stream.handshake());
         }
 
         match mem::replace(self, MidHandshake::End) {
@@ -67,19 +736,62 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut stream = Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            ret => ret?,
+        }
+
+        // what `poll_fill_buf` kept was counted when it was read
+        let buffered = self.read_buf.0 < self.read_buf.1.len();
+        let len = if buffered { buf.len() } else { self.transfer_budget(buf.len())? };
+        let ret = self.read_plaintext(&mut buf[..len]);
+        if let (false, Ok(n)) = (buffered, &ret) {
+            self.ctx.bytes_read += *n as u64;
+        }
+        self.track_idle(ret)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        common::read_vectored(self, bufs)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ctx.strict && self.session.is_handshaking() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "read before the handshake completed"));
+        }
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+
+        if let Some(len) = common::read_buffered(&mut self.read_buf, buf) {
+            return Ok(len);
+        }
+
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
 
         match self.state {
+            // An empty `buf` still pulls records in, and what they decrypt
+            // to waits in the session for the next read.
             TlsState::Stream | TlsState::WriteShutdown => match stream.read(buf) {
-                Ok(0) => {
+                Ok(0) if !buf.is_empty() && stream.ctx.require_clean_close => Err(common::truncated()),
+                Ok(0) if !buf.is_empty() => {
                     self.state.shutdown_read();
                     Ok(0)
                 }
                 Ok(n) => Ok(n),
                 Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
                     self.state.shutdown_read();
+                    stream.ctx.peer_closed = true;
                     if self.state.writeable() {
-                        stream.session.send_close_notify();
+                        if stream.ctx.close_notify_on_eof {
+                            stream.session.send_close_notify();
+                        }
                         self.state.shutdown_write();
                     }
                     Ok(0)
@@ -91,6 +803,155 @@ where
             s => unreachable!("server TLS can not hit this state: {:?}", s),
         }
     }
+
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_plaintext_with(buf, false)
+    }
+
+    /// `write_plaintext`, leaving the records queued in the session when
+    /// `hold` is set, for the caller to add to before flushing.
+    fn write_plaintext_with(&mut self, buf: &[u8], hold: bool) -> io::Result<usize> {
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+        if !self.state.writeable() || !self.ctx.half_close && !self.state.readable() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable())
+            .set_hold(hold)
+            .write(buf)
+    }
+
+    /// Cut `len` down to what the transfer limit still lets through,
+    /// closing the connection once that is nothing.
+    fn transfer_budget(&mut self, len: usize) -> io::Result<usize> {
+        match self.ctx.transfer_budget() {
+            None => Ok(len),
+            Some(0) => {
+                self.state.shutdown_read();
+                if self.state.writeable() {
+                    self.session.send_close_notify();
+                    self.state.shutdown_write();
+                }
+                let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx).flush();
+                Err(io::Error::other("transfer limit reached"))
+            }
+            Some(budget) => Ok(cmp::min(len, budget)),
+        }
+    }
+
+    /// Write out the writes held back by `set_write_coalesce`, if they are
+    /// due or `force`.
+    #[cfg(feature = "coalesce")]
+    fn push_coalesced(&mut self, force: bool) -> io::Result<()> {
+        let mut coalesce = match self.coalesce.take() {
+            Some(coalesce) => coalesce,
+            None => return Ok(()),
+        };
+        let ret = coalesce.push(force, |buf| self.write_plaintext(buf));
+        if !coalesce.is_spent() {
+            self.coalesce = Some(coalesce);
+        }
+        ret
+    }
+
+    #[cfg(not(feature = "coalesce"))]
+    #[inline]
+    fn push_coalesced(&mut self, _: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// `write_plaintext`, holding `buf` back instead while coalescing.
+    #[cfg(feature = "coalesce")]
+    fn write_coalesced(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.coalesce.is_none() || self.session.is_handshaking() || !self.state.writeable() {
+            return self.write_plaintext(buf);
+        }
+
+        // what is due goes first, but a full IO is no reason to refuse more
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            ret => ret?,
+        }
+        let coalesce = match &mut self.coalesce {
+            Some(coalesce) if !coalesce.is_spent() => coalesce,
+            _ => {
+                self.push_coalesced(true)?;
+                return self.write_plaintext(buf);
+            }
+        };
+
+        let len = coalesce.hold(buf);
+        match self.push_coalesced(false) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && len != 0 => (),
+            ret => ret?,
+        }
+        if len == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(len)
+    }
+
+    #[cfg(not(feature = "coalesce"))]
+    #[inline]
+    fn write_coalesced(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_plaintext(buf)
+    }
+
+    /// Restart the idle timer after progress, or check it while blocked.
+    #[cfg(feature = "idle-timeout")]
+    fn track_idle<T>(&mut self, ret: io::Result<T>) -> io::Result<T> {
+        let idle = match &mut self.idle {
+            Some(idle) => idle,
+            None => return ret,
+        };
+
+        match ret {
+            Ok(_) => idle.reset(),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if idle.poll_expired()? {
+                    self.idle = None;
+                    self.state.shutdown_read();
+                    if self.state.writeable() {
+                        self.session.send_close_notify();
+                        self.state.shutdown_write();
+                    }
+                    let _ = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx).flush();
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+            Err(_) => (),
+        }
+
+        ret
+    }
+
+    #[cfg(not(feature = "idle-timeout"))]
+    #[inline]
+    fn track_idle<T>(&mut self, ret: io::Result<T>) -> io::Result<T> {
+        ret
+    }
+
+    /// Give up on flushing close_notify once the close timeout is up.
+    #[cfg(feature = "idle-timeout")]
+    fn poll_close_timeout(&mut self) -> Poll<(), io::Error> {
+        let timer = match &mut self.close_timer {
+            Some(timer) => timer,
+            None => return Ok(Async::NotReady),
+        };
+        if !timer.poll_expired()? {
+            return Ok(Async::NotReady);
+        }
+        let action = timer.action;
+
+        futures::try_ready!(self.io.shutdown());
+        match action {
+            CloseTimeoutAction::Finish => Ok(Async::Ready(())),
+            CloseTimeoutAction::Fail => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
 }
 
 impl<IO> io::Write for TlsStream<IO>
@@ -98,16 +959,25 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        Stream::new(&mut self.io, &mut self.session)
-            .set_eof(!self.state.readable())
-            .write(buf)
+        let len = self.transfer_budget(buf.len())?;
+        let wire = self.ctx.records_written.bytes;
+        let ret = self.write_coalesced(&buf[..len]);
+        self.ctx.last_write_wire_bytes = (self.ctx.records_written.bytes - wire) as usize;
+        if let Ok(n) = ret {
+            self.ctx.bytes_written += n as u64;
+        }
+        self.track_idle(ret)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Stream::new(&mut self.io, &mut self.session)
+        if self.ctx.explicit_handshake && self.session.is_handshaking() {
+            return Err(common::handshake_pending());
+        }
+        self.push_coalesced(true)?;
+        Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
             .set_eof(!self.state.readable())
             .flush()?;
-        self.io.flush()
+        common::retry_interrupted(|| self.io.flush())
     }
 }
 
@@ -125,13 +995,21 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.push_coalesced(true));
         if self.state.writeable() {
             self.session.send_close_notify();
             self.state.shutdown_write();
         }
 
-        let mut stream = Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
-        try_nb!(stream.complete_io());
+        let mut stream = Stream::new(&mut *self.io, &mut *self.session, &mut self.ctx)
+            .set_eof(!self.state.readable());
+        match stream.complete_io() {
+            #[cfg(feature = "idle-timeout")]
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && self.close_timer.is_some() => {
+                return self.poll_close_timeout();
+            }
+            ret => try_nb!(ret),
+        };
         stream.io.shutdown()
     }
 }
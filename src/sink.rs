@@ -0,0 +1,79 @@
+//! A `Sink` of `Bytes` over a TLS stream.
+
+use std::io;
+use bytes::Bytes;
+use futures::{ Async, AsyncSink, Poll, Sink, StartSend };
+use tokio_io::{ try_nb, AsyncWrite };
+
+/// Writes each `Bytes` sent to it into the wrapped stream.
+///
+/// Only one item is held at a time: a new item is refused until the previous
+/// one has been written and flushed, so a slow peer pushes back on the sender
+/// instead of piling up records in the session.
+#[derive(Debug)]
+pub struct TlsSink<S> {
+    stream: S,
+    pending: Bytes,
+}
+
+impl<S> TlsSink<S> {
+    pub fn new(stream: S) -> TlsSink<S> {
+        TlsSink { stream, pending: Bytes::new() }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Note that the unwritten part of the current item is dropped.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncWrite> TlsSink<S> {
+    fn poll_pending(&mut self) -> Poll<(), io::Error> {
+        while !self.pending.is_empty() {
+            let n = try_nb!(self.stream.write(&self.pending));
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.pending.advance(n);
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S: AsyncWrite> Sink for TlsSink<S> {
+    type SinkItem = Bytes;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Bytes) -> StartSend<Bytes, io::Error> {
+        if self.poll_complete()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        self.pending = item;
+        self.poll_pending()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        futures::try_ready!(self.poll_pending());
+        try_nb!(self.stream.flush());
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        futures::try_ready!(self.poll_complete());
+        self.stream.shutdown()
+    }
+}
@@ -24,7 +24,7 @@ fn get(config: Arc<ClientConfig>, domain: &str, rtt0: bool)
 
     TcpStream::connect(&addr)
         .and_then(move |stream| {
-            let domain = webpki::DNSNameRef::try_from_ascii_str(&domain).unwrap();
+            let domain = webpki::DNSNameRef::try_from_ascii_str(domain).unwrap();
             config.connect(domain, stream)
         })
         .and_then(move |stream| aio::write_all(stream, input))
@@ -0,0 +1,1468 @@
+#[cfg(feature = "idle-timeout")]
+extern crate tokio;
+
+use std::io::{ self, Write };
+use std::sync::Arc;
+use futures::{ Async, Future, Poll };
+#[cfg(feature = "sink")]
+use futures::Sink;
+use rustls::{ CipherSuite, ClientConfig, ProtocolVersion, ClientSession, ServerSession, Session, TLSError };
+use webpki::DNSNameRef;
+use common;
+use common::test_stream::{ Good, make_configs, make_server };
+use tokio_io::{ AsyncRead, AsyncWrite };
+#[cfg(feature = "sink")]
+use sink;
+use prefixed::Prefixed;
+use { client, server, Connect, ConnectionInfo, Interest, ReadExactOutcome, ResumptionData, TlsAcceptor, TlsConnector, WritePolicy, WriteSemantics };
+
+fn connect<'a>(server: &'a mut ServerSession, cconfig: ClientConfig) -> client::TlsStream<Good<'a>> {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Good(server))
+        .wait()
+        .unwrap_or_else(|err| panic!("handshake failed: {}", err))
+}
+
+#[cfg(not(feature = "early-data"))]
+#[test]
+fn client_stream_without_early_data() {
+    use std::mem;
+    use rustls::ClientSession;
+    use server;
+
+    // Without `early-data` the client stream must not carry the fallback
+    // buffer, so apart from the session it is laid out like the server one.
+    assert_eq!(
+        mem::size_of::<client::TlsStream<()>>() - mem::size_of::<ClientSession>(),
+        mem::size_of::<server::TlsStream<()>>() - mem::size_of::<ServerSession>()
+    );
+}
+
+/// A transport whose reads and writes can be blocked independently; a read
+/// with nothing to deliver blocks too, like a non-blocking socket.
+struct Gated<'a> {
+    good: Good<'a>,
+    read: bool,
+    write: bool,
+}
+
+impl<'a> io::Read for Gated<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        match self.good.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            ret => ret,
+        }
+    }
+}
+
+impl<'a> io::Write for Gated<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write {
+            self.good.write(buf)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> AsyncRead for Gated<'a> {}
+impl<'a> AsyncWrite for Gated<'a> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn gated<'a, 'b>(connect: &'b mut Connect<Gated<'a>>) -> &'b mut Gated<'a> {
+    match &mut connect.0 {
+        client::MidHandshake::Handshaking(stream) => &mut stream.io,
+        _ => panic!("handshake already finished"),
+    }
+}
+
+#[test]
+fn handshake_interest() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: false, write: false };
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut connect = TlsConnector::from(Arc::new(cconfig)).connect(domain, io);
+
+    // nothing can go out, nothing comes in
+    assert!(connect.poll()?.is_not_ready());
+    assert_eq!(connect.interest(), Some(Interest::ReadWrite));
+
+    // the ClientHello is sent, then we wait for the server
+    gated(&mut connect).write = true;
+    assert!(connect.poll()?.is_not_ready());
+    assert_eq!(connect.interest(), Some(Interest::Read));
+
+    gated(&mut connect).read = true;
+    assert!(connect.poll()?.is_ready());
+
+    Ok(())
+}
+
+/// A transport that never has anything to read and records all writes.
+#[cfg(feature = "early-data")]
+struct Recorder(Vec<u8>);
+
+#[cfg(feature = "early-data")]
+impl io::Read for Recorder {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+#[cfg(feature = "early-data")]
+impl io::Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "early-data")]
+impl AsyncRead for Recorder {}
+#[cfg(feature = "early-data")]
+impl AsyncWrite for Recorder {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(feature = "sink")]
+#[test]
+fn sink_send_bytes() {
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+
+    let items = vec![
+        bytes::Bytes::from_static(b"hello "),
+        bytes::Bytes::from_static(b"tls "),
+        bytes::Bytes::from_static(b"sink"),
+    ];
+    {
+        let stream = connector.connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        let (sink, _) = sink::TlsSink::new(stream)
+            .send_all(futures::stream::iter_ok::<_, io::Error>(items))
+            .wait()
+            .unwrap_or_else(|err| panic!("send failed: {}", err));
+        drop(sink);
+    }
+
+    let mut buf = Vec::new();
+    let _ = io::Read::read_to_end(&mut server, &mut buf);
+    assert_eq!(buf, b"hello tls sink");
+    assert!(!server.is_handshaking());
+}
+
+/// Hands the handshake to `good`, then discards writes, taking at most
+/// 1000 bytes per call and blocking every other call.
+struct Slow<'a> {
+    good: Good<'a>,
+    discard: bool,
+    blocked: bool,
+    written: usize,
+}
+
+impl<'a> io::Read for Slow<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.good.read(buf)
+    }
+}
+
+impl<'a> io::Write for Slow<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.discard {
+            return self.good.write(buf);
+        }
+
+        self.blocked = !self.blocked;
+        if self.blocked {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = std::cmp::min(buf.len(), 1000);
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> AsyncRead for Slow<'a> {}
+impl<'a> AsyncWrite for Slow<'a> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn bounded_write_buffer() -> io::Result<()> {
+    const LIMIT: usize = 16 * 1024;
+
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let connector = TlsConnector::from(Arc::new(cconfig)).buffer_limit(LIMIT);
+    let slow = Slow { good: Good(&mut server), discard: false, blocked: false, written: 0 };
+    let mut stream = connector.connect(domain, slow)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    stream.io.discard = true;
+
+    let data = vec![0x42; 10 * 1024 * 1024];
+    let mut pos = 0;
+    let mut peak = 0;
+    while pos < data.len() {
+        match stream.write(&data[pos..]) {
+            Ok(n) => pos += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
+        }
+        peak = std::cmp::max(peak, pos.saturating_sub(stream.io.written));
+    }
+
+    // records add a little overhead on top of the plaintext
+    assert!(peak <= LIMIT + 1024, "peak {} over the limit", peak);
+    Ok(())
+}
+
+#[test]
+fn connection_info() {
+    let (mut sconfig, mut cconfig) = make_configs();
+    sconfig.set_protocols(&[b"h2".to_vec()]);
+    cconfig.set_protocols(&[b"h2".to_vec()]);
+    let cconfig = Arc::new(cconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    assert_eq!(ConnectionInfo::new(&ClientSession::new(&cconfig, domain), Some(false)), None);
+
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(cconfig);
+    let handshake = || {
+        let mut server = ServerSession::new(&sconfig);
+        let mut stream = connector.connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        // take in the ticket
+        let mut buf = [0; 1];
+        let _ = io::Read::read(&mut stream, &mut buf);
+        let server = ConnectionInfo::new(stream.get_ref().0 .0, None).unwrap();
+        (stream.connection_info().unwrap(), server)
+    };
+
+    let (info, server) = handshake();
+    assert_eq!(info, ConnectionInfo {
+        protocol_version: ProtocolVersion::TLSv1_3,
+        cipher_suite: CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        alpn_protocol: Some(b"h2".to_vec()),
+        resumed: Some(false),
+        peer_certificates: 1,
+    });
+    assert_eq!(server.resumed, None);
+
+    // the ticket from the first handshake resumes the second
+    let (info, server) = handshake();
+    assert_eq!(info.resumed, Some(true));
+    assert_eq!(info.peer_certificates, 0);
+    assert_eq!(server.resumed, None);
+}
+
+#[test]
+fn session_mid_handshake() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: false, write: true };
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut connect = TlsConnector::from(Arc::new(cconfig)).connect(domain, io);
+
+    // the ClientHello is out, nothing is negotiated yet
+    assert!(connect.poll()?.is_not_ready());
+    assert_eq!(connect.get_session().unwrap().get_protocol_version(), None);
+
+    // the server flight is in, but the client Finished is held back
+    gated(&mut connect).write = false;
+    gated(&mut connect).read = true;
+    assert!(connect.poll()?.is_not_ready());
+    let session = connect.get_session().unwrap();
+    assert_eq!(session.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    gated(&mut connect).write = true;
+    assert!(connect.poll()?.is_ready());
+    assert!(connect.get_session().is_none());
+
+    Ok(())
+}
+
+/// Session storage that marks every stored ticket as allowing early data,
+/// since a rustls server only offers it over QUIC.
+#[cfg(feature = "early-data")]
+struct EarlyDataTickets(Arc<rustls::ClientSessionMemoryCache>);
+
+#[cfg(feature = "early-data")]
+impl rustls::StoresClientSessions for EarlyDataTickets {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        use rustls::internal::msgs::codec::Codec;
+        use rustls::internal::msgs::persist::ClientSessionValue;
+
+        let value = match ClientSessionValue::read_bytes(&value) {
+            Some(mut ticket) => {
+                ticket.set_max_early_data_size(1024);
+                ticket.get_encoding()
+            }
+            None => value,
+        };
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn flush_early_data() -> io::Result<()> {
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.enable_early_data = true;
+    cconfig.session_persistence = Arc::new(EarlyDataTickets(rustls::ClientSessionMemoryCache::new(8)));
+    let connector = TlsConnector::from(Arc::new(cconfig.clone())).early_data(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    // a full handshake, reading the server's ticket
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let mut stream = connect(&mut server, cconfig);
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+
+    let mut stream = connector.connect(domain, Recorder(Vec::new()))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    stream.write_all(b"early")?;
+    assert!(stream.io.0.is_empty());
+    assert!(!stream.sent_early_application_data());
+
+    assert!(stream.poll_flush_early_data()?.is_ready());
+    assert!(stream.sent_early_application_data());
+    let wire = &stream.io.0;
+    assert_eq!(wire[0], 0x16);
+    let mut types = Vec::new();
+    let mut rest = &wire[..];
+    while rest.len() >= 5 {
+        types.push(rest[0]);
+        let len = (usize::from(rest[3]) << 8) | usize::from(rest[4]);
+        rest = &rest[5 + len..];
+    }
+    assert_eq!(types.last(), Some(&0x17));
+    Ok(())
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn strict_read_before_handshake() {
+    let (_, mut cconfig) = make_configs();
+    cconfig.enable_early_data = true;
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .early_data(true)
+        .strict(true)
+        .connect(domain, Recorder(Vec::new()))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    let err = io::Read::read(&mut stream, &mut [0; 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    assert!(stream.io.0.is_empty());
+}
+
+#[test]
+fn drain_first_write_policy() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .write_policy(WritePolicy::DrainFirst)
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    // the first write is queued, the second waits for it to go out
+    stream.io.write = false;
+    assert_eq!(stream.write(b"a")?, 1);
+    let err = stream.write(b"b").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    stream.io.write = true;
+    assert_eq!(stream.write(b"b")?, 1);
+    let mut buf = [0; 2];
+    io::Read::read_exact(&mut *stream.io.good.0, &mut buf)?;
+    assert_eq!(&buf, b"ab");
+    Ok(())
+}
+
+#[test]
+fn read_keeps_decrypted_plaintext() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    // the record is taken off the socket, but no plaintext is copied out
+    stream.io.good.0.write_all(b"hello")?;
+    assert_eq!(io::Read::read(&mut stream, &mut [])?, 0);
+    stream.io.read = false;
+
+    let mut buf = [0; 5];
+    io::Read::read_exact(&mut stream, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn early_data_phase() {
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.enable_early_data = true;
+    cconfig.session_persistence = Arc::new(EarlyDataTickets(rustls::ClientSessionMemoryCache::new(8)));
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(Arc::new(cconfig.clone())).early_data(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    {
+        let mut server = ServerSession::new(&sconfig);
+        let mut stream = connect(&mut server, cconfig);
+        let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    }
+
+    let mut server = ServerSession::new(&sconfig);
+    let mut stream = connector.connect(domain, Good(&mut server))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert!(stream.in_early_data_phase());
+
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    assert!(!stream.get_ref().1.is_handshaking());
+    assert!(!stream.in_early_data_phase());
+}
+
+#[test]
+fn write_after_read_shutdown() -> io::Result<()> {
+    for &half_close in &[true, false] {
+        let (mut server, cconfig) = make_server();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(cconfig))
+            .half_close(half_close)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+        // `Good` reports EOF once the server has nothing to send
+        assert_eq!(io::Read::read(&mut stream, &mut [0; 1])?, 0);
+
+        let ret = stream.write(b"x");
+        if half_close {
+            assert_eq!(ret?, 1);
+            let mut buf = [0; 1];
+            io::Read::read_exact(&mut *stream.io.0, &mut buf)?;
+            assert_eq!(&buf, b"x");
+        } else {
+            assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn from_pem() {
+    let acceptor = TlsAcceptor::from_pem(
+        include_bytes!("../tests/end.cert"),
+        include_bytes!("../tests/end.rsa"),
+    ).unwrap();
+    let connector = TlsConnector::from_pem_roots(include_bytes!("../tests/end.chain")).unwrap();
+
+    let mut server = ServerSession::new(&acceptor.inner);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = connector.connect(domain, Good(&mut server))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert!(!stream.get_ref().1.is_handshaking());
+
+    let err = TlsConnector::from_pem_roots(b"not a certificate").map(drop).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn write_semantics() -> io::Result<()> {
+    for &semantics in &[WriteSemantics::Buffered, WriteSemantics::OnWire] {
+        let (mut server, cconfig) = make_server();
+        let io = Gated { good: Good(&mut server), read: true, write: true };
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(cconfig))
+            .write_semantics(semantics)
+            .connect(domain, io)
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+        stream.io.write = false;
+        let ret = stream.write(b"ping");
+        if let WriteSemantics::Buffered = semantics {
+            assert_eq!(ret?, 4);
+        } else {
+            assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+            assert!(stream.write(b"ping").is_err());
+
+            stream.io.write = true;
+            assert_eq!(stream.write(b"ping")?, 4);
+            assert!(!stream.get_ref().1.wants_write());
+        }
+
+        stream.io.write = true;
+        stream.flush()?;
+        let mut buf = [0; 5];
+        assert_eq!(io::Read::read(&mut *stream.io.good.0, &mut buf)?, 4);
+        assert_eq!(&buf[..4], b"ping");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn early_data_remaining() -> io::Result<()> {
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.enable_early_data = true;
+    cconfig.session_persistence = Arc::new(EarlyDataTickets(rustls::ClientSessionMemoryCache::new(8)));
+    let connector = TlsConnector::from(Arc::new(cconfig.clone())).early_data(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    {
+        let mut server = ServerSession::new(&Arc::new(sconfig));
+        let mut stream = connect(&mut server, cconfig);
+        let _ = io::Read::read(&mut stream, &mut [0; 1]);
+        assert_eq!(stream.early_data_remaining(), None);
+        assert!(!stream.sent_early_application_data());
+    }
+
+    // nothing answers, so the handshake stays in the early-data phase
+    let mut stream = connector.connect(domain, Recorder(Vec::new()))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert_eq!(stream.early_data_remaining(), Some(1024));
+    stream.write_all(b"early")?;
+    assert_eq!(stream.early_data_remaining(), Some(1019));
+    Ok(())
+}
+
+#[test]
+fn byte_counts() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    assert_eq!(stream.byte_counts(), (0, 0));
+
+    stream.write_all(&[0; 3000])?;
+    let mut buf = vec![0; 3000];
+    io::Read::read_exact(&mut *stream.io.0, &mut buf)?;
+
+    stream.io.0.write_all(&[1; 1200])?;
+    let mut buf = vec![0; 1200];
+    io::Read::read_exact(&mut stream, &mut buf)?;
+    assert_eq!(stream.byte_counts(), (1200, 3000));
+    Ok(())
+}
+
+#[test]
+fn post_handshake_auth_unsupported() {
+    use rustls::internal::msgs::enums::HandshakeType;
+
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    let err = stream.poll_provide_client_cert().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+    // what rustls reports for a CertificateRequest after the handshake
+    let err = TLSError::InappropriateHandshakeMessage {
+        expect_types: vec![HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate],
+        got_type: HandshakeType::CertificateRequest,
+    };
+    assert!(common::is_post_handshake_auth(&err));
+    assert!(!common::is_post_handshake_auth(&TLSError::DecryptError));
+}
+
+#[test]
+fn write_close_in_one_flush() -> io::Result<()> {
+    struct Writes(Vec<Vec<u8>>);
+
+    impl io::Read for Writes {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl io::Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Writes {}
+    impl AsyncWrite for Writes {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        // gathers like a socket's writev
+        fn write_buf<B: bytes::Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+            let mut gathered = Vec::new();
+            while buf.has_remaining() {
+                let len = buf.bytes().len();
+                gathered.extend_from_slice(buf.bytes());
+                buf.advance(len);
+            }
+            let len = gathered.len();
+            self.0.push(gathered);
+            Ok(Async::Ready(len))
+        }
+    }
+
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+    let (_, session) = stream.into_inner();
+    let mut stream = client::TlsStream::new(Writes(Vec::new()), session);
+
+    assert_eq!(stream.poll_write_close(b"bye")?, Async::Ready(3));
+    assert_eq!(stream.io.0.len(), 1);
+
+    // the data record and the alert record
+    let mut records = 0;
+    let mut rest = &stream.io.0[0][..];
+    while !rest.is_empty() {
+        let len = (usize::from(rest[3]) << 8) | usize::from(rest[4]);
+        rest = &rest[5 + len..];
+        records += 1;
+    }
+    assert_eq!(records, 2);
+
+    server.read_tls(&mut &stream.io.0[0][..])?;
+    server.process_new_packets().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut buf = Vec::new();
+    let err = io::Read::read_to_end(&mut server, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    assert_eq!(buf, b"bye");
+    Ok(())
+}
+
+#[test]
+fn close_notify_on_eof() -> io::Result<()> {
+    struct DeadWrite<'a> {
+        good: Good<'a>,
+        dead: bool,
+    }
+
+    impl<'a> io::Read for DeadWrite<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.good.read(buf)
+        }
+    }
+
+    impl<'a> io::Write for DeadWrite<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.dead {
+                Err(io::ErrorKind::BrokenPipe.into())
+            } else {
+                self.good.write(buf)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> AsyncRead for DeadWrite<'a> {}
+    impl<'a> AsyncWrite for DeadWrite<'a> {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    for &reply in &[true, false] {
+        let (mut server, cconfig) = make_server();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(cconfig))
+            .close_notify_on_eof(reply)
+            .connect(domain, DeadWrite { good: Good(&mut server), dead: false })
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+        stream.io.good.0.send_close_notify();
+        stream.io.dead = true;
+        assert_eq!(io::Read::read(&mut stream, &mut [0; 1])?, 0);
+
+        let ret = stream.shutdown();
+        if reply {
+            assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        } else {
+            assert!(ret?.is_ready());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "idle-timeout")]
+#[test]
+fn close_timeout() {
+    use std::time::{ Duration, Instant };
+    use idle::CloseTimeoutAction;
+
+    for &action in &[CloseTimeoutAction::Finish, CloseTimeoutAction::Fail] {
+        let (mut server, cconfig) = make_server();
+        let io = Gated { good: Good(&mut server), read: true, write: true };
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(cconfig))
+            .connect(domain, io)
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+        // the peer never drains the close_notify
+        stream.io.write = false;
+        let timeout = Duration::from_millis(50);
+        stream.set_close_timeout(timeout, action);
+        let start = Instant::now();
+        let ret = tokio::runtime::current_thread::Runtime::new()
+            .unwrap()
+            .block_on(futures::future::poll_fn(|| stream.shutdown()));
+        assert!(start.elapsed() >= timeout);
+        match action {
+            CloseTimeoutAction::Finish => assert!(ret.is_ok()),
+            CloseTimeoutAction::Fail => assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::TimedOut),
+        }
+    }
+}
+
+#[test]
+fn read_exact_or_eof() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig.clone());
+    stream.io.0.write_all(b"abcdef")?;
+    let mut buf = [0; 4];
+    assert_eq!(stream.read_exact_or_eof(&mut buf).wait()?, ReadExactOutcome::Complete);
+    assert_eq!(&buf, b"abcd");
+
+    // a short read, then close_notify
+    stream.io.0.send_close_notify();
+    assert_eq!(stream.read_exact_or_eof(&mut buf).wait()?, ReadExactOutcome::PartialThenEof {
+        read: 2,
+        close_notify: true,
+    });
+    assert_eq!(&buf[..2], b"ef");
+
+    // `Good` reaches EOF without any close_notify
+    let (mut server, _) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    assert_eq!(stream.read_exact_or_eof(&mut buf).wait()?, ReadExactOutcome::PartialThenEof {
+        read: 0,
+        close_notify: false,
+    });
+    Ok(())
+}
+
+/// Hands everything to `Good`, counting the bytes read from the server.
+struct Counted<'a>(Good<'a>, usize);
+
+impl<'a> io::Read for Counted<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        self.1 += n;
+        Ok(n)
+    }
+}
+
+impl<'a> io::Write for Counted<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> AsyncRead for Counted<'a> {}
+impl<'a> AsyncWrite for Counted<'a> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn connect_with_session_store() {
+    let (sconfig, cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+
+    // how much the server sent: a resumed handshake carries no certificate
+    let handshake = |store: &Arc<rustls::ClientSessionMemoryCache>| -> usize {
+        let mut server = ServerSession::new(&sconfig);
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = connector.connect_with_session_store(domain, Counted(Good(&mut server), 0), store.clone())
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        let _ = io::Read::read(&mut stream, &mut [0; 1]);
+        stream.io.1
+    };
+
+    let (tenant_a, tenant_b) = (rustls::ClientSessionMemoryCache::new(8), rustls::ClientSessionMemoryCache::new(8));
+    let full = handshake(&tenant_a);
+    assert!(handshake(&tenant_a) < full / 2);
+    assert!(handshake(&tenant_b) > full / 2);
+    assert!(handshake(&tenant_b) < full / 2);
+}
+
+#[test]
+fn has_unflushed_plaintext() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert!(!stream.has_unflushed_plaintext());
+
+    stream.io.write = false;
+    stream.write_all(b"pending")?;
+    assert!(stream.has_unflushed_plaintext());
+
+    stream.io.write = true;
+    stream.flush()?;
+    assert!(!stream.has_unflushed_plaintext());
+    Ok(())
+}
+
+#[test]
+fn record_reads() -> io::Result<()> {
+    for &flag in &[true, false] {
+        let (mut server, cconfig) = make_server();
+        let (_, session) = connect(&mut server, cconfig).into_inner();
+
+        // all three records arrive in one read
+        let mut wire = Vec::new();
+        for record in &[&b"one"[..], b"two", b"three"] {
+            server.write_all(record)?;
+        }
+        while server.wants_write() {
+            server.write_tls(&mut wire)?;
+        }
+        let mut stream = client::TlsStream::new(Prefixed::new(wire, Good(&mut server)), session);
+        stream.set_record_reads(flag);
+
+        let mut buf = [0; 64];
+        if flag {
+            for record in &[&b"one"[..], b"two", b"three"] {
+                let n = io::Read::read(&mut stream, &mut buf)?;
+                assert_eq!(&buf[..n], *record);
+            }
+        } else {
+            let n = io::Read::read(&mut stream, &mut buf)?;
+            assert_eq!(&buf[..n], b"onetwothree");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn pause_reads() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    stream.io.good.0.write_all(b"hello")?;
+    let mut buf = [0; 2];
+    io::Read::read_exact(&mut stream, &mut buf)?;
+    assert_eq!(&buf, b"he");
+
+    // what was already read is delivered, what the server sends stays put
+    stream.pause_reads();
+    stream.io.good.0.write_all(b"world")?;
+    let mut buf = [0; 16];
+    let n = io::Read::read(&mut stream, &mut buf)?;
+    assert_eq!(&buf[..n], b"llo");
+    let err = io::Read::read(&mut stream, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(stream.io.good.0.wants_write());
+
+    stream.resume_reads();
+    let n = io::Read::read(&mut stream, &mut buf)?;
+    assert_eq!(&buf[..n], b"world");
+    assert!(!stream.io.good.0.wants_write());
+
+    Ok(())
+}
+
+#[test]
+fn offered_alpn_protocols() {
+    let (mut sconfig, mut cconfig) = make_configs();
+    sconfig.set_protocols(&[b"http/1.1".to_vec()]);
+    cconfig.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let sconfig = Arc::new(sconfig);
+    let cconfig = Arc::new(cconfig);
+
+    let mut client = ClientSession::new(&cconfig, domain);
+    let stream = TlsAcceptor::from(sconfig.clone())
+        .record_hellos(true)
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert_eq!(stream.offered_alpn_protocols(), Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]));
+    assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    // nothing is kept by default
+    let mut client = ClientSession::new(&cconfig, domain);
+    let stream = TlsAcceptor::from(sconfig)
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert!(stream.ctx.client_hello.is_none());
+    assert_eq!(stream.offered_alpn_protocols(), None);
+
+    // without the extension
+    let (sconfig, cconfig) = make_configs();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let stream = TlsAcceptor::from(Arc::new(sconfig))
+        .record_hellos(true)
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert_eq!(stream.offered_alpn_protocols(), None);
+}
+
+#[test]
+fn selected_key_share_group() {
+    const X25519: u16 = 0x001d;
+
+    let (sconfig, _) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let group = |cconfig: &Arc<ClientConfig>| {
+        let mut client = ClientSession::new(cconfig, domain);
+        let stream = TlsAcceptor::from(sconfig.clone())
+            .record_hellos(true)
+            .accept(Good(&mut client))
+            .wait()
+            .unwrap_or_else(|err| panic!("accept failed: {}", err));
+        stream.selected_key_share_group()
+    };
+
+    // rustls clients send an X25519 key share, their first choice, and
+    // resume TLS 1.3 sessions with a key exchange too
+    let cconfig = Arc::new(make_configs().1);
+    assert_eq!(group(&cconfig), Some(X25519));
+    assert_eq!(group(&cconfig), Some(X25519));
+
+    // TLS 1.2 resumes without one
+    let mut cconfig = make_configs().1;
+    cconfig.versions = vec![ProtocolVersion::TLSv1_2];
+    let cconfig = Arc::new(cconfig);
+    assert_eq!(group(&cconfig), Some(X25519));
+    assert_eq!(group(&cconfig), None);
+
+    // not known yet while handshaking
+    let (_, cconfig) = make_configs();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let mut accept = TlsAcceptor::from(sconfig.clone())
+        .record_hellos(true)
+        .accept(Gated { good: Good(&mut client), read: true, write: false });
+    assert!(accept.poll().unwrap().is_not_ready());
+    match &accept.0 {
+        server::MidHandshake::Handshaking(stream) => assert_eq!(stream.selected_key_share_group(), None),
+        server::MidHandshake::End => panic!("handshake already finished"),
+    }
+
+    // a default acceptor follows none of its hellos
+    let (_, cconfig) = make_configs();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let stream = TlsAcceptor::from(sconfig)
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert!(stream.ctx.server_kex.is_none());
+    assert!(stream.ctx.client_hello.is_none());
+    assert_eq!(stream.selected_key_share_group(), None);
+}
+
+#[test]
+fn export_resumption() -> io::Result<()> {
+    let (sconfig, cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    // nothing is kept unless asked for
+    let mut server = ServerSession::new(&sconfig);
+    let mut stream = connect(&mut server, cconfig.clone());
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    assert!(stream.ctx.session_record.is_none());
+    assert_eq!(stream.export_resumption(), None);
+
+    let mut server = ServerSession::new(&sconfig);
+    let mut stream = TlsConnector::from(Arc::new(cconfig.clone()))
+        .exportable_resumption(true)
+        .connect(domain, Good(&mut server))
+        .wait()?;
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    let data = stream.export_resumption().expect("a ticket should have arrived");
+    assert_eq!(data.server_name(), "localhost");
+
+    // elsewhere, with nowhere to keep sessions but the exported one
+    let data = ResumptionData::from_bytes(&data.to_bytes())?;
+    let mut cconfig = cconfig;
+    cconfig.session_persistence = Arc::new(rustls::NoClientSessionStorage {});
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let handshake = |data: Option<&ResumptionData>| -> usize {
+        let mut server = ServerSession::new(&sconfig);
+        let io = Counted(Good(&mut server), 0);
+        let connect = match data {
+            Some(data) => connector.connect_resuming(domain, io, data),
+            None => connector.connect(domain, io),
+        };
+        let stream = connect.wait().unwrap_or_else(|err| panic!("connect failed: {}", err));
+        stream.io.1
+    };
+    assert!(handshake(Some(&data)) < handshake(None) / 2);
+
+    let err = ResumptionData::from_bytes(b"\x20localhost").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut bytes = data.to_bytes();
+    bytes.pop();
+    let err = ResumptionData::from_bytes(&bytes).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn established_at() {
+    use std::time::Instant;
+
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = client::TlsStream::new((), ClientSession::new(&Arc::new(cconfig.clone()), domain));
+    assert_eq!(stream.established_at(), None);
+
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let before = Instant::now();
+    let mut stream = connect(&mut server, cconfig);
+    let established = stream.established_at().expect("the handshake is done");
+    assert!(before <= established && established <= Instant::now());
+
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    assert_eq!(stream.established_at(), Some(established));
+}
+
+#[test]
+fn require_explicit_handshake() -> io::Result<()> {
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let session = ClientSession::new(&Arc::new(cconfig), domain);
+    let mut stream = client::TlsStream::new(Good(&mut server), session);
+    stream.set_require_explicit_handshake(true);
+
+    let err = io::Read::read(&mut stream, &mut [0; 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    let err = stream.write(b"hello").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    let err = stream.flush().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    let err = stream.poll_write_close(b"bye").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    assert!(stream.get_ref().1.is_handshaking());
+
+    assert!(stream.poll_handshake()?.is_ready());
+    assert!(!stream.get_ref().1.is_handshaking());
+    stream.write_all(b"hello")?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[test]
+fn poll_read_ready() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()?;
+    assert!(stream.poll_read_ready()?.is_not_ready());
+
+    // the socket has bytes, but they are not decrypted yet
+    stream.get_mut().0.good.0.write_all(b"hello")?;
+    assert!(stream.poll_read_ready()?.is_ready());
+    assert!(!stream.ctx.partial_tls.is_empty());
+
+    stream.get_mut().0.read = false;
+    assert!(stream.poll_read_ready()?.is_ready());
+    let mut buf = [0; 5];
+    assert_eq!(io::Read::read(&mut stream, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    assert!(stream.poll_read_ready()?.is_not_ready());
+    Ok(())
+}
+
+#[test]
+fn send_close_notify_on_drop() {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    // what the server reads once the client stream is dropped
+    let after_drop = |flag: bool| -> io::Result<usize> {
+        let (mut server, cconfig) = make_server();
+        let stream = TlsConnector::from(Arc::new(cconfig))
+            .send_close_notify_on_drop(flag)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("handshake failed: {}", err));
+        drop(stream);
+
+        io::Read::read(&mut server, &mut [0; 16])
+    };
+
+    let err = after_drop(true).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    assert!(after_drop(false).is_ok());
+
+    // handing back the IO disarms it
+    let (mut server, cconfig) = make_server();
+    let stream = TlsConnector::from(Arc::new(cconfig))
+        .send_close_notify_on_drop(true)
+        .connect(domain, Good(&mut server))
+        .wait()
+        .unwrap();
+    drop(stream.into_inner());
+    assert!(io::Read::read(&mut server, &mut [0; 16]).is_ok());
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn early_data_rejected() -> io::Result<()> {
+    /// Drops the 0-RTT records, which a rustls 0.16 server fails to skip
+    /// when it rejects early data.
+    struct SkipEarlyData<'a> {
+        good: Good<'a>,
+        answered: bool,
+    }
+
+    impl<'a> io::Read for SkipEarlyData<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.good.read(buf)?;
+            self.answered |= n != 0;
+            Ok(n)
+        }
+    }
+
+    impl<'a> io::Write for SkipEarlyData<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.answered {
+                return self.good.write(buf);
+            }
+
+            // before the server answers, 0x17 records can only be early data
+            let mut rest = buf;
+            while rest.len() >= 5 {
+                let len = 5 + ((usize::from(rest[3]) << 8) | usize::from(rest[4]));
+                if rest[0] != 0x17 {
+                    self.good.write_all(&rest[..len])?;
+                }
+                rest = &rest[len..];
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> AsyncRead for SkipEarlyData<'a> {}
+    impl<'a> AsyncWrite for SkipEarlyData<'a> {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.enable_early_data = true;
+    cconfig.session_persistence = Arc::new(EarlyDataTickets(rustls::ClientSessionMemoryCache::new(8)));
+    let connector = TlsConnector::from(Arc::new(cconfig.clone())).early_data(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let sconfig = Arc::new(sconfig);
+
+    // a full handshake, reading the server's ticket
+    let mut server = ServerSession::new(&sconfig);
+    let mut stream = connect(&mut server, cconfig);
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    assert!(!stream.early_data_rejected());
+
+    // a rustls server never takes 0-RTT
+    let mut server = ServerSession::new(&sconfig);
+    let io = SkipEarlyData { good: Good(&mut server), answered: false };
+    let mut stream = connector.connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    stream.write_all(b"early")?;
+    assert!(!stream.early_data_rejected());
+    stream.flush()?;
+    let _ = io::Read::read(&mut stream, &mut [0; 1]);
+    assert!(stream.sent_early_application_data());
+    assert!(stream.early_data_rejected());
+
+    let mut buf = [0; 5];
+    io::Read::read_exact(&mut *stream.io.good.0, &mut buf)?;
+    assert_eq!(&buf, b"early");
+    Ok(())
+}
+
+#[test]
+fn set_buffer_limit() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()?;
+    assert_eq!(stream.buffer_limit(), Some(common::DEFAULT_BUFFER_LIMIT));
+
+    // the socket is full, so a small limit pushes back early
+    stream.set_buffer_limit(Some(1024));
+    assert_eq!(stream.buffer_limit(), Some(1024));
+    stream.get_mut().0.write = false;
+    let len = stream.write(&[0x42; 4096])?;
+    assert!(len > 0 && len <= 1024, "{}", len);
+    let err = stream.write(&[0x42; 4096]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    stream.get_mut().0.write = true;
+    assert!(stream.poll_writable()?.is_ready());
+    stream.set_buffer_limit(None);
+    assert_eq!(stream.buffer_limit(), None);
+    stream.get_mut().0.write = false;
+    assert_eq!(stream.write(&[0x42; 4096])?, 4096);
+    Ok(())
+}
+
+#[cfg(feature = "coalesce")]
+#[test]
+fn write_coalesce() {
+    use std::thread;
+    use std::time::Duration;
+
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    let window = Duration::from_millis(50);
+    stream.set_write_coalesce(Some(window));
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    runtime.block_on(futures::future::lazy(|| -> io::Result<()> {
+        let start = stream.ctx.records_written.count;
+
+        // within the window: held together
+        stream.write_all(b"a")?;
+        stream.write_all(b"b")?;
+        assert_eq!(stream.ctx.records_written.count, start);
+
+        // spaced beyond it: each goes out with the next write
+        thread::sleep(window * 2);
+        stream.write_all(b"c")?;
+        assert_eq!(stream.ctx.records_written.count, start + 1);
+        thread::sleep(window * 2);
+        stream.write_all(b"d")?;
+        assert_eq!(stream.ctx.records_written.count, start + 2);
+
+        // a full record does not wait, taking the `d` along
+        stream.write_all(&[b'e'; 16 * 1024])?;
+        assert_eq!(stream.ctx.records_written.count, start + 3);
+
+        stream.flush()?;
+        assert_eq!(stream.ctx.records_written.count, start + 4);
+        stream.write_all(b"f")?;
+        stream.flush()?;
+        assert_eq!(stream.ctx.records_written.count, start + 5);
+        Ok(())
+    })).unwrap();
+
+    let mut buf = vec![0; 5 + 16 * 1024];
+    io::Read::read_exact(&mut *stream.get_mut().0 .0, &mut buf).unwrap();
+    assert_eq!(&buf[..4], b"abcd");
+    assert_eq!(buf[buf.len() - 1], b'f');
+}
+
+#[test]
+fn connect_full_handshake() {
+    let (sconfig, cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(Arc::new(cconfig)).exportable_resumption(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let handshake = |full: bool| -> usize {
+        let mut server = ServerSession::new(&sconfig);
+        let io = Counted(Good(&mut server), 0);
+        let connect = if full {
+            connector.connect_full_handshake(domain, io)
+        } else {
+            connector.connect(domain, io)
+        };
+        let mut stream = connect.wait().unwrap_or_else(|err| panic!("connect failed: {}", err));
+        // take in the ticket
+        let mut buf = [0; 1];
+        let _ = io::Read::read(&mut stream, &mut buf);
+        assert!(stream.export_resumption().is_some());
+        stream.io.1
+    };
+
+    // the first handshake leaves a ticket behind for the second to resume
+    let first = handshake(false);
+    let resumed = handshake(false);
+    assert!(resumed < first / 2);
+
+    assert!(handshake(true) > first / 2);
+    // the ticket is still there, and the full handshake left another
+    assert!(handshake(false) < first / 2);
+}
+
+#[cfg(feature = "early-data")]
+#[test]
+fn connect_full_handshake_early_data() {
+    let (sconfig, mut cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    cconfig.enable_early_data = true;
+    cconfig.session_persistence = Arc::new(EarlyDataTickets(rustls::ClientSessionMemoryCache::new(8)));
+    let connector = TlsConnector::from(Arc::new(cconfig))
+        .early_data(true)
+        .exportable_resumption(true);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let handshake = |full: bool| -> usize {
+        let mut server = ServerSession::new(&sconfig);
+        let io = Counted(Good(&mut server), 0);
+        let connect = if full {
+            connector.connect_full_handshake(domain, io)
+        } else {
+            connector.connect(domain, io)
+        };
+        let mut stream = connect.wait().unwrap_or_else(|err| panic!("connect failed: {}", err));
+        // finish the handshake and take in the ticket
+        let _ = io::Read::read(&mut stream, &mut [0; 1]);
+        assert!(stream.export_resumption().is_some());
+        stream.io.1
+    };
+
+    let first = handshake(false);
+    assert!(handshake(true) > first / 2);
+    // the full handshake's ticket went to the config's store, not just
+    // to the one keeping it from resuming
+    assert!(handshake(false) < first / 2);
+}
+
+#[test]
+fn peer_verified() {
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let session = ClientSession::new(&Arc::new(cconfig.clone()), domain);
+    assert_eq!(client::TlsStream::new(Good(&mut server), session).peer_verified(), None);
+
+    // a verifier from `ClientConfig::dangerous` could have accepted anything
+    let expected = if cfg!(feature = "dangerous_configuration") { None } else { Some(true) };
+    let (mut server, _) = make_server();
+    assert_eq!(connect(&mut server, cconfig).peer_verified(), expected);
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn peer_verified_recheck() {
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    // as of the time `connect_with_time` verified the chain
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let now = UNIX_EPOCH + Duration::from_secs(2000000000);
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .recheck_server_certificate(true)
+        .connect_with_time(domain, Good(&mut server), now)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert_eq!(stream.ctx.peer_check.as_ref().unwrap().now, Some(now));
+    assert_eq!(stream.peer_verified(), Some(true));
+    // `tests/end.cert` expires after 2107311651
+    stream.ctx.peer_check.as_mut().unwrap().now = Some(UNIX_EPOCH + Duration::from_secs(2107311652));
+    assert_eq!(stream.peer_verified(), Some(false));
+
+    // a resumed session presents no chain to check
+    let (sconfig, cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let cconfig = Arc::new(cconfig);
+    let handshake = |recheck: bool| {
+        let mut server = ServerSession::new(&sconfig);
+        let mut stream = TlsConnector::from(cconfig.clone())
+            .recheck_server_certificate(recheck)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        // take in the ticket
+        let _ = io::Read::read(&mut stream, &mut [0; 1]);
+        (stream.connection_info().unwrap().resumed, stream.peer_verified())
+    };
+    assert_eq!(handshake(true), (Some(false), Some(true)));
+    assert_eq!(handshake(true), (Some(true), None));
+    assert_eq!(handshake(false), (Some(true), None));
+}
+
+#[cfg(feature = "coalesce")]
+#[test]
+fn poll_write_urgent() {
+    use std::time::Duration;
+
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    stream.set_write_coalesce(Some(Duration::from_secs(60)));
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    runtime.block_on(futures::future::lazy(|| -> io::Result<()> {
+        let start = stream.ctx.records_written.count;
+
+        // bulk data is held back
+        stream.write_all(b"bulk")?;
+        assert_eq!(stream.ctx.records_written.count, start);
+
+        // a control message is not, and goes out behind it
+        assert_eq!(stream.poll_write_urgent(b"ping")?, Async::Ready(4));
+        assert_eq!(stream.ctx.records_written.count, start + 2);
+        Ok(())
+    })).unwrap();
+
+    let mut buf = [0; 8];
+    io::Read::read_exact(&mut *stream.get_mut().0 .0, &mut buf).unwrap();
+    assert_eq!(&buf, b"bulkping");
+}
@@ -0,0 +1,27 @@
+//! Ready-made configs for loopback tests.
+
+use { TlsAcceptor, TlsConnector };
+
+const CERT: &[u8] = include_bytes!("../tests/end.cert");
+const KEY: &[u8] = include_bytes!("../tests/end.rsa");
+const CHAIN: &[u8] = include_bytes!("../tests/end.chain");
+
+/// The name the certificate of `bundled_localhost_pair` is valid for; it is
+/// also valid for `testserver.com` and `second.testserver.com`.
+pub const DOMAIN: &str = "localhost";
+
+/// An acceptor and a connector that trusts it, to handshake with each
+/// other over `DOMAIN`.
+///
+/// Nothing is generated: there is no certificate generator among our
+/// dependencies, so this uses the fixed chain the crate's own tests run
+/// on, built into the library. The acceptor serves a leaf issued by the
+/// `ponytown RSA level 2 intermediate`, and the connector trusts that
+/// intermediate and the `ponytown RSA CA` above it. All three expire on
+/// 2036-10-11, after which handshakes fail until a release ships new
+/// ones. Their keys are public: never trust them outside of tests.
+pub fn bundled_localhost_pair() -> (TlsAcceptor, TlsConnector) {
+    let acceptor = TlsAcceptor::from_pem(CERT, KEY).expect("bundled test certificate is valid");
+    let connector = TlsConnector::from_pem_roots(CHAIN).expect("bundled test CA is valid");
+    (acceptor, connector)
+}
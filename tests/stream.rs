@@ -0,0 +1,2068 @@
+extern crate bytes;
+extern crate futures;
+extern crate rustls;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_rustls;
+extern crate webpki;
+
+use std::cmp;
+use std::io::{ self, BufReader, Cursor, Read, Write };
+use std::cell::{ Cell, RefCell };
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use futures::{ Async, Future, Poll };
+use rustls::{ CipherSuite, ClientConfig, ProtocolVersion, ClientSession, ServerConfig, ServerSession, Session, TLSError, ResolvesServerCertUsingSNI };
+use rustls::{ Certificate, NoClientAuth, PrivateKey };
+use rustls::internal::pemfile::{ certs, rsa_private_keys };
+use rustls::sign::{ CertifiedKey, RSASigningKey };
+use webpki::DNSNameRef;
+use tokio_io::{ AsyncRead, AsyncWrite };
+use tokio_rustls::{ client, server, BoxedIo, CipherStrength, HandshakeProgress, HandshakeTooLarge, NameMatchKind, PeerAuthKind, RetryPolicy, TlsAcceptor, TlsConnector };
+use tokio_rustls::duplex::Duplex;
+use tokio_rustls::inspect::Direction;
+use tokio_rustls::prefixed::Prefixed;
+
+const CERT: &str = include_str!("end.cert");
+const CHAIN: &str = include_str!("end.chain");
+const RSA: &str = include_str!("end.rsa");
+
+/// Drives a session as if it were at the other end of the IO.
+struct Good<'a>(&'a mut dyn Session);
+
+impl<'a> Read for Good<'a> {
+    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+        self.0.write_tls(buf.by_ref())
+    }
+}
+
+impl<'a> Write for Good<'a> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = self.0.read_tls(buf.by_ref())?;
+        self.0.process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> AsyncRead for Good<'a> {}
+impl<'a> AsyncWrite for Good<'a> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn load_cert_key() -> (Vec<Certificate>, PrivateKey) {
+    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    (cert, keys.pop().unwrap())
+}
+
+fn make_configs() -> (ServerConfig, ClientConfig) {
+    let (cert, key) = load_cert_key();
+    let mut sconfig = ServerConfig::new(NoClientAuth::new());
+    sconfig.set_single_cert(cert, key).unwrap();
+
+    let mut cconfig = ClientConfig::new();
+    let mut chain = BufReader::new(Cursor::new(CHAIN));
+    cconfig.root_store.add_pem_file(&mut chain).unwrap();
+
+    (sconfig, cconfig)
+}
+
+/// A server session, and a client config trusting its certificate.
+fn make_server() -> (ServerSession, ClientConfig) {
+    let (sconfig, cconfig) = make_configs();
+    (ServerSession::new(&Arc::new(sconfig)), cconfig)
+}
+
+fn connect<'a>(server: &'a mut ServerSession, cconfig: ClientConfig) -> client::TlsStream<Good<'a>> {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Good(server))
+        .wait()
+        .unwrap_or_else(|err| panic!("handshake failed: {}", err))
+}
+
+/// A transport whose reads and writes can be blocked independently; a read
+/// with nothing to deliver blocks too, like a non-blocking socket.
+struct Gated<'a> {
+    good: Good<'a>,
+    read: bool,
+    write: bool,
+}
+
+impl<'a> io::Read for Gated<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        match self.good.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            ret => ret,
+        }
+    }
+}
+
+impl<'a> io::Write for Gated<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write {
+            self.good.write(buf)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> AsyncRead for Gated<'a> {}
+impl<'a> AsyncWrite for Gated<'a> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A transport that never has anything to read and records all writes.
+struct Recorder(Vec<u8>);
+
+impl io::Read for Recorder {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl io::Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Recorder {}
+impl AsyncWrite for Recorder {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A transport to a shared server session that fails with `ConnectionReset`
+/// when `reset` is set.
+struct Flaky {
+    server: Rc<RefCell<ServerSession>>,
+    reset: bool,
+}
+
+impl io::Read for Flaky {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reset {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        Good(&mut *self.server.borrow_mut()).read(buf)
+    }
+}
+
+impl io::Write for Flaky {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reset {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        Good(&mut *self.server.borrow_mut()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Flaky {}
+impl AsyncWrite for Flaky {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// One direction of an in-memory pipe; reading it empty blocks.
+#[derive(Clone, Default)]
+struct Pipe(Rc<RefCell<VecDeque<u8>>>);
+
+impl io::Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut data = self.0.borrow_mut();
+        if data.is_empty() && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        io::Read::read(&mut *data, buf)
+    }
+}
+
+impl io::Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Pipe {}
+impl AsyncWrite for Pipe {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A transport over `Pipe`s that fails with `ConnectionReset` once broken.
+struct Link {
+    read: Pipe,
+    write: Pipe,
+    broken: Rc<Cell<bool>>,
+    bytes_read: Rc<Cell<usize>>,
+}
+
+impl io::Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.broken.get() {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        let n = self.read.read(buf)?;
+        self.bytes_read.set(self.bytes_read.get() + n);
+        Ok(n)
+    }
+}
+
+impl io::Write for Link {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.broken.get() {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        self.write.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Link {}
+impl AsyncWrite for Link {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn custom_tls_error_kind() {
+    fn kind(err: &TLSError) -> io::ErrorKind {
+        match err {
+            TLSError::WebPKIError(_) => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        }
+    }
+
+    let (mut server, cconfig) = make_server();
+    let connector = TlsConnector::from(Arc::new(cconfig)).tls_error_kind(kind);
+
+    let domain = DNSNameRef::try_from_ascii_str("example.com").unwrap();
+    let err = connector.connect(domain, Good(&mut server))
+        .wait()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn fill_buf_length_prefixed() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+
+    stream.get_mut().0 .0.write_all(b"\x00\x05hello\x00\x05world")?;
+
+    for &expected in &[b"hello", b"world"] {
+        let len = match stream.poll_fill_buf()? {
+            Async::Ready(buf) => {
+                let len = (usize::from(buf[0]) << 8) | usize::from(buf[1]);
+                assert_eq!(&buf[2..][..len], expected);
+                len
+            }
+            Async::NotReady => panic!("plaintext should be ready"),
+        };
+        stream.consume(2 + len);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn abort_handshake() {
+    let (_, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut connect = TlsConnector::from(Arc::new(cconfig)).connect(domain, Recorder(Vec::new()));
+    assert!(connect.poll().unwrap().is_not_ready());
+
+    let Recorder(written) = connect.abort().expect("socket should be recovered");
+    let hello_len = 5 + ((usize::from(written[3]) << 8) | usize::from(written[4]));
+
+    // a ClientHello, then a warning-level close_notify alert
+    assert_eq!(written[0], 0x16);
+    assert_eq!(written[hello_len], 0x15);
+    assert_eq!(&written[hello_len + 3..], &[0, 2, 1, 0]);
+}
+
+#[test]
+fn record_stats() -> io::Result<()> {
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.set_mtu(&Some(1024 + 5));
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let mut stream = connect(&mut server, cconfig);
+
+    let handshake = stream.record_stats();
+    assert!(handshake.read > 0);
+    assert!(handshake.written > 0);
+
+    stream.write_all(&[0x42; 4096])?;
+    stream.flush()?;
+
+    let stats = stream.record_stats();
+    assert_eq!(stats.written - handshake.written, 4);
+    assert_eq!(stats.read, handshake.read);
+
+    Ok(())
+}
+
+#[test]
+fn accept_with_forced_cert() {
+    let (mut sconfig, cconfig) = make_configs();
+    let (certs, key) = load_cert_key();
+    let key = RSASigningKey::new(&key).unwrap();
+    let forced = Arc::new(CertifiedKey::new(certs.clone(), Arc::new(Box::new(key))));
+
+    // a resolver without any names fails every handshake on its own
+    sconfig.cert_resolver = Arc::new(ResolvesServerCertUsingSNI::new());
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+    let cconfig = Arc::new(cconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    let mut client = ClientSession::new(&cconfig, domain);
+    assert!(acceptor.accept(Good(&mut client)).wait().is_err());
+
+    let mut client = ClientSession::new(&cconfig, domain);
+    let stream = acceptor.accept_with_cert(Good(&mut client), forced)
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    drop(stream);
+
+    assert!(!client.is_handshaking());
+    assert_eq!(client.get_peer_certificates(), Some(certs));
+}
+
+#[test]
+fn connect_with_retries() {
+    let (sconfig, cconfig) = make_configs();
+    let server = Rc::new(RefCell::new(ServerSession::new(&Arc::new(sconfig))));
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let policy = RetryPolicy { max_attempts: 3 };
+    let attempts = Cell::new(0);
+
+    let factory = || {
+        attempts.set(attempts.get() + 1);
+        futures::future::ok(Flaky { server: server.clone(), reset: attempts.get() == 1 })
+    };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = connector.connect_with_retries(domain, factory, policy)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert_eq!(attempts.get(), 2);
+    assert!(!stream.get_ref().1.is_handshaking());
+
+    // a certificate error is not retried
+    let (sconfig, _) = make_configs();
+    *server.borrow_mut() = ServerSession::new(&Arc::new(sconfig));
+    attempts.set(1);
+    let domain = DNSNameRef::try_from_ascii_str("example.com").unwrap();
+    let err = connector.connect_with_retries(domain, factory, policy)
+        .wait()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(attempts.get(), 2);
+}
+
+#[test]
+fn inspect_plaintext() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut seen = Vec::new();
+
+    {
+        let stream = connect(&mut server, cconfig);
+        let mut stream = stream.inspect(|dir, buf: &[u8]| seen.push((dir, buf.to_vec())));
+        stream.write_all(b"ping")?;
+        stream.flush()?;
+
+        let server = &mut *stream.get_mut().get_mut().0 .0;
+        server.write_all(b"pong")?;
+        let mut buf = [0; 4];
+        io::Read::read_exact(&mut stream, &mut buf)?;
+        assert_eq!(&buf, b"pong");
+    }
+
+    assert_eq!(seen, vec![
+        (Direction::Write, b"ping".to_vec()),
+        (Direction::Read, b"pong".to_vec()),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn connect_min_version() {
+    let (mut sconfig, cconfig) = make_configs();
+    sconfig.versions = vec![ProtocolVersion::TLSv1_2];
+    let sconfig = Arc::new(sconfig);
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    let mut server = ServerSession::new(&sconfig);
+    let stream = connector.connect_min_version(domain, Good(&mut server), ProtocolVersion::TLSv1_2)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert_eq!(stream.get_ref().1.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    drop(stream);
+
+    let mut server = ServerSession::new(&sconfig);
+    let err = connector.connect_min_version(domain, Good(&mut server), ProtocolVersion::TLSv1_3)
+        .wait()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // the server was sent a close_notify
+    let mut buf = [0; 1];
+    let err = io::Read::read(&mut server, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn read_to_end_until_close() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let payload = (0..40_000).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut stream = connect(&mut server, cconfig);
+
+    {
+        let server = &mut *stream.get_mut().0 .0;
+        server.write_all(&payload)?;
+        server.send_close_notify();
+    }
+    let err = stream.read_to_end_until_close(payload.len() - 1).wait().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+
+    {
+        let server = &mut *stream.get_mut().0 .0;
+        server.write_all(&payload)?;
+        server.send_close_notify();
+    }
+    let buf = stream.read_to_end_until_close(payload.len()).wait()?;
+    assert_eq!(buf, payload);
+    Ok(())
+}
+
+#[cfg(feature = "idle-timeout")]
+#[test]
+fn idle_timeout_closes() {
+    use std::time::{ Duration, Instant };
+    use tokio_rustls::idle::IdleAction;
+
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    let timeout = Duration::from_millis(50);
+    stream.set_idle_timeout(timeout, IdleAction::Close);
+    let start = Instant::now();
+    let mut buf = [0; 16];
+    let err = tokio::runtime::current_thread::Runtime::new()
+        .unwrap()
+        .block_on(futures::future::poll_fn(|| stream.poll_read(&mut buf)))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= timeout);
+
+    // the server got a close_notify
+    let server = &mut *stream.get_mut().0.good.0;
+    let err = io::Read::read(server, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn verify_pin() {
+    const PIN: [u8; 32] = [
+        0xfa, 0xc4, 0x10, 0x99, 0x12, 0x3f, 0xc4, 0x5c, 0x25, 0x84, 0x92, 0x25,
+        0x7e, 0xa6, 0xc5, 0x4f, 0xcc, 0x17, 0xc7, 0xa1, 0xa2, 0xe5, 0xd3, 0x7b,
+        0x7c, 0x20, 0x0e, 0xe3, 0xd7, 0x42, 0xf0, 0x9d
+    ];
+
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+
+    assert!(stream.verify_pin(&PIN).is_ok());
+    let err = stream.verify_pin(&[0; 32]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn split_transports() -> io::Result<()> {
+    fn ready<T>(ret: io::Result<T>) -> io::Result<Option<T>> {
+        match ret {
+            Ok(t) => Ok(Some(t)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let (to_server, to_client) = (Pipe::default(), Pipe::default());
+    let mut client = client::TlsStream::from_split(
+        to_client.clone(),
+        to_server.clone(),
+        ClientSession::new(&Arc::new(cconfig), domain),
+    );
+    let mut server = server::TlsStream::from_split(
+        to_server,
+        to_client,
+        ServerSession::new(&Arc::new(sconfig)),
+    );
+
+    // queued until the handshake is done
+    client.write_all(b"ping")?;
+
+    let mut buf = [0; 4];
+    for _ in 0..8 {
+        if let Some(4) = ready(io::Read::read(&mut server, &mut buf))? {
+            break;
+        }
+        ready(client.flush())?;
+        ready(io::Read::read(&mut client, &mut [0; 1]))?;
+    }
+
+    assert_eq!(&buf, b"ping");
+    assert!(!client.get_ref().1.is_handshaking());
+    assert!(!server.get_ref().1.is_handshaking());
+    Ok(())
+}
+
+#[test]
+fn peer_signature_scheme() {
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+
+    // an RSA_PSS_SHA256 signature once rustls reports it
+    assert!(!stream.get_ref().1.is_handshaking());
+    assert_eq!(stream.peer_signature_scheme(), None);
+}
+
+#[test]
+fn on_handshake_error() {
+    use std::sync::Mutex;
+
+    let (mut sconfig, mut cconfig) = make_configs();
+    sconfig.versions = vec![ProtocolVersion::TLSv1_3];
+    cconfig.versions = vec![ProtocolVersion::TLSv1_2];
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig))
+        .on_handshake_error({
+            let failures = failures.clone();
+            move |failure| failures.lock().unwrap().push((
+                failure.tls_error.cloned(),
+                failure.sni_hostname.map(str::to_owned),
+            ))
+        });
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    assert!(acceptor.accept(Good(&mut client)).wait().is_err());
+
+    let failures = failures.lock().unwrap();
+    assert_eq!(failures.len(), 1);
+    match &failures[0] {
+        (Some(TLSError::PeerIncompatibleError(_)), _) => (),
+        failure => panic!("unexpected failure: {:?}", failure),
+    }
+}
+
+#[test]
+fn transcript_hash() {
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let stream = TlsAcceptor::from(Arc::new(sconfig))
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+
+    let hash = stream.transcript_hash().unwrap();
+    drop(stream);
+    let mut binding = vec![0; 32];
+    client.export_keying_material(&mut binding, b"EXPORTER-Channel-Binding", None).unwrap();
+    assert_eq!(hash, binding);
+
+    let (mut server, cconfig) = make_server();
+    let other = connect(&mut server, cconfig).transcript_hash().unwrap();
+    assert_ne!(hash, other);
+}
+
+#[test]
+fn accept_with_prefix() -> io::Result<()> {
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+
+    // sniff the ClientHello record off the wire
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello)?;
+    assert_eq!(hello[0], 0x16);
+    let len = 5 + ((usize::from(hello[3]) << 8) | usize::from(hello[4]));
+    let prefix = hello[..len].to_vec();
+
+    let stream = TlsAcceptor::from(Arc::new(sconfig))
+        .accept_with_prefix(Good(&mut client), prefix)
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert!(!stream.get_ref().1.is_handshaking());
+    drop(stream);
+    assert!(!client.is_handshaking());
+    Ok(())
+}
+
+#[test]
+fn into_inner_after_close() -> io::Result<()> {
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let (to_server, to_client) = (Pipe::default(), Pipe::default());
+    let mut client = client::TlsStream::from_split(
+        to_client.clone(),
+        to_server.clone(),
+        ClientSession::new(&Arc::new(cconfig), domain),
+    );
+    let mut server = server::TlsStream::from_split(
+        to_server,
+        to_client,
+        ServerSession::new(&Arc::new(sconfig)),
+    );
+
+    let mut buf = [0; 5];
+    while server.get_ref().1.is_handshaking() || client.get_ref().1.is_handshaking() {
+        let _ = io::Read::read(&mut server, &mut []);
+        let _ = io::Read::read(&mut client, &mut []);
+    }
+    client.write_all(b"hello")?;
+    io::Read::read_exact(&mut server, &mut buf)?;
+
+    assert!(client.shutdown()?.is_ready());
+    assert_eq!(io::Read::read(&mut server, &mut buf)?, 0);
+    server.flush()?;
+    assert_eq!(io::Read::read(&mut client, &mut buf)?, 0);
+
+    let mut client = client.into_inner_after_close()?;
+    let mut server = server.into_inner_after_close()?;
+    client.write_all(b"plain")?;
+    io::Read::read_exact(&mut server, &mut buf)?;
+    assert_eq!(&buf, b"plain");
+    Ok(())
+}
+
+#[test]
+fn into_inner_after_close_unclean() {
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+    let err = stream.into_inner_after_close().map(drop).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn connection_context() {
+    #[derive(Debug, PartialEq)]
+    struct RequestId(u64);
+
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    assert_eq!(stream.get_context::<RequestId>(), None);
+
+    stream.set_context(RequestId(42));
+    assert_eq!(stream.get_context::<RequestId>(), Some(&RequestId(42)));
+    assert_eq!(stream.get_context::<u64>(), None);
+}
+
+#[test]
+fn take_buffered_tls() -> io::Result<()> {
+    fn deliver(from: &Pipe, to: &Pipe, max: usize) -> Vec<u8> {
+        let mut from = from.0.borrow_mut();
+        let len = cmp::min(max, from.len());
+        let bytes = from.drain(..len).collect::<Vec<_>>();
+        to.0.borrow_mut().extend(&bytes);
+        bytes
+    }
+
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let (to_server, wire, to_client) = (Pipe::default(), Pipe::default(), Pipe::default());
+    let mut client = client::TlsStream::from_split(
+        to_client.clone(),
+        to_server.clone(),
+        ClientSession::new(&Arc::new(cconfig), domain),
+    );
+    let mut server = server::TlsStream::from_split(
+        to_server,
+        wire.clone(),
+        ServerSession::new(&Arc::new(sconfig)),
+    );
+
+    while server.get_ref().1.is_handshaking() || client.get_ref().1.is_handshaking() {
+        let _ = io::Read::read(&mut server, &mut []);
+        deliver(&wire, &to_client, usize::MAX);
+        let _ = io::Read::read(&mut client, &mut []);
+    }
+    assert!(client.take_buffered_tls().is_empty());
+
+    // only part of the record makes it before the handoff
+    server.write_all(b"hello")?;
+    let sent = deliver(&wire, &to_client, 10);
+    let mut buf = [0; 5];
+    let err = io::Read::read(&mut client, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let taken = client.take_buffered_tls();
+    assert_eq!(taken, sent);
+
+    let (io, session) = client.into_inner();
+    let (read, write) = io.into_inner();
+    let mut client = client::TlsStream::from_split(Prefixed::new(taken, read), write, session);
+    deliver(&wire, &to_client, usize::MAX);
+    io::Read::read_exact(&mut client, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[test]
+fn handshake_progress() {
+    let (_, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let to_server = Pipe::default();
+
+    // the peer never answers the ClientHello
+    let mut connect = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Duplex::new(Pipe::default(), to_server.clone()));
+    assert_eq!(connect.progress(), HandshakeProgress::NotStarted);
+    assert!(connect.poll().unwrap().is_not_ready());
+    assert!(!to_server.0.borrow().is_empty());
+    assert_eq!(connect.progress(), HandshakeProgress::HelloSent);
+    assert_eq!(connect.progress().to_string(), "sent hello, awaiting the peer's");
+}
+
+#[test]
+fn blocked_handshake_does_not_spin() {
+    use futures::executor::{ self, Notify };
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    struct Wakeups(AtomicUsize);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Stalled(Rc<Cell<usize>>);
+
+    impl io::Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Stalled {}
+
+    let (_, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let reads = Rc::new(Cell::new(0));
+    let connect = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Duplex::new(Stalled(reads.clone()), Pipe::default()));
+
+    let wakeups = Arc::new(Wakeups(AtomicUsize::new(0)));
+    let mut task = executor::spawn(connect);
+    for polls in 1..4 {
+        assert!(task.poll_future_notify(&wakeups, 0).unwrap().is_not_ready());
+        assert_eq!(reads.get(), polls);
+    }
+    assert_eq!(wakeups.0.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn cipher_strength() {
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.ciphersuites.retain(|suite| suite.suite == CipherSuite::TLS13_AES_128_GCM_SHA256);
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let stream = connect(&mut server, cconfig);
+    assert_eq!(stream.cipher_strength(), Some(CipherStrength::Modern));
+
+    assert_eq!(CipherStrength::of(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256), CipherStrength::Compatible);
+    assert_eq!(CipherStrength::of(CipherSuite::TLS_RSA_WITH_AES_128_CBC_SHA), CipherStrength::Weak);
+}
+
+#[test]
+fn reverify_peer() {
+    let (sconfig, cconfig) = make_configs();
+    let roots = cconfig.root_store.clone();
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let stream = connect(&mut server, cconfig);
+    assert!(stream.reverify_peer(&roots).is_ok());
+
+    // a store without the issuing CA, as after a rotation
+    let err = stream.reverify_peer(&rustls::RootCertStore::empty()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn fallible_accept() -> io::Result<()> {
+    let (sconfig, _) = make_configs();
+    let (from_client, to_client) = (Pipe::default(), Pipe::default());
+    // long enough to fill the record its first bytes would announce
+    let request = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(9000));
+    from_client.0.borrow_mut().extend(request.as_bytes());
+
+    let (err, mut io) = TlsAcceptor::from(Arc::new(sconfig))
+        .accept(Duplex::new(from_client, to_client.clone()))
+        .fallible()
+        .poll()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let reply = b"HTTP/1.1 426 Upgrade Required\r\n\r\n";
+    io.write_all(reply)?;
+    assert!(to_client.0.borrow().iter().cloned().collect::<Vec<_>>().ends_with(reply));
+    Ok(())
+}
+
+#[test]
+fn boxed_io() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let io: BoxedIo = Box::new(Good(&mut server));
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+
+    stream.write_all(b"ping")?;
+    stream.flush()?;
+    assert!(!stream.get_ref().1.is_handshaking());
+    drop(stream);
+
+    let mut buf = [0; 4];
+    io::Read::read_exact(&mut server, &mut buf)?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[test]
+fn peer_cert_validity() {
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+
+    // tests/end.cert: Oct 14 04:20:51 2026 GMT to Oct 11 04:20:51 2036 GMT
+    let (not_before, not_after) = stream.peer_cert_validity().unwrap();
+    assert_eq!(not_before, UNIX_EPOCH + Duration::from_secs(1_791_951_651));
+    assert_eq!(not_after, UNIX_EPOCH + Duration::from_secs(2_107_311_651));
+}
+
+#[test]
+fn connection_id() {
+    let (sconfig, cconfig) = make_configs();
+    let (mut server1, mut server2) = (ServerSession::new(&Arc::new(sconfig.clone())), ServerSession::new(&Arc::new(sconfig)));
+    let first = connect(&mut server1, cconfig.clone());
+    let second = connect(&mut server2, cconfig);
+
+    assert_eq!(first.connection_id(), first.connection_id());
+    assert!(second.connection_id() > first.connection_id());
+}
+
+#[test]
+fn offered_versions() {
+    use rustls::ProtocolVersion;
+
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.versions = vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+
+    // the server reads the ClientHello but cannot answer it yet
+    let mut accept = TlsAcceptor::from(Arc::new(sconfig))
+        .record_hellos(true)
+        .accept(Gated { good: Good(&mut client), read: true, write: false });
+    assert_eq!(accept.offered_versions(), None);
+    assert!(accept.poll().unwrap().is_not_ready());
+    assert_eq!(accept.offered_versions(), Some(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]));
+}
+
+#[test]
+fn accept_limiter() {
+    use futures::executor::{ self, Notify };
+    use std::sync::Mutex;
+    use tokio_rustls::limit::AcceptLimiter;
+
+    struct Wakeups(Mutex<Vec<usize>>);
+
+    impl Notify for Wakeups {
+        fn notify(&self, id: usize) {
+            self.0.lock().unwrap().push(id);
+        }
+    }
+
+    struct Stalled(Rc<Cell<usize>>);
+
+    impl io::Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            self.0.set(self.0.get() + 1);
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Stalled {}
+
+    let (sconfig, _) = make_configs();
+    let limiter = AcceptLimiter::new(TlsAcceptor::from(Arc::new(sconfig)), 2);
+    let reads = (0..3).map(|_| Rc::new(Cell::new(0))).collect::<Vec<_>>();
+    let mut tasks = reads.iter()
+        .map(|reads| executor::spawn(limiter.accept(Duplex::new(Stalled(reads.clone()), Pipe::default()))))
+        .collect::<Vec<_>>();
+
+    let wakeups = Arc::new(Wakeups(Mutex::new(Vec::new())));
+    for (id, task) in tasks.iter_mut().enumerate() {
+        assert!(task.poll_future_notify(&wakeups, id).unwrap().is_not_ready());
+    }
+    assert_eq!(limiter.in_flight(), 2);
+    assert_eq!(reads.iter().map(|reads| reads.get()).collect::<Vec<_>>(), [1, 1, 0]);
+
+    // the first handshake going away lets the third one start
+    drop(tasks.remove(0));
+    assert_eq!(*wakeups.0.lock().unwrap(), [2]);
+    assert!(tasks[1].poll_future_notify(&wakeups, 2).unwrap().is_not_ready());
+    assert_eq!(reads[2].get(), 1);
+    assert_eq!(limiter.in_flight(), 2);
+}
+
+#[test]
+fn last_tls_error() {
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("example.com").unwrap();
+    let mut connect = TlsConnector::from(Arc::new(cconfig)).connect(domain, Good(&mut server));
+    assert_eq!(connect.last_tls_error(), None);
+
+    let err = connect.poll().map(drop).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(connect.last_tls_error(), Some(&TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+}
+
+#[test]
+fn over_framed() -> io::Result<()> {
+    use bytes::Bytes;
+    use futures::{ AsyncSink, Sink, StartSend, Stream };
+
+    /// Frames to and from a server session, logging the ones it receives.
+    struct Frames<'a>(&'a mut ServerSession, Vec<Bytes>);
+
+    impl<'a> Stream for Frames<'a> {
+        type Item = Bytes;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+            let mut frame = Vec::new();
+            self.0.write_tls(&mut frame)?;
+            if frame.is_empty() {
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(Some(frame.into())))
+            }
+        }
+    }
+
+    impl<'a> Sink for Frames<'a> {
+        type SinkItem = Bytes;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, frame: Bytes) -> StartSend<Bytes, io::Error> {
+            let mut rd = &frame[..];
+            while !rd.is_empty() {
+                self.0.read_tls(&mut rd)?;
+            }
+            self.0.process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.1.push(frame);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let session = ClientSession::new(&Arc::new(cconfig), domain);
+    let mut stream = client::TlsStream::over_framed(Frames(&mut server, Vec::new()), session);
+
+    stream.write_all(b"hello")?;
+    stream.flush()?;
+    stream.get_mut().0.get_mut().0.write_all(b"world")?;
+    let mut buf = [0; 5];
+    io::Read::read_exact(&mut stream, &mut buf)?;
+    assert_eq!(&buf, b"world");
+
+    // every frame carried whole records
+    for frame in &stream.get_ref().0.get_ref().1 {
+        let mut records = &frame[..];
+        while !records.is_empty() {
+            let len = 5 + ((usize::from(records[3]) << 8) | usize::from(records[4]));
+            assert!(len <= records.len());
+            records = &records[len..];
+        }
+    }
+
+    let Frames(server, _) = stream.into_inner().0.into_inner();
+    let mut buf = [0; 5];
+    io::Read::read_exact(server, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[test]
+fn connect_expect_alpn() {
+    let connect = |server_protocols: &[&[u8]], expected: &[u8]| {
+        let (mut sconfig, mut cconfig) = make_configs();
+        sconfig.set_protocols(&server_protocols.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+        cconfig.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        let mut server = ServerSession::new(&Arc::new(sconfig));
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let ret = TlsConnector::from(Arc::new(cconfig))
+            .connect_expect_alpn(domain, Good(&mut server), expected)
+            .wait()
+            .map(|stream| stream.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec));
+        ret
+    };
+
+    assert_eq!(connect(&[b"h2"], b"h2").unwrap(), Some(b"h2".to_vec()));
+
+    // the server picked the other protocol, or none at all
+    let err = connect(&[b"http/1.1"], b"h2").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let err = connect(&[], b"h2").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn read_vectored() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    stream.get_mut().0 .0.write_all(b"0123456789abcdefghijABCDEFGHIJ+")?;
+
+    let (mut a, mut b, mut c) = ([0; 10], [0; 10], [0; 10]);
+    let n = io::Read::read_vectored(&mut stream, &mut [
+        io::IoSliceMut::new(&mut a),
+        io::IoSliceMut::new(&mut []),
+        io::IoSliceMut::new(&mut b),
+        io::IoSliceMut::new(&mut c),
+    ])?;
+    assert_eq!(n, 30);
+    assert_eq!((&a, &b, &c), (b"0123456789", b"abcdefghij", b"ABCDEFGHIJ"));
+
+    // a short read ends the call
+    let (mut a, mut b) = ([0; 10], [0; 10]);
+    let n = io::Read::read_vectored(&mut stream, &mut [io::IoSliceMut::new(&mut a), io::IoSliceMut::new(&mut b)])?;
+    assert_eq!(&a[..n], b"+");
+    Ok(())
+}
+
+#[test]
+fn transfer_limit() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    stream.set_transfer_limit(10);
+
+    assert_eq!(stream.write(b"hello ")?, 6);
+    stream.get_mut().0 .0.write_all(b"world!!!")?;
+    let mut buf = [0; 16];
+    let n = io::Read::read(&mut stream, &mut buf)?;
+    assert_eq!(&buf[..n], b"worl");
+
+    let err = stream.write(b"more").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let err = io::Read::read(&mut stream, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+
+    // the server got what fit, then a close_notify
+    let server = &mut *stream.get_mut().0 .0;
+    let mut buf = [0; 6];
+    io::Read::read_exact(server, &mut buf)?;
+    assert_eq!(&buf, b"hello ");
+    let err = io::Read::read(server, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    Ok(())
+}
+
+#[cfg(feature = "incoming")]
+#[test]
+fn acceptor_stream() {
+    use futures::{ stream, Stream };
+    use tokio_rustls::incoming::acceptor_stream;
+
+    let (sconfig, cconfig) = make_configs();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let cconfig = Arc::new(cconfig);
+    let mut untrusting = (*cconfig).clone();
+    untrusting.root_store = rustls::RootCertStore::empty();
+
+    let mut clients = [
+        ClientSession::new(&cconfig, domain),
+        ClientSession::new(&Arc::new(untrusting), domain),
+        ClientSession::new(&cconfig, domain),
+    ];
+    let incoming = stream::iter_ok(clients.iter_mut().map(|client| Good(client)));
+    let accepted = acceptor_stream(incoming, TlsAcceptor::from(Arc::new(sconfig)))
+        .collect()
+        .wait()
+        .unwrap();
+
+    // the client that gave up on its handshake did not stop the others
+    assert_eq!(accepted.len(), 3);
+    assert_eq!(accepted.iter().filter(|ret| ret.is_ok()).count(), 2);
+    for stream in accepted.iter().flatten() {
+        assert!(!stream.get_ref().1.is_handshaking());
+    }
+}
+
+#[test]
+fn peer_auth_kind() {
+    let accept = |sconfig: rustls::ServerConfig, cconfig: ClientConfig| -> PeerAuthKind {
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+        let stream = TlsAcceptor::from(Arc::new(sconfig))
+            .accept(Good(&mut client))
+            .wait()
+            .unwrap_or_else(|err| panic!("accept failed: {}", err));
+        stream.peer_auth_kind()
+    };
+
+    let (sconfig, cconfig) = make_configs();
+    assert_eq!(accept(sconfig, cconfig), PeerAuthKind::Anonymous);
+
+    // the test CA issued the client's certificate too
+    let (_, mut cconfig) = make_configs();
+    let mut sconfig = rustls::ServerConfig::new(rustls::AllowAnyAuthenticatedClient::new(cconfig.root_store.clone()));
+    let (cert, key) = load_cert_key();
+    sconfig.set_single_cert(cert.clone(), key.clone()).unwrap();
+    cconfig.set_single_client_cert(cert, key);
+    assert_eq!(accept(sconfig, cconfig), PeerAuthKind::Certificate);
+}
+
+#[test]
+fn accept_without_tickets() {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    // whether the client came away with a session to resume
+    let resumable = |without_tickets: bool| -> bool {
+        let (sconfig, cconfig) = make_configs();
+        let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+        let cconfig = Arc::new(cconfig);
+        let mut client = ClientSession::new(&cconfig, domain);
+        let accept = if without_tickets {
+            acceptor.accept_without_tickets(Good(&mut client))
+        } else {
+            acceptor.accept(Good(&mut client))
+        };
+        accept.wait().unwrap_or_else(|err| panic!("accept failed: {}", err));
+
+        // a client resuming gets no certificate from the server
+        let mut client = ClientSession::new(&cconfig, domain);
+        acceptor.accept(Good(&mut client)).wait().unwrap_or_else(|err| panic!("accept failed: {}", err));
+        client.get_peer_certificates().is_none()
+    };
+
+    assert!(resumable(false));
+    // so its next connect is a full handshake
+    assert!(!resumable(true));
+}
+
+#[test]
+fn poll_writable() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let io = Gated { good: Good(&mut server), read: true, write: true };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()?;
+    assert!(stream.poll_writable()?.is_ready());
+
+    // the socket is full, the record waits in the session
+    stream.get_mut().0.write = false;
+    assert_eq!(stream.write(b"hello")?, 5);
+    assert!(stream.poll_writable()?.is_not_ready());
+    assert!(stream.poll_writable()?.is_not_ready());
+
+    stream.get_mut().0.write = true;
+    assert!(stream.poll_writable()?.is_ready());
+
+    let server = &mut *stream.get_mut().0.good.0;
+    let mut buf = [0; 5];
+    server.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"hello");
+    Ok(())
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn connect_with_time() {
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    // `tests/end.cert` is valid from 1791951651 to 2107311651
+    let connect = |secs: u64| -> Result<(), TLSError> {
+        let (mut server, cconfig) = make_server();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(secs);
+        TlsConnector::from(Arc::new(cconfig))
+            .connect_with_time(domain, Good(&mut server), now)
+            .wait()
+            .map(drop)
+            .map_err(|err| err.into_inner().unwrap().downcast::<TLSError>().map(|err| *err).unwrap())
+    };
+
+    assert_eq!(connect(1791951651), Ok(()));
+    assert_eq!(connect(2107311651), Ok(()));
+    assert_eq!(connect(2107311652), Err(TLSError::WebPKIError(webpki::Error::CertExpired)));
+    assert_eq!(connect(1791951650), Err(TLSError::WebPKIError(webpki::Error::CertNotValidYet)));
+
+    // the name is still checked
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("example.com").unwrap();
+    let now = UNIX_EPOCH + Duration::from_secs(2000000000);
+    let err = TlsConnector::from(Arc::new(cconfig))
+        .connect_with_time(domain, Good(&mut server), now)
+        .wait()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn name_match_kind() {
+    use std::io::{ BufReader, Cursor };
+    use rustls::{ NoClientAuth, ServerConfig };
+    use rustls::internal::pemfile::{ certs, rsa_private_keys };
+
+    const CERT: &str = include_str!("../tests/wildcard.cert");
+    const CHAIN: &str = include_str!("../tests/wildcard.chain");
+    const RSA: &str = include_str!("../tests/wildcard.rsa");
+
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = TlsConnector::from(Arc::new(cconfig))
+        .record_name_match(true)
+        .connect(domain, Good(&mut server))
+        .wait().unwrap_or_else(|err| panic!("handshake failed: {}", err));
+    assert_eq!(stream.name_match_kind(), Some(NameMatchKind::Exact));
+
+    // not kept by default
+    let (mut server, cconfig) = make_server();
+    assert_eq!(connect(&mut server, cconfig).name_match_kind(), None);
+
+    // `*.testserver.com`, signed by a CA of its own
+    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    let mut sconfig = ServerConfig::new(NoClientAuth::new());
+    sconfig.set_single_cert(cert, keys.pop().unwrap()).unwrap();
+    let mut cconfig = ClientConfig::new();
+    cconfig.root_store.add_pem_file(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let domain = DNSNameRef::try_from_ascii_str("api.testserver.com").unwrap();
+    let stream = TlsConnector::from(Arc::new(cconfig))
+        .record_name_match(true)
+        .connect(domain, Good(&mut server))
+        .wait().unwrap_or_else(|err| panic!("handshake failed: {}", err));
+    assert_eq!(stream.name_match_kind(), Some(NameMatchKind::Wildcard));
+}
+
+#[test]
+fn proxy_copy_bidirectional() -> io::Result<()> {
+    use tokio_rustls::proxy::copy_bidirectional;
+
+    type Pair = (client::TlsStream<Duplex<Pipe, Pipe>>, server::TlsStream<Duplex<Pipe, Pipe>>);
+
+    fn pair() -> io::Result<Pair> {
+        let (sconfig, cconfig) = make_configs();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let (to_server, to_client) = (Pipe::default(), Pipe::default());
+        let mut connect = TlsConnector::from(Arc::new(cconfig))
+            .connect(domain, Duplex::new(to_client.clone(), to_server.clone()));
+        let mut accept = TlsAcceptor::from(Arc::new(sconfig))
+            .accept(Duplex::new(to_server, to_client));
+
+        let (mut client, mut server) = (None, None);
+        while client.is_none() || server.is_none() {
+            if client.is_none() {
+                if let Async::Ready(stream) = connect.poll()? {
+                    client = Some(stream);
+                }
+            }
+            if server.is_none() {
+                if let Async::Ready(stream) = accept.poll()? {
+                    server = Some(stream);
+                }
+            }
+        }
+        Ok((client.unwrap(), server.unwrap()))
+    }
+
+    let (mut client, inbound) = pair()?;
+    let (outbound, mut server) = pair()?;
+    let mut copy = copy_bidirectional(inbound, outbound);
+
+    client.write_all(b"ping")?;
+    client.flush()?;
+    assert!(copy.poll()?.is_not_ready());
+    let mut buf = [0; 4];
+    io::Read::read_exact(&mut server, &mut buf)?;
+    assert_eq!(&buf, b"ping");
+
+    // the server answers and closes, which reaches the client
+    server.write_all(b"pong!")?;
+    assert!(server.shutdown()?.is_ready());
+    assert!(copy.poll()?.is_not_ready());
+    let mut buf = [0; 5];
+    io::Read::read_exact(&mut client, &mut buf)?;
+    assert_eq!(&buf, b"pong!");
+    assert_eq!(io::Read::read(&mut client, &mut [0; 1])?, 0);
+    assert!(client.shutdown()?.is_ready());
+
+    // and the client's answer to it ends the copy the other way
+    assert_eq!(copy.poll()?, Async::Ready((4, 5)));
+    assert_eq!(io::Read::read(&mut server, &mut [0; 1])?, 0);
+
+    // nothing is written past a close_notify
+    let err = client.write(b"x").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    let (_, mut outbound) = copy.into_inner();
+    let err = outbound.write(b"x").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    Ok(())
+}
+
+#[test]
+fn completion_signal() {
+    let (sconfig, cconfig) = make_configs();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+    let sconfig = Arc::new(sconfig);
+
+    let mut server = ServerSession::new(&sconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut connect = connector.connect(domain, Good(&mut server));
+    let signal = connect.completion_signal();
+    let other = connect.completion_signal();
+    let stream = connect.wait();
+    assert!(stream.is_ok());
+    assert!(signal.wait().unwrap().is_ok());
+    assert!(other.wait().unwrap().is_ok());
+
+    let mut server = ServerSession::new(&sconfig);
+    let domain = DNSNameRef::try_from_ascii_str("example.com").unwrap();
+    let mut connect = connector.connect(domain, Good(&mut server));
+    let signal = connect.completion_signal();
+    let err = connect.wait().map(drop).unwrap_err();
+    let signalled = signal.wait().unwrap().unwrap_err();
+    assert_eq!(signalled.kind(), err.kind());
+    assert_eq!(signalled.to_string(), err.to_string());
+
+    // dropped before it resolved
+    let mut server = ServerSession::new(&sconfig);
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let signal = connector.connect(domain, Good(&mut server)).completion_signal();
+    assert!(signal.wait().is_err());
+}
+
+#[test]
+fn connect_with_extra_roots() {
+    use std::io::{ BufReader, Cursor };
+    use rustls::{ NoClientAuth, RootCertStore, ServerConfig };
+    use rustls::internal::pemfile::{ certs, rsa_private_keys };
+
+    const CERT: &str = include_str!("../tests/wildcard.cert");
+    const CHAIN: &str = include_str!("../tests/wildcard.chain");
+    const RSA: &str = include_str!("../tests/wildcard.rsa");
+
+    // `*.testserver.com`, signed by a CA the config does not trust
+    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    let mut sconfig = ServerConfig::new(NoClientAuth::new());
+    sconfig.set_single_cert(cert, keys.pop().unwrap()).unwrap();
+    let sconfig = Arc::new(sconfig);
+    let mut extra = RootCertStore::empty();
+    extra.add_pem_file(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+
+    let (_, cconfig) = make_configs();
+    let cconfig = Arc::new(cconfig);
+    let connector = TlsConnector::from(cconfig.clone());
+    let domain = DNSNameRef::try_from_ascii_str("api.testserver.com").unwrap();
+
+    let mut server = ServerSession::new(&sconfig);
+    let err = connector.connect(domain, Good(&mut server))
+        .wait()
+        .map(drop)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let mut server = ServerSession::new(&sconfig);
+    connector.connect_with_extra_roots(domain, Good(&mut server), &extra)
+        .wait()
+        .unwrap_or_else(|err| panic!("handshake failed: {}", err));
+
+    // the shared config is left alone
+    assert_eq!(cconfig.root_store.len(), make_configs().1.root_store.len());
+}
+
+#[test]
+fn retry_interrupted() -> io::Result<()> {
+    /// Fails every other read and write with `Interrupted`.
+    struct Eintr<'a> {
+        good: Good<'a>,
+        read: bool,
+        write: bool,
+        interrupted: usize,
+    }
+
+    impl<'a> io::Read for Eintr<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read = !self.read;
+            if self.read {
+                self.interrupted += 1;
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.good.read(buf)
+        }
+    }
+
+    impl<'a> io::Write for Eintr<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write = !self.write;
+            if self.write {
+                self.interrupted += 1;
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.good.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> AsyncRead for Eintr<'a> {}
+    impl<'a> AsyncWrite for Eintr<'a> {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let (mut server, cconfig) = make_server();
+    let io = Eintr { good: Good(&mut server), read: false, write: false, interrupted: 0 };
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, io)
+        .wait()?;
+
+    stream.write_all(b"ping")?;
+    stream.flush()?;
+    stream.get_mut().0.good.0.write_all(b"pong")?;
+    let mut buf = [0; 4];
+    io::Read::read_exact(&mut stream, &mut buf)?;
+    assert_eq!(&buf, b"pong");
+    assert!(stream.get_ref().0.interrupted >= 4);
+
+    let server = &mut *stream.get_mut().0.good.0;
+    io::Read::read_exact(server, &mut buf)?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[test]
+fn sent_client_certificate() {
+    use rustls::{ AllowAnyAnonymousOrAuthenticatedClient, ServerConfig };
+
+    let sent = |sconfig: ServerConfig, cconfig: ClientConfig| -> Option<bool> {
+        let mut server = ServerSession::new(&Arc::new(sconfig));
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(cconfig))
+            .record_client_certificate(true)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        stream.sent_client_certificate()
+    };
+    // the test CA issued the client's certificate too
+    let optional_auth = || {
+        let (_, cconfig) = make_configs();
+        let mut sconfig = ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(cconfig.root_store));
+        let (cert, key) = load_cert_key();
+        sconfig.set_single_cert(cert, key).unwrap();
+        sconfig
+    };
+    let with_cert = || {
+        let (_, mut cconfig) = make_configs();
+        let (cert, key) = load_cert_key();
+        cconfig.set_single_client_cert(cert, key);
+        cconfig
+    };
+
+    assert_eq!(sent(optional_auth(), with_cert()), Some(true));
+    // asked for one, but has none
+    assert_eq!(sent(optional_auth(), make_configs().1), Some(false));
+    // has one, but was not asked
+    assert_eq!(sent(make_configs().0, with_cert()), Some(false));
+
+    // not recorded unless asked to
+    let mut server = ServerSession::new(&Arc::new(optional_auth()));
+    let stream = connect(&mut server, with_cert());
+    assert_eq!(stream.sent_client_certificate(), None);
+}
+
+#[test]
+fn handshake_error_hint() {
+    use rustls::internal::msgs::enums::AlertDescription;
+    use tokio_rustls::HandshakeErrorClass::*;
+
+    let hint = |err: TLSError| tokio_rustls::handshake_error_hint(&io::Error::new(io::ErrorKind::InvalidData, err));
+    assert_eq!(hint(TLSError::NoCertificatesPresented), CertificateRequired);
+    assert_eq!(hint(TLSError::AlertReceived(AlertDescription::CertificateRequired)), CertificateRequired);
+    assert_eq!(hint(TLSError::AlertReceived(AlertDescription::UnknownCA)), UnknownCa);
+    assert_eq!(hint(TLSError::WebPKIError(webpki::Error::CertExpired)), BadCertificate);
+    assert_eq!(hint(TLSError::PeerIncompatibleError("no ciphersuites in common".into())), ProtocolMismatch);
+    assert_eq!(hint(TLSError::CorruptMessage), ProtocolMismatch);
+    assert_eq!(hint(TLSError::DecryptError), Other);
+    assert_eq!(tokio_rustls::handshake_error_hint(&io::ErrorKind::UnexpectedEof.into()), Transport);
+    assert_eq!(tokio_rustls::handshake_error_hint(&io::Error::other("custom")), Transport);
+
+    // a real handshake against a client trusting nothing
+    let (sconfig, mut cconfig) = make_configs();
+    cconfig.root_store = rustls::RootCertStore::empty();
+    let mut server = ServerSession::new(&Arc::new(sconfig));
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let err = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Good(&mut server))
+        .wait()
+        .err()
+        .expect("handshake succeeded");
+    assert_eq!(tokio_rustls::handshake_error_hint(&err), UnknownCa);
+}
+
+#[test]
+fn poll_drain_to_close() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+
+    {
+        let server = &mut *stream.get_mut().0 .0;
+        server.write_all(&[7; 40_000])?;
+        server.send_close_notify();
+    }
+    assert!(stream.poll_drain_to_close()?.is_ready());
+    assert_eq!(io::Read::read(&mut stream, &mut [0; 1])?, 0);
+
+    // the IO ends without a close_notify
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    stream.get_mut().0 .0.write_all(b"trailing")?;
+    let err = stream.poll_drain_to_close().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    Ok(())
+}
+
+#[test]
+fn require_clean_close() -> io::Result<()> {
+    fn read_to_end(require: bool, close_notify: bool) -> io::Result<Vec<u8>> {
+        let (mut server, cconfig) = make_server();
+        let mut stream = connect(&mut server, cconfig);
+        stream.set_require_clean_close(require);
+
+        {
+            let server = &mut *stream.get_mut().0 .0;
+            server.write_all(b"body")?;
+            if close_notify {
+                server.send_close_notify();
+            }
+        }
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut stream, &mut buf)?;
+        Ok(buf)
+    }
+
+    assert_eq!(read_to_end(false, true)?, b"body");
+    assert_eq!(read_to_end(false, false)?, b"body");
+    assert_eq!(read_to_end(true, true)?, b"body");
+    assert_eq!(read_to_end(true, false).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    Ok(())
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn bundled_localhost_pair() -> io::Result<()> {
+    use tokio_rustls::test_util::{ self, DOMAIN };
+
+    let (acceptor, connector) = test_util::bundled_localhost_pair();
+    let (to_server, to_client) = (Pipe::default(), Pipe::default());
+    let domain = DNSNameRef::try_from_ascii_str(DOMAIN).unwrap();
+    let mut connect = connector.connect(domain, Duplex::new(to_client.clone(), to_server.clone()));
+    let mut accept = acceptor.accept(Duplex::new(to_server, to_client));
+
+    let (mut client, mut server) = (None, None);
+    while client.is_none() || server.is_none() {
+        if client.is_none() {
+            if let Async::Ready(stream) = connect.poll()? {
+                client = Some(stream);
+            }
+        }
+        if server.is_none() {
+            if let Async::Ready(stream) = accept.poll()? {
+                server = Some(stream);
+            }
+        }
+    }
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+    client.write_all(b"ping")?;
+    client.flush()?;
+    let mut buf = [0; 4];
+    io::Read::read_exact(&mut server, &mut buf)?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[test]
+fn resilient_reconnect() -> io::Result<()> {
+    use futures::future::{ self, FutureResult };
+
+    type ServerEnd = server::TlsStream<tokio_rustls::duplex::Duplex<Pipe, Pipe>>;
+
+    struct Peer {
+        stream: ServerEnd,
+        received: Vec<u8>,
+        broken: Rc<Cell<bool>>,
+        bytes_read: Rc<Cell<usize>>,
+    }
+
+    fn pump(peers: &RefCell<Vec<Peer>>) {
+        for peer in peers.borrow_mut().iter_mut().filter(|peer| !peer.broken.get()) {
+            let _ = peer.stream.poll_handshake();
+            let mut buf = [0; 64];
+            while let Ok(n) = io::Read::read(&mut peer.stream, &mut buf) {
+                if n == 0 {
+                    break;
+                }
+                peer.received.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+
+    fn retry<T>(peers: &RefCell<Vec<Peer>>, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match f() {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => pump(peers),
+                ret => return ret,
+            }
+        }
+    }
+
+    let (sconfig, cconfig) = make_configs();
+    let sconfig = Arc::new(sconfig);
+    let peers = Rc::new(RefCell::new(Vec::new()));
+    let factory = {
+        let peers = peers.clone();
+        move || -> FutureResult<Link, io::Error> {
+            let (to_server, to_client) = (Pipe::default(), Pipe::default());
+            let (broken, bytes_read) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(0)));
+            peers.borrow_mut().push(Peer {
+                stream: server::TlsStream::from_split(to_server.clone(), to_client.clone(), ServerSession::new(&sconfig)),
+                received: Vec::new(),
+                broken: broken.clone(),
+                bytes_read: bytes_read.clone(),
+            });
+            future::ok(Link { read: to_client, write: to_server, broken, bytes_read })
+        }
+    };
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect_resilient(domain, factory, RetryPolicy { max_attempts: 2 });
+
+    // a request and its answer, which brings the session ticket along
+    retry(&peers, || stream.write(b"hello"))?;
+    retry(&peers, || stream.flush())?;
+    pump(&peers);
+    peers.borrow_mut()[0].stream.write_all(b"ack")?;
+    peers.borrow_mut()[0].stream.flush()?;
+    let mut buf = [0; 3];
+    retry(&peers, || io::Read::read_exact(&mut stream, &mut buf))?;
+    assert_eq!(&buf, b"ack");
+    stream.acknowledge(5);
+
+    // the next request is cut off
+    retry(&peers, || stream.write(b"world"))?;
+    retry(&peers, || stream.flush())?;
+    pump(&peers);
+    peers.borrow()[0].broken.set(true);
+    retry(&peers, || stream.write(b"!"))?;
+    retry(&peers, || stream.flush())?;
+    pump(&peers);
+
+    assert_eq!(stream.reconnects(), 1);
+    assert_eq!(stream.unacknowledged(), b"world!");
+    let peers = peers.borrow();
+    assert_eq!(peers.len(), 2);
+    // it got "world" before the failure and gets it again
+    assert_eq!(peers[0].received, b"helloworld");
+    assert_eq!(peers[1].received, b"world!");
+    // resumed, so without the certificate
+    assert!(peers[1].bytes_read.get() < peers[0].bytes_read.get() / 2);
+    Ok(())
+}
+
+#[test]
+fn last_write_wire_bytes() -> io::Result<()> {
+    let (mut server, cconfig) = make_server();
+    let mut stream = connect(&mut server, cconfig);
+    assert_eq!(stream.last_write_wire_bytes(), 0);
+
+    // TLS 1.3 adds a header, the inner content type and the AEAD tag
+    const OVERHEAD: usize = 5 + 1 + 16;
+    assert_eq!(stream.write(&[0; 100])?, 100);
+    assert_eq!(stream.last_write_wire_bytes(), 100 + OVERHEAD);
+
+    // one record per 16 KiB
+    assert_eq!(stream.write(&[0; 20_000])?, 20_000);
+    assert_eq!(stream.last_write_wire_bytes(), 20_000 + 2 * OVERHEAD);
+    Ok(())
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn ocsp_stapling_satisfied() {
+    use std::io::{ BufReader, Cursor };
+    use rustls::{ NoClientAuth, ServerConfig };
+    use rustls::internal::pemfile::{ certs, rsa_private_keys };
+
+    const CERT: &str = include_str!("../tests/muststaple.cert");
+    const CHAIN: &str = include_str!("../tests/muststaple.chain");
+    const RSA: &str = include_str!("../tests/muststaple.rsa");
+
+    // a must-staple certificate, signed by a CA of its own
+    let satisfied = |ocsp: &[u8], record: bool| {
+        let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+        let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+        let mut sconfig = ServerConfig::new(NoClientAuth::new());
+        sconfig.set_single_cert_with_ocsp_and_sct(cert, keys.pop().unwrap(), ocsp.to_vec(), Vec::new()).unwrap();
+        let mut cconfig = ClientConfig::new();
+        cconfig.root_store.add_pem_file(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+
+        let mut server = ServerSession::new(&Arc::new(sconfig));
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = TlsConnector::from(Arc::new(cconfig))
+            .record_ocsp_stapling(record)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        stream.ocsp_stapling_satisfied()
+    };
+    // rustls does not check the response, so any bytes do
+    assert_eq!(satisfied(b"ocsp response", true), Some(true));
+    assert_eq!(satisfied(b"", true), Some(false));
+    assert_eq!(satisfied(b"ocsp response", false), None);
+
+    // without must-staple
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = TlsConnector::from(Arc::new(cconfig))
+        .record_ocsp_stapling(true)
+        .connect(domain, Good(&mut server))
+        .wait()
+        .unwrap_or_else(|err| panic!("connect failed: {}", err));
+    assert_eq!(stream.ocsp_stapling_satisfied(), None);
+}
+
+#[test]
+fn max_handshake_size() {
+    // a ClientHello claiming 60000 bytes, with all of them on the way
+    let mut flight = Vec::new();
+    let mut message = vec![0x01, 0x00, 0xea, 0x60];
+    message.resize(4 + 60_000, 0);
+    for fragment in message.chunks(16 * 1024) {
+        flight.extend_from_slice(&[0x16, 0x03, 0x01, (fragment.len() >> 8) as u8, fragment.len() as u8]);
+        flight.extend_from_slice(fragment);
+    }
+
+    struct Flight(io::Cursor<Vec<u8>>);
+
+    impl io::Read for Flight {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl io::Write for Flight {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Flight {}
+    impl AsyncWrite for Flight {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let (sconfig, cconfig) = make_configs();
+    let mut accept = TlsAcceptor::from(Arc::new(sconfig))
+        .max_handshake_size(4096)
+        .accept(Flight(io::Cursor::new(flight.clone())));
+    let err = accept.poll().err().expect("oversized handshake accepted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&HandshakeTooLarge { limit: 4096 }));
+    // given up on long before the message is whole
+    let read = accept.take_inner().expect("io kept after the error").0.position() as usize;
+    assert!(read > 4096 && read < flight.len() / 2);
+
+    // a client refusing the server's certificate flight
+    let mut server = ServerSession::new(&Arc::new(make_configs().0));
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let err = TlsConnector::from(Arc::new(cconfig))
+        .max_handshake_size(256)
+        .connect(domain, Good(&mut server))
+        .wait()
+        .err()
+        .expect("oversized handshake accepted");
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&HandshakeTooLarge { limit: 256 }));
+}
+
+#[test]
+fn peer_certificates_pem() {
+    use rustls::internal::pemfile::certs;
+
+    // the server's chain, as seen by the client
+    let (mut server, cconfig) = make_server();
+    let stream = connect(&mut server, cconfig);
+    let pem = stream.peer_certificates_pem().unwrap();
+    assert!(pem.starts_with("-----BEGIN CERTIFICATE-----\n"));
+    assert!(pem.lines().all(|line| line.len() <= 64));
+    assert_eq!(certs(&mut pem.as_bytes()).unwrap(), stream.get_ref().1.get_peer_certificates().unwrap());
+    assert_eq!(pem, include_str!("../tests/end.cert").replace("\r\n", "\n"));
+
+    // a client chain of several certificates, signed by the test CA
+    let (_, mut cconfig) = make_configs();
+    let mut sconfig = rustls::ServerConfig::new(rustls::AllowAnyAuthenticatedClient::new(cconfig.root_store.clone()));
+    let (mut chain, key) = load_cert_key();
+    sconfig.set_single_cert(chain.clone(), key.clone()).unwrap();
+    chain.extend(certs(&mut &include_bytes!("../tests/end.chain")[..]).unwrap());
+    cconfig.set_single_client_cert(chain.clone(), key);
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let stream = TlsAcceptor::from(Arc::new(sconfig))
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    let pem = stream.peer_certificates_pem().unwrap();
+    assert_eq!(certs(&mut pem.as_bytes()).unwrap(), chain);
+
+    // without a client certificate
+    let (sconfig, cconfig) = make_configs();
+    let mut client = ClientSession::new(&Arc::new(cconfig), domain);
+    let stream = TlsAcceptor::from(Arc::new(sconfig))
+        .accept(Good(&mut client))
+        .wait()
+        .unwrap_or_else(|err| panic!("accept failed: {}", err));
+    assert_eq!(stream.peer_certificates_pem(), None);
+}
+
+#[test]
+fn poll_write_buffer_empty() -> io::Result<()> {
+    // once slow, takes a few bytes every other write
+    struct Trickle<'a> {
+        good: Good<'a>,
+        slow: bool,
+        ready: bool,
+    }
+
+    impl<'a> io::Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.good.read(buf)
+        }
+    }
+
+    impl<'a> io::Write for Trickle<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.slow {
+                return self.good.write(buf);
+            }
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(100);
+            self.good.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.good.flush()
+        }
+    }
+
+    impl<'a> AsyncRead for Trickle<'a> {}
+    impl<'a> AsyncWrite for Trickle<'a> {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.good.shutdown()
+        }
+    }
+
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Trickle { good: Good(&mut server), slow: false, ready: false })
+        .wait()?;
+    stream.get_mut().0.slow = true;
+    assert!(stream.poll_write_buffer_empty()?.is_ready());
+
+    let data = vec![7; 1000];
+    let mut written = 0;
+    while written < data.len() {
+        match io::Write::write(&mut stream, &data[written..]) {
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut polls = 0;
+    while stream.poll_write_buffer_empty()?.is_not_ready() {
+        polls += 1;
+    }
+    assert!(polls > 0);
+    assert!(stream.poll_write_buffer_empty()?.is_ready());
+
+    // all of it is with the server by then
+    let server = &mut *stream.get_mut().0.good.0;
+    let mut buf = vec![0; data.len()];
+    server.read_exact(&mut buf)?;
+    assert_eq!(buf, data);
+    Ok(())
+}
+
+#[cfg(feature = "rate-limit")]
+#[test]
+fn rate_limited() -> io::Result<()> {
+    use std::time::{ Duration, Instant };
+    use tokio_rustls::rate::{ RateLimit, RateLimited };
+
+    let (mut server, cconfig) = make_server();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let stream = TlsConnector::from(Arc::new(cconfig))
+        .connect(domain, Good(&mut server))
+        .wait()?;
+
+    // past the burst, 8000 bytes at 20000 per second take 400ms
+    let limit = RateLimit { bytes_per_sec: 20_000, burst: 2_000 };
+    let data = vec![7; 10_000];
+    let start = Instant::now();
+    let (mut stream, _) = tokio::runtime::current_thread::Runtime::new()
+        .unwrap()
+        .block_on(tokio_io::io::write_all(RateLimited::new(stream, limit), &data[..]))?;
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    stream.flush()?;
+    let server = &mut *stream.get_mut().get_mut().0.0;
+    let mut buf = vec![0; data.len()];
+    io::Read::read_exact(server, &mut buf)?;
+    assert_eq!(buf, data);
+    Ok(())
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn peer_verified_custom_verifier() {
+    // accepts every chain
+    struct AcceptAny;
+
+    impl rustls::ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _: &rustls::RootCertStore,
+            _: &[rustls::Certificate],
+            _: DNSNameRef,
+            _: &[u8],
+        ) -> Result<rustls::ServerCertVerified, TLSError> {
+            Ok(rustls::ServerCertVerified::assertion())
+        }
+    }
+
+    let verified = |cconfig: ClientConfig, name: &str, recheck: bool| {
+        let (mut server, _) = make_server();
+        let domain = DNSNameRef::try_from_ascii_str(name).unwrap();
+        let stream = TlsConnector::from(Arc::new(cconfig))
+            .recheck_server_certificate(recheck)
+            .connect(domain, Good(&mut server))
+            .wait()
+            .unwrap_or_else(|err| panic!("connect failed: {}", err));
+        assert!(stream.get_ref().1.get_peer_certificates().is_some());
+        stream.peer_verified()
+    };
+
+    // a chain the roots vouch for
+    let (_, mut cconfig) = make_configs();
+    cconfig.dangerous().set_certificate_verifier(Arc::new(AcceptAny));
+    assert_eq!(verified(cconfig, "localhost", true), Some(true));
+
+    // presented and accepted, but for another name
+    let (_, mut cconfig) = make_configs();
+    cconfig.dangerous().set_certificate_verifier(Arc::new(AcceptAny));
+    assert_eq!(verified(cconfig.clone(), "example.com", true), Some(false));
+    // which is not known without the recheck
+    assert_eq!(verified(cconfig, "example.com", false), None);
+
+    // presented and accepted, but untrusted
+    let mut cconfig = ClientConfig::new();
+    cconfig.dangerous().set_certificate_verifier(Arc::new(AcceptAny));
+    assert_eq!(verified(cconfig, "localhost", true), Some(false));
+}
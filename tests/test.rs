@@ -63,14 +63,14 @@ lazy_static!{
 
 
 fn start_server() -> &'static (SocketAddr, &'static str, &'static str) {
-    &*TEST_SERVER
+    &TEST_SERVER
 }
 
 fn start_client(addr: &SocketAddr, domain: &str, config: Arc<ClientConfig>) -> io::Result<()> {
     use tokio::prelude::*;
     use tokio::io as aio;
 
-    const FILE: &'static [u8] = include_bytes!("../README.md");
+    const FILE: &[u8] = include_bytes!("../README.md");
 
     let domain = webpki::DNSNameRef::try_from_ascii_str(domain).unwrap();
     let config = TlsConnector::from(config);
@@ -112,3 +112,38 @@ fn fail() {
     assert_ne!(domain, &"google.com");
     assert!(start_client(addr, "google.com", config).is_err());
 }
+
+#[cfg(all(unix, feature = "unix"))]
+#[test]
+fn unix_socket() {
+    use tokio::prelude::*;
+    use tokio::io as aio;
+    use tokio::net::UnixStream;
+    use tokio::runtime::Runtime;
+
+    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    let mut sconfig = ServerConfig::new(rustls::NoClientAuth::new());
+    sconfig.set_single_cert(cert, keys.pop().unwrap()).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(sconfig));
+
+    let mut cconfig = ClientConfig::new();
+    cconfig.root_store.add_pem_file(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+    let connector = TlsConnector::from(Arc::new(cconfig));
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let echo = acceptor.accept_unix(server)
+        .and_then(|stream| aio::read_exact(stream, [0; 5]))
+        .and_then(|(stream, buf)| aio::write_all(stream, buf))
+        .and_then(|(stream, _)| aio::flush(stream));
+
+    // no host name on a socket path, so SNI names the certificate's
+    let domain = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let ping = connector.connect_unix(domain, client)
+        .and_then(|stream| aio::write_all(stream, b"hello"))
+        .and_then(|(stream, _)| aio::read_exact(stream, [0; 5]));
+
+    let mut runtime = Runtime::new().unwrap();
+    let (_, (_, buf)) = runtime.block_on(echo.join(ping)).unwrap();
+    assert_eq!(&buf, b"hello");
+}